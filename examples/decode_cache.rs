@@ -0,0 +1,73 @@
+//! Times the interpreter with and without the decoded instruction cache.
+//!
+//! The workload is the recursive function behind the teleporter's
+//! confirmation check, a variant of Ackermann's function. Run with
+//!
+//! ```text
+//! cargo run --release --example decode_cache [M N]
+//! ```
+
+extern crate synacor;
+
+use std::env;
+use std::time::{Duration, Instant};
+
+use synacor::{asm, Vm};
+
+const SOURCE: &str = "
+        call check
+        halt
+
+; r0 = check(r0, r1), with r7 used when r1 runs out
+check:  jt r0 dec_n
+        add r0 r1 1
+        ret
+dec_n:  jt r1 both
+        add r0 r0 32767
+        set r1 r7
+        call check
+        ret
+both:   push r0
+        add r1 r1 32767
+        call check
+        set r1 r0
+        pop r0
+        add r0 r0 32767
+        call check
+        ret
+";
+
+// Runs check(m, n) with r7 = 1, returning the result, cycles and time
+fn time_check (program: &[u16], m: u16, n: u16, cache: bool) -> (u16, u64, Duration) {
+    let mut vm = Vm::new();
+    vm.set_decode_cache(cache);
+    vm.load_mem(program).unwrap();
+    vm.set_register(0, m).unwrap();
+    vm.set_register(1, n).unwrap();
+    vm.set_register(7, 1).unwrap();
+
+    // Stop at halt rather than executing it
+    let halt_at = 2;
+    let start = Instant::now();
+    vm.run_until(|vm| vm.pc() == halt_at).unwrap();
+    (vm.registers()[0], vm.cc(), start.elapsed())
+}
+
+fn main() {
+    let args: Vec<u16> = env::args().skip(1).filter_map(|a| a.parse().ok()).collect();
+    let (m, n) = match args.as_slice() {
+        [m, n] => (*m, *n),
+        _ => (3, 9),
+    };
+    let program = asm::assemble(SOURCE).unwrap();
+
+    let (result, cycles, uncached) = time_check(&program, m, n, false);
+    let (cached_result, _, cached) = time_check(&program, m, n, true);
+    assert_eq!(result, cached_result);
+
+    let rate = |d: Duration| cycles as f64 / d.as_secs_f64() / 1e6;
+    println!("check({}, {}) = {} in {} cycles", m, n, result, cycles);
+    println!("without cache: {:.2}s ({:.1} M instructions/s)", uncached.as_secs_f64(), rate(uncached));
+    println!("with cache:    {:.2}s ({:.1} M instructions/s)", cached.as_secs_f64(), rate(cached));
+    println!("speedup:       {:.2}x", uncached.as_secs_f64() / cached.as_secs_f64());
+}
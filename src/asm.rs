@@ -0,0 +1,412 @@
+//! A two-pass assembler for Synacor programs.
+//!
+//! Source is line based:
+//!
+//! ```text
+//! ; comments run to the end of the line
+//! start:  set r0 4            ; labels end with a colon
+//!         add r0, r0, 'A'     ; operands may be separated by commas
+//!         out r0
+//!         jmp start
+//! msg:    .string "hi\n"      ; one word per character
+//!         .data 1, 0x10, msg  ; numbers, characters and labels
+//! ```
+//!
+//! Mnemonics are those of `arch-spec`. Operands are numbers (decimal or
+//! `0x` hex), registers `r0`..`r7`, character literals and labels. A
+//! numeric label such as `1798:` asserts the current address, which lets
+//! the disassembler's output be reassembled unchanged.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use opcode::Opcode;
+
+const MAX_LITERAL: u16 = 32_767;
+const REG_BASE: u16 = 32_768;
+const MEM_CAPACITY: usize = 32_768;
+
+/// An error in the source, with the 1-based line it was found on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl Error for AsmError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(u16),
+    Char(u16),
+    Str(String),
+    Colon,
+    Comma,
+}
+
+// A value that may refer to a label not yet defined
+#[derive(Debug, Clone)]
+enum Value {
+    Word(u16),
+    Label(String),
+}
+
+// Output of the first pass: the words of one line, at a known address
+struct Item {
+    line: usize,
+    values: Vec<Value>,
+}
+
+/// Where each source line and label ended up in memory.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    // (line, address) of every line that produced words, in order
+    lines: Vec<(usize, u16)>,
+    labels: HashMap<String, u16>,
+}
+
+impl SourceMap {
+    /// Address of the first word produced by `line` (1-based) or, if it
+    /// produced none, by the next line that did.
+    pub fn addr_of_line (&self, line: usize) -> Option<u16> {
+        self.lines.iter().find(|&&(l, _)| l >= line).map(|&(_, addr)| addr)
+    }
+
+    /// The line that produced the word at `addr`.
+    pub fn line_of_addr (&self, addr: u16) -> Option<usize> {
+        self.lines.iter().rev().find(|&&(_, a)| a <= addr).map(|&(line, _)| line)
+    }
+
+    pub fn label (&self, name: &str) -> Option<u16> {
+        self.labels.get(name).cloned()
+    }
+
+    /// The closest label at or before `addr`, with the distance from it.
+    pub fn label_before (&self, addr: u16) -> Option<(&str, u16)> {
+        self.labels.iter()
+            .filter(|&(_, &a)| a <= addr)
+            .max_by_key(|&(name, &a)| (a, ::std::cmp::Reverse(name.clone())))
+            .map(|(name, &a)| (name.as_str(), addr - a))
+    }
+}
+
+/// Assembles `src` into memory words ready for `Vm::load_mem`.
+pub fn assemble (src: &str) -> Result<Vec<u16>, AsmError> {
+    assemble_with_map(src).map(|(words, _)| words)
+}
+
+/// Assembles `src` as `assemble` does, also returning where each line and
+/// label was placed.
+pub fn assemble_with_map (src: &str) -> Result<(Vec<u16>, SourceMap), AsmError> {
+    let mut labels: HashMap<String, u16> = HashMap::new();
+    let mut map = SourceMap::default();
+    let mut items = Vec::new();
+    let mut addr = 0usize;
+
+    // First pass: parse every line, lay out addresses and record labels
+    for (i, text) in src.lines().enumerate() {
+        let line = i + 1;
+        let err = |msg: String| AsmError { line, msg };
+        let mut tokens = tokenize(text).map_err(&err)?;
+
+        // Leading labels
+        while tokens.len() >= 2 && tokens[1] == Token::Colon {
+            match tokens[0] {
+                Token::Ident(ref name) => {
+                    if labels.insert(name.clone(), addr as u16).is_some() {
+                        return Err(err(format!("label '{}' defined twice", name)));
+                    }
+                },
+                Token::Number(n) => {
+                    if n as usize != addr {
+                        return Err(err(format!("address label {} doesn't match current address {}", n, addr)));
+                    }
+                },
+                ref t => return Err(err(format!("unexpected {:?} before ':'", t))),
+            }
+            tokens.drain(..2);
+        }
+
+        if tokens.is_empty() {
+            continue;
+        }
+
+        let values = parse_statement(&tokens).map_err(&err)?;
+        map.lines.push((line, addr as u16));
+        addr += values.len();
+        if addr > MEM_CAPACITY {
+            return Err(err("program is larger than memory".to_string()));
+        }
+        items.push(Item { line, values });
+    }
+
+    // Second pass: resolve labels
+    let mut words = Vec::with_capacity(addr);
+    for item in items {
+        for value in item.values {
+            words.push(match value {
+                Value::Word(w) => w,
+                Value::Label(name) => match labels.get(&name) {
+                    Some(&a) => a,
+                    None => return Err(AsmError { line: item.line, msg: format!("undefined label '{}'", name) }),
+                },
+            });
+        }
+    }
+    map.labels = labels;
+    Ok((words, map))
+}
+
+fn parse_statement (tokens: &[Token]) -> Result<Vec<Value>, String> {
+    let name = match tokens[0] {
+        Token::Ident(ref name) => name.as_str(),
+        ref t => return Err(format!("expected a mnemonic or directive, found {:?}", t)),
+    };
+    let args = parse_args(&tokens[1..])?;
+
+    match name {
+        ".data" => {
+            if args.is_empty() {
+                return Err(".data needs at least one value".to_string());
+            }
+            args.iter().map(|arg| match *arg {
+                Token::Str(_) => Err("use .string for strings".to_string()),
+                ref t => operand(t, true),
+            }).collect()
+        },
+        ".string" => match args.as_slice() {
+            [Token::Str(s)] => s.chars().map(|c| char_word(c).map(Value::Word)).collect(),
+            _ => Err(".string needs one quoted string".to_string()),
+        },
+        _ => {
+            let op = match Opcode::from_mnemonic(name) {
+                Some(op) => op,
+                None => return Err(format!("unknown mnemonic '{}'", name)),
+            };
+            if args.len() != op.arity() as usize {
+                return Err(format!("{} takes {} operands, found {}", name, op.arity(), args.len()));
+            }
+            let mut values = vec![Value::Word(op.code())];
+            for arg in &args {
+                values.push(operand(arg, false)?);
+            }
+            Ok(values)
+        },
+    }
+}
+
+// Splits operands on commas and whitespace, which may be mixed
+fn parse_args (tokens: &[Token]) -> Result<Vec<Token>, String> {
+    let mut args = Vec::new();
+    let mut expect_value = true;
+    for t in tokens {
+        match *t {
+            Token::Comma => {
+                if expect_value {
+                    return Err("unexpected ','".to_string());
+                }
+                expect_value = true;
+            },
+            Token::Colon => return Err("unexpected ':'".to_string()),
+            ref t => {
+                args.push(t.clone());
+                expect_value = false;
+            },
+        }
+    }
+    if expect_value && !args.is_empty() {
+        return Err("trailing ','".to_string());
+    }
+    Ok(args)
+}
+
+// Converts an operand token to a value. Data words may be any 16-bit
+// number; instruction operands must be literals or registers.
+fn operand (t: &Token, data: bool) -> Result<Value, String> {
+    match *t {
+        Token::Number(n) => {
+            if !data && n > MAX_LITERAL {
+                return Err(format!("literal {} is larger than {}", n, MAX_LITERAL));
+            }
+            Ok(Value::Word(n))
+        },
+        Token::Char(c) => Ok(Value::Word(c)),
+        Token::Ident(ref name) => match register(name) {
+            Some(r) => Ok(Value::Word(REG_BASE + r)),
+            None => Ok(Value::Label(name.clone())),
+        },
+        ref t => Err(format!("unexpected {:?}", t)),
+    }
+}
+
+fn register (name: &str) -> Option<u16> {
+    let bytes = name.as_bytes();
+    if bytes.len() == 2 && bytes[0] == b'r' && (b'0'..=b'7').contains(&bytes[1]) {
+        Some(u16::from(bytes[1] - b'0'))
+    }
+    else {
+        None
+    }
+}
+
+fn tokenize (text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            ';' => break,
+            ',' => { chars.next(); tokens.push(Token::Comma); },
+            ':' => { chars.next(); tokens.push(Token::Colon); },
+            '\'' => {
+                chars.next();
+                let ch = match chars.next() {
+                    Some('\\') => escape(chars.next())?,
+                    Some('\'') | None => return Err("empty character literal".to_string()),
+                    Some(ch) => ch,
+                };
+                if chars.next() != Some('\'') {
+                    return Err("unterminated character literal".to_string());
+                }
+                tokens.push(Token::Char(char_word(ch)?));
+            },
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => s.push(escape(chars.next())?),
+                        Some(ch) => s.push(ch),
+                        None => return Err("unterminated string".to_string()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            },
+            _ if c.is_whitespace() => { chars.next(); },
+            _ => {
+                let mut word = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_alphanumeric() || ch == '_' || ch == '.' {
+                        word.push(ch);
+                        chars.next();
+                    }
+                    else {
+                        break;
+                    }
+                }
+                if word.is_empty() {
+                    return Err(format!("unexpected character '{}'", c));
+                }
+                tokens.push(parse_word(word)?);
+            },
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_word (word: String) -> Result<Token, String> {
+    let first = word.chars().next().unwrap_or('_');
+    if !first.is_ascii_digit() {
+        return Ok(Token::Ident(word));
+    }
+    let parsed = if word.starts_with("0x") || word.starts_with("0X") {
+        u16::from_str_radix(&word[2..], 16)
+    }
+    else {
+        word.parse()
+    };
+    parsed.map(Token::Number).map_err(|_| format!("invalid number '{}'", word))
+}
+
+// A character as a word, if its code fits in a literal
+fn char_word (c: char) -> Result<u16, String> {
+    match c as u32 {
+        code if code <= u32::from(MAX_LITERAL) => Ok(code as u16),
+        code => Err(format!("character '{}' (U+{:04X}) is larger than {}", c, code, MAX_LITERAL)),
+    }
+}
+
+fn escape (c: Option<char>) -> Result<char, String> {
+    match c {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('0') => Ok('\0'),
+        Some('\\') => Ok('\\'),
+        Some('\'') => Ok('\''),
+        Some('"') => Ok('"'),
+        Some(c) => Err(format!("unknown escape '\\{}'", c)),
+        None => Err("unterminated escape".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_line (src: &str) -> (usize, String) {
+        let err = assemble(src).unwrap_err();
+        (err.line, err.msg)
+    }
+
+    #[test]
+    fn forward_references () {
+        let src = "\
+start:  jmp end
+        out 'a'
+end:    set r1, start
+        halt
+";
+        let (words, map) = assemble_with_map(src).unwrap();
+        assert_eq!(words, [6, 4, 19, 97, 1, 32769, 0, 0]);
+        assert_eq!(map.label("end"), Some(4));
+        assert_eq!(map.line_of_addr(4), Some(3));
+    }
+
+    #[test]
+    fn strings_and_data () {
+        let words = assemble("msg: .string \"hi\\n\"\n.data 1, 0x10, msg, 'x', 65535").unwrap();
+        assert_eq!(words, [104, 105, 10, 1, 16, 0, 120, 65535]);
+    }
+
+    #[test]
+    fn label_errors () {
+        let (line, msg) = error_line("a: noop\nnoop\na: halt");
+        assert_eq!(line, 3);
+        assert!(msg.contains("defined twice"), "{}", msg);
+
+        let (line, msg) = error_line("noop\njmp nowhere");
+        assert_eq!(line, 2);
+        assert!(msg.contains("undefined label 'nowhere'"), "{}", msg);
+
+        assert_eq!(error_line("noop\n5: halt").0, 2);
+    }
+
+    #[test]
+    fn bad_operands () {
+        assert_eq!(error_line("set r0 32768").0, 1);
+        assert_eq!(error_line("noop\nadd r0 r1").0, 2);
+        assert_eq!(error_line("out 70000").0, 1);
+        assert_eq!(error_line("jmp ,").0, 1);
+        assert_eq!(error_line("frob r0").0, 1);
+        assert_eq!(error_line(".string \"unterminated").0, 1);
+    }
+
+    #[test]
+    fn rejects_characters_beyond_15_bits () {
+        let (line, msg) = error_line("noop\nout '\u{1F600}'");
+        assert_eq!(line, 2);
+        assert!(msg.contains("larger than"), "{}", msg);
+        assert_eq!(error_line(".string \"a\u{10000}\"").0, 1);
+        // Characters up to 32767 are fine
+        assert_eq!(assemble("out '\u{7FFF}'").unwrap(), [19, 32767]);
+    }
+}
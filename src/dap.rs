@@ -0,0 +1,768 @@
+//! A Debug Adapter Protocol server, for debugging programs from editors.
+//!
+//! Messages are JSON bodies behind a `Content-Length` header, as the
+//! protocol specifies. The `launch` request takes:
+//!
+//! ```text
+//! program       path of an image, or of assembler source ending in .asm
+//! stopOnEntry   stop before the first instruction (default false)
+//! input         path of a file whose lines are queued as program input
+//! ```
+//!
+//! Breakpoints can be set on lines of the assembler source when the
+//! program was launched from source, and on addresses or labels with
+//! function and instruction breakpoints. Addresses are word addresses,
+//! written in decimal or `0x` hex.
+//!
+//! The program's output is sent as output events. Since stdio carries
+//! the protocol, its input comes from the `input` file and from the debug
+//! console: an expression there that isn't a register (`r0`..`r7`, `pc`,
+//! `cc`) or memory word (`[ADDR]`) is queued as a line of input. When the
+//! program runs out of input it stops, waiting for more. Diagnostics
+//! from the VM appear as console output.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use serde_json::Value;
+
+use asm::{self, SourceMap};
+use debugger::Debugger;
+use diag::BufferSink;
+use disasm;
+use error::VmError;
+use image;
+use instr::{decode, Instruction};
+use io::BufferIo;
+use vm::{StopReason, Vm};
+
+const THREAD_ID: u64 = 1;
+
+// Instructions run between checks for new requests
+const SLICE: u32 = 10_000;
+
+// Variable references of the scopes. Memory is split into pages, whose
+// references start at PAGE_REF_BASE.
+const REGISTERS_REF: u64 = 1;
+const STACK_REF: u64 = 2;
+const MEMORY_REF: u64 = 3;
+const PAGE_REF_BASE: u64 = 1000;
+const PAGE_SIZE: usize = 256;
+const MEM_SIZE: usize = 32_768;
+const MAX_15_BIT_VAL: u16 = 32_767;
+
+/// Reads one message, returning `None` at end of input.
+pub fn read_message<R: BufRead> (input: &mut R) -> io::Result<Option<Value>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() && len.is_some() {
+            break;
+        }
+        if let Some(n) = line.strip_prefix("Content-Length:") {
+            len = n.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut body = vec![0; len.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn write_message<W: Write> (out: &mut W, msg: &Value) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+/// Reads messages from `input` on a new thread, so that requests such as
+/// `pause` are seen while the program runs. The channel closes at the
+/// end of input or on a malformed message.
+pub fn spawn_reader<R: BufRead + Send + 'static> (mut input: R) -> Receiver<Value> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(Some(msg)) = read_message(&mut input) {
+            if tx.send(msg).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// How a running program should stop, besides breakpoints and halting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    // Stepping over a call: back at `ret` with `depth` calls in progress
+    Over { ret: u16, depth: usize },
+    // Stepping out: fewer than `depth` calls in progress
+    Out { depth: usize },
+}
+
+struct Program {
+    dbg: Debugger,
+    io: BufferIo,
+    diag: BufferSink,
+    path: PathBuf,
+    // Present when launched from assembler source
+    map: Option<SourceMap>,
+}
+
+/// A DAP server debugging one program.
+pub struct DapServer<W: Write> {
+    out: W,
+    seq: u64,
+    program: Option<Program>,
+    stop_on_entry: bool,
+    running: Option<Resume>,
+    // Events to send once the current response is out
+    pending: Vec<Value>,
+    // Breakpoint addresses by the request that set them
+    line_bps: Vec<u16>,
+    function_bps: Vec<u16>,
+    instr_bps: Vec<u16>,
+    // Addresses currently set in the VM
+    applied: HashSet<u16>,
+    done: bool,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new (out: W) -> DapServer<W> {
+        DapServer {
+            out,
+            seq: 0,
+            program: None,
+            stop_on_entry: false,
+            running: None,
+            pending: vec![],
+            line_bps: vec![],
+            function_bps: vec![],
+            instr_bps: vec![],
+            applied: HashSet::new(),
+            done: false,
+        }
+    }
+
+    /// Handles requests until the client disconnects or the channel
+    /// closes.
+    pub fn serve (&mut self, requests: &Receiver<Value>) -> io::Result<()> {
+        while !self.done {
+            if self.running.is_some() {
+                match requests.try_recv() {
+                    Ok(msg) => self.handle(&msg)?,
+                    Err(TryRecvError::Empty) => self.run_slice()?,
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            }
+            else {
+                match requests.recv() {
+                    Ok(msg) => self.handle(&msg)?,
+                    Err(_) => break,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle (&mut self, msg: &Value) -> io::Result<()> {
+        if msg["type"] != "request" {
+            return Ok(());
+        }
+        let command = msg["command"].as_str().unwrap_or("");
+        let args = &msg["arguments"];
+        let result = match command {
+            "initialize" => Ok(capabilities()),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_line_breakpoints(args),
+            "setFunctionBreakpoints" => self.set_function_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints" => Ok(json!({})),
+            "configurationDone" => self.configuration_done(),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "synacor" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(scopes()),
+            "variables" => self.variables(args),
+            "setVariable" => self.set_variable(args),
+            "continue" => self.resume(Resume::Continue),
+            "next" => self.next(),
+            "stepIn" => self.step_in(),
+            "stepOut" => self.step_out(),
+            "pause" => self.pause(),
+            "disassemble" => self.disassemble(args),
+            "evaluate" => self.evaluate(args),
+            "disconnect" | "terminate" => {
+                self.done = true;
+                Ok(json!({}))
+            },
+            _ => Err(format!("unsupported request '{}'", command)),
+        };
+
+        let mut response = json!({
+            "type": "response",
+            "request_seq": msg["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(e) => response["message"] = json!(e),
+        }
+        self.send(response)?;
+        for event in ::std::mem::take(&mut self.pending) {
+            self.send(event)?;
+        }
+        Ok(())
+    }
+
+    fn send (&mut self, mut msg: Value) -> io::Result<()> {
+        self.seq += 1;
+        msg["seq"] = json!(self.seq);
+        write_message(&mut self.out, &msg)
+    }
+
+    fn event (&mut self, event: &str, body: Value) {
+        self.pending.push(json!({ "type": "event", "event": event, "body": body }));
+    }
+
+    fn program (&mut self) -> Result<&mut Program, String> {
+        self.program.as_mut().ok_or_else(|| "no program has been launched".to_string())
+    }
+
+    fn launch (&mut self, args: &Value) -> Result<Value, String> {
+        let path = match args["program"].as_str() {
+            Some(path) => PathBuf::from(path),
+            None => return Err("launch needs a 'program'".to_string()),
+        };
+        let (words, map) = if path.extension().is_some_and(|ext| ext == "asm") {
+            let src = fs::read_to_string(&path)
+                .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+            let (words, map) = asm::assemble_with_map(&src)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            (words, Some(map))
+        }
+        else {
+            let words = image::read_image(&path)
+                .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+            (words, None)
+        };
+
+        let io = BufferIo::default();
+        if let Some(input) = args["input"].as_str() {
+            let script = fs::read_to_string(input)
+                .map_err(|e| format!("could not read {}: {}", input, e))?;
+            io.push_input(&script);
+        }
+        let diag = BufferSink::default();
+        let mut vm = Vm::with_io(Box::new(io.clone()));
+        vm.diagnostics_mut().set_sink(Box::new(diag.clone()));
+        vm.load_mem(&words).map_err(|e| e.to_string())?;
+
+        self.program = Some(Program { dbg: Debugger::new(vm), io, diag, path, map });
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        self.applied.clear();
+        self.event("initialized", json!({}));
+        Ok(json!({}))
+    }
+
+    fn configuration_done (&mut self) -> Result<Value, String> {
+        self.program()?;
+        if self.stop_on_entry {
+            self.stopped_event("entry", None);
+        }
+        else {
+            self.running = Some(Resume::Continue);
+        }
+        Ok(json!({}))
+    }
+
+    // Sets the VM's breakpoints to those of all three requests
+    fn apply_breakpoints (&mut self) {
+        let wanted: HashSet<u16> = self.line_bps.iter()
+            .chain(&self.function_bps)
+            .chain(&self.instr_bps)
+            .cloned()
+            .collect();
+        if let Some(ref mut program) = self.program {
+            let vm = program.dbg.vm_mut();
+            for &addr in self.applied.difference(&wanted) {
+                vm.remove_breakpoint(addr);
+            }
+            for &addr in &wanted {
+                vm.add_breakpoint(addr);
+            }
+        }
+        self.applied = wanted;
+    }
+
+    fn set_line_breakpoints (&mut self, args: &Value) -> Result<Value, String> {
+        let program = self.program()?;
+        let same_file = args["source"]["path"].as_str().is_some_and(|p| same_path(Path::new(p), &program.path));
+        let mut addrs = vec![];
+        let mut results = vec![];
+        for bp in args["breakpoints"].as_array().map_or(&[][..], |a| a.as_slice()) {
+            let line = bp["line"].as_u64().unwrap_or(0) as usize;
+            let map = match program.map {
+                Some(ref map) if same_file => map,
+                _ => {
+                    results.push(json!({ "verified": false, "message": "not the source of the launched program" }));
+                    continue;
+                },
+            };
+            match map.addr_of_line(line) {
+                Some(addr) => {
+                    addrs.push(addr);
+                    results.push(json!({
+                        "verified": true,
+                        "line": map.line_of_addr(addr),
+                        "instructionReference": format_addr(addr),
+                    }));
+                },
+                None => results.push(json!({ "verified": false, "message": "no code at or after this line" })),
+            }
+        }
+        self.line_bps = addrs;
+        self.apply_breakpoints();
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn set_function_breakpoints (&mut self, args: &Value) -> Result<Value, String> {
+        let program = self.program()?;
+        let mut addrs = vec![];
+        let mut results = vec![];
+        for bp in args["breakpoints"].as_array().map_or(&[][..], |a| a.as_slice()) {
+            let name = bp["name"].as_str().unwrap_or("");
+            let addr = parse_addr(name).or_else(|| program.map.as_ref().and_then(|m| m.label(name)));
+            match addr {
+                Some(addr) => {
+                    addrs.push(addr);
+                    results.push(json!({ "verified": true, "instructionReference": format_addr(addr) }));
+                },
+                None => results.push(json!({ "verified": false, "message": "not an address or label" })),
+            }
+        }
+        self.function_bps = addrs;
+        self.apply_breakpoints();
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn set_instruction_breakpoints (&mut self, args: &Value) -> Result<Value, String> {
+        self.program()?;
+        let mut addrs = vec![];
+        let mut results = vec![];
+        for bp in args["breakpoints"].as_array().map_or(&[][..], |a| a.as_slice()) {
+            let base = bp["instructionReference"].as_str().and_then(parse_addr);
+            let addr = base.map(|b| i64::from(b) + bp["offset"].as_i64().unwrap_or(0));
+            match addr {
+                Some(addr) if (0..MEM_SIZE as i64).contains(&addr) => {
+                    addrs.push(addr as u16);
+                    results.push(json!({ "verified": true, "instructionReference": format_addr(addr as u16) }));
+                },
+                _ => results.push(json!({ "verified": false, "message": "invalid address" })),
+            }
+        }
+        self.instr_bps = addrs;
+        self.apply_breakpoints();
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn stack_trace (&mut self) -> Result<Value, String> {
+        let program = self.program()?;
+        let vm = program.dbg.vm();
+        let calls = vm.call_stack();
+
+        // Innermost first. Each caller is shown at its call instruction.
+        let mut frames = vec![];
+        let mut pc = vm.pc();
+        for depth in (0..=calls.len()).rev() {
+            let name = match depth {
+                0 => "entry".to_string(),
+                _ => function_name(program.map.as_ref(), calls[depth - 1].target),
+            };
+            let mut frame = json!({
+                "id": calls.len() - depth,
+                "name": name,
+                "line": 0,
+                "column": 0,
+                "instructionPointerReference": format_addr(pc),
+            });
+            if let Some(line) = program.map.as_ref().and_then(|m| m.line_of_addr(pc)) {
+                frame["line"] = json!(line);
+                frame["column"] = json!(1);
+                frame["source"] = source(&program.path);
+            }
+            frames.push(frame);
+            if depth > 0 {
+                pc = calls[depth - 1].call_pc;
+            }
+        }
+        let total = frames.len();
+        Ok(json!({ "stackFrames": frames, "totalFrames": total }))
+    }
+
+    fn variables (&mut self, args: &Value) -> Result<Value, String> {
+        let program = self.program()?;
+        let vm = program.dbg.vm();
+        let vars: Vec<Value> = match args["variablesReference"].as_u64().unwrap_or(0) {
+            REGISTERS_REF => {
+                let mut vars: Vec<Value> = vm.registers().iter().enumerate()
+                    .map(|(i, &val)| variable(&format!("r{}", i), val, 0))
+                    .collect();
+                vars.push(variable("pc", vm.pc(), 0));
+                vars.push(json!({ "name": "cc", "value": vm.cc().to_string(), "variablesReference": 0 }));
+                vars
+            },
+            STACK_REF => vm.stack().iter().rev().enumerate()
+                .map(|(i, &val)| variable(&format!("#{}", i), val, 0))
+                .collect(),
+            MEMORY_REF => (0..MEM_SIZE / PAGE_SIZE).map(|page| {
+                let start = page * PAGE_SIZE;
+                json!({
+                    "name": format!("{}..{}", start, start + PAGE_SIZE - 1),
+                    "value": "",
+                    "variablesReference": PAGE_REF_BASE + page as u64,
+                })
+            }).collect(),
+            r if r >= PAGE_REF_BASE && r < PAGE_REF_BASE + (MEM_SIZE / PAGE_SIZE) as u64 => {
+                let start = (r - PAGE_REF_BASE) as usize * PAGE_SIZE;
+                vm.memory()[start..start + PAGE_SIZE].iter().enumerate()
+                    .map(|(i, &val)| variable(&format!("[{}]", start + i), val, 0))
+                    .collect()
+            },
+            r => return Err(format!("unknown variables reference {}", r)),
+        };
+        Ok(json!({ "variables": vars }))
+    }
+
+    fn set_variable (&mut self, args: &Value) -> Result<Value, String> {
+        let program = self.program()?;
+        let name = args["name"].as_str().unwrap_or("");
+        let val = args["value"].as_str().and_then(parse_num)
+            .ok_or_else(|| "value must be a number".to_string())?;
+        let vm = program.dbg.vm_mut();
+        match args["variablesReference"].as_u64().unwrap_or(0) {
+            REGISTERS_REF => set_location(vm, name, val)?,
+            STACK_REF => {
+                let depth = vm.stack().len();
+                match name.strip_prefix('#').and_then(|i| i.parse::<usize>().ok()) {
+                    Some(_) if val > MAX_15_BIT_VAL => return Err(format!("{} is out of range", val)),
+                    Some(i) if i < depth => vm.set_stack(i, val).map_err(|e| e.to_string())?,
+                    _ => return Err(format!("no stack entry {}", name)),
+                }
+            },
+            _ => set_location(vm, name, val)?,
+        }
+        Ok(json!({ "value": val.to_string() }))
+    }
+
+    fn resume (&mut self, resume: Resume) -> Result<Value, String> {
+        self.program()?;
+        self.running = Some(resume);
+        Ok(json!({ "allThreadsContinued": true }))
+    }
+
+    fn next (&mut self) -> Result<Value, String> {
+        let program = self.program()?;
+        let vm = program.dbg.vm();
+        match decode(vm.memory(), vm.pc()) {
+            Ok(instr @ Instruction::Call(_)) => {
+                let ret = vm.pc() + instr.size();
+                let depth = vm.call_stack().len();
+                self.resume(Resume::Over { ret, depth })
+            },
+            _ => self.step_in(),
+        }
+    }
+
+    fn step_in (&mut self) -> Result<Value, String> {
+        let result = self.program()?.dbg.step();
+        self.stopped(result);
+        Ok(json!({}))
+    }
+
+    fn step_out (&mut self) -> Result<Value, String> {
+        let depth = self.program()?.dbg.vm().call_stack().len();
+        if depth == 0 {
+            return self.step_in();
+        }
+        self.resume(Resume::Out { depth })
+    }
+
+    fn pause (&mut self) -> Result<Value, String> {
+        if self.running.take().is_some() {
+            self.flush_output();
+            self.stopped_event("pause", None);
+        }
+        Ok(json!({}))
+    }
+
+    fn disassemble (&mut self, args: &Value) -> Result<Value, String> {
+        let program = self.program()?;
+        let base = args["memoryReference"].as_str().and_then(parse_addr)
+            .ok_or_else(|| "invalid memory reference".to_string())?;
+        let base = i64::from(base).saturating_add(args["offset"].as_i64().unwrap_or(0)).clamp(0, MEM_SIZE as i64) as usize;
+        // Neither can usefully go beyond the size of memory
+        let offset = args["instructionOffset"].as_i64().unwrap_or(0).clamp(-(MEM_SIZE as i64), MEM_SIZE as i64);
+        let count = (args["instructionCount"].as_u64().unwrap_or(0) as usize).min(MEM_SIZE);
+
+        // Decode enough around base to cover the range asked for; no
+        // instruction is longer than 4 words
+        let mem = program.dbg.vm().memory();
+        let from = base.saturating_sub(4 * offset.min(0).unsigned_abs() as usize);
+        let to = base.saturating_add(4 * (count + offset.max(0) as usize)).min(MEM_SIZE);
+        let lines = disasm::disassemble(mem, from as u16, to);
+        let first = lines.iter().position(|l| l.addr as usize >= base).unwrap_or(lines.len()) as i64 + offset;
+
+        let map = program.map.as_ref();
+        let instructions: Vec<Value> = (first..first + count as i64).map(|i| {
+            let line = match if i < 0 { None } else { lines.get(i as usize) } {
+                Some(line) => line,
+                None => return json!({ "address": format_addr(0), "instruction": "", "presentationHint": "invalid" }),
+            };
+            let text = match line.instr {
+                Some(instr) => instr.to_string(),
+                None => {
+                    let words: Vec<String> = line.words.iter().map(|w| w.to_string()).collect();
+                    format!(".data {}", words.join(", "))
+                },
+            };
+            let bytes: Vec<String> = line.words.iter().map(|w| format!("{:04x}", w)).collect();
+            let mut value = json!({
+                "address": format_addr(line.addr),
+                "instructionBytes": bytes.join(" "),
+                "instruction": text,
+            });
+            if let Some((label, 0)) = map.and_then(|m| m.label_before(line.addr)) {
+                value["symbol"] = json!(label);
+            }
+            if let Some(l) = map.and_then(|m| m.line_of_addr(line.addr)) {
+                value["line"] = json!(l);
+                value["location"] = source(&program.path);
+            }
+            value
+        }).collect();
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    fn evaluate (&mut self, args: &Value) -> Result<Value, String> {
+        let program = self.program()?;
+        let expr = args["expression"].as_str().unwrap_or("").trim();
+        if let Some(val) = read_location(program.dbg.vm(), expr) {
+            return Ok(json!({ "result": val, "variablesReference": 0 }));
+        }
+        if args["context"].as_str().is_some_and(|c| c != "repl") {
+            return Err(format!("can't evaluate '{}'", expr));
+        }
+        program.io.push_input(expr);
+        Ok(json!({ "result": format!("queued input: {}", expr), "variablesReference": 0 }))
+    }
+
+    // Runs the program for a while, stopping it if it stops by itself
+    fn run_slice (&mut self) -> io::Result<()> {
+        let resume = match self.running {
+            Some(resume) => resume,
+            None => return Ok(()),
+        };
+        let program = match self.program {
+            Some(ref mut program) => program,
+            None => {
+                self.running = None;
+                return Ok(());
+            },
+        };
+
+        let mut budget = SLICE;
+        let mut reached = false;
+        let result = program.dbg.vm_mut().run_until(|vm| {
+            reached = match resume {
+                Resume::Continue => false,
+                Resume::Over { ret, depth } => vm.pc() == ret && vm.call_stack().len() == depth,
+                Resume::Out { depth } => vm.call_stack().len() < depth,
+            };
+            budget -= 1;
+            reached || budget == 0
+        });
+
+        if result == Ok(StopReason::Reached) && !reached {
+            self.flush_output();
+        }
+        else {
+            self.running = None;
+            self.stopped(result);
+        }
+        for event in ::std::mem::take(&mut self.pending) {
+            self.send(event)?;
+        }
+        Ok(())
+    }
+
+    // Reports why the program stopped
+    fn stopped (&mut self, result: Result<StopReason, VmError>) {
+        self.flush_output();
+        let halted = self.program.as_ref().is_some_and(|p| p.dbg.vm().is_halted());
+        match result {
+            Err(VmError::InputExhausted { .. }) => {
+                self.output("console", "Waiting for input. Type it in the debug console, then continue.\n");
+                self.stopped_event("pause", Some("Waiting for input"));
+            },
+            Err(e) => {
+                self.output("stderr", &format!("{}\n", e));
+                self.stopped_event("exception", Some(&e.to_string()));
+            },
+            Ok(_) if halted => {
+                self.event("exited", json!({ "exitCode": 0 }));
+                self.event("terminated", json!({}));
+            },
+            Ok(StopReason::Breakpoint) => self.stopped_event("breakpoint", None),
+            Ok(StopReason::Watchpoint) => self.stopped_event("data breakpoint", None),
+            Ok(StopReason::Reached) => self.stopped_event("step", None),
+            Ok(_) => self.stopped_event("pause", None),
+        }
+    }
+
+    fn stopped_event (&mut self, reason: &str, text: Option<&str>) {
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body);
+    }
+
+    fn output (&mut self, category: &str, text: &str) {
+        self.event("output", json!({ "category": category, "output": text }));
+    }
+
+    // Sends what the program and the VM have written since the last call
+    fn flush_output (&mut self) {
+        let (text, messages) = match self.program {
+            Some(ref program) => (program.io.take_output(), program.diag.take()),
+            None => return,
+        };
+        if !text.is_empty() {
+            self.output("stdout", &text);
+        }
+        for (level, msg) in messages {
+            self.output("console", &format!("{}: {}\n", level, msg));
+        }
+    }
+}
+
+fn capabilities () -> Value {
+    json!({
+        "supportsConfigurationDoneRequest": true,
+        "supportsFunctionBreakpoints": true,
+        "supportsInstructionBreakpoints": true,
+        "supportsDisassembleRequest": true,
+        "supportsSetVariable": true,
+        "supportsTerminateRequest": true,
+        "supportsSteppingGranularity": false,
+        "exceptionBreakpointFilters": [],
+    })
+}
+
+fn scopes () -> Value {
+    json!({ "scopes": [
+        { "name": "Registers", "presentationHint": "registers", "variablesReference": REGISTERS_REF, "expensive": false },
+        { "name": "Stack", "variablesReference": STACK_REF, "expensive": false },
+        { "name": "Memory", "variablesReference": MEMORY_REF, "expensive": true },
+    ]})
+}
+
+fn variable (name: &str, val: u16, reference: u64) -> Value {
+    json!({
+        "name": name,
+        "value": val.to_string(),
+        "variablesReference": reference,
+        "memoryReference": format_addr(val),
+    })
+}
+
+fn source (path: &Path) -> Value {
+    json!({
+        "name": path.file_name().map(|n| n.to_string_lossy().into_owned()),
+        "path": path.to_string_lossy(),
+    })
+}
+
+// Names a called function by its label, or by its address
+fn function_name (map: Option<&SourceMap>, target: u16) -> String {
+    match map.and_then(|m| m.label_before(target)) {
+        Some((label, 0)) => label.to_string(),
+        _ => format!("sub_{}", target),
+    }
+}
+
+fn same_path (a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn format_addr (addr: u16) -> String {
+    format!("0x{:04x}", addr)
+}
+
+// Parses a decimal number, or a hexadecimal one prefixed with 0x
+fn parse_num (s: &str) -> Option<u16> {
+    let s = s.trim();
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u16::from_str_radix(&s[2..], 16).ok()
+    }
+    else {
+        s.parse().ok()
+    }
+}
+
+fn parse_addr (s: &str) -> Option<u16> {
+    parse_num(s).filter(|&addr| (addr as usize) < MEM_SIZE)
+}
+
+// Reads a register, pc, cc or memory word named as in the debug console
+fn read_location (vm: &Vm, expr: &str) -> Option<String> {
+    match expr {
+        "pc" => return Some(vm.pc().to_string()),
+        "cc" => return Some(vm.cc().to_string()),
+        _ => {},
+    }
+    if let Some(addr) = expr.strip_prefix('[').and_then(|e| e.strip_suffix(']')).and_then(parse_addr) {
+        return Some(vm.memory()[addr as usize].to_string());
+    }
+    register(expr).map(|r| vm.registers()[r].to_string())
+}
+
+fn set_location (vm: &mut Vm, name: &str, val: u16) -> Result<(), String> {
+    if name == "pc" {
+        vm.set_pc(val);
+        return Ok(());
+    }
+    if let Some(addr) = name.strip_prefix('[').and_then(|e| e.strip_suffix(']')).and_then(parse_addr) {
+        return vm.mem_write(addr, val).map_err(|e| e.to_string());
+    }
+    match register(name) {
+        Some(r) => vm.set_register(r as u16, val).map_err(|e| e.to_string()),
+        None => Err(format!("can't set {}", name)),
+    }
+}
+
+fn register (name: &str) -> Option<usize> {
+    let bytes = name.as_bytes();
+    if bytes.len() == 2 && bytes[0] == b'r' && (b'0'..=b'7').contains(&bytes[1]) {
+        Some((bytes[1] - b'0') as usize)
+    }
+    else {
+        None
+    }
+}
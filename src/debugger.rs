@@ -0,0 +1,619 @@
+//! An interactive debugger wrapped around a `Vm`.
+//!
+//! Commands are read a line at a time from an `Io` backend, so the same
+//! prompt works on the terminal and against canned input. The debugger
+//! can be entered at startup, when `run` stops on a breakpoint, or by
+//! typing `DEBUG` at one of the game's input prompts.
+
+use std::io;
+use std::io::Write;
+
+use disasm;
+use error::VmError;
+use instr::{decode, Instruction};
+use io::Io;
+use vm::{StopReason, Vm};
+use watch::{format_location, WatchAction, WatchKind, Watchpoint};
+
+const PROMPT: &str = "(sdb) ";
+
+// Default number of lines shown by disas and words shown by x
+const DISAS_LINES: usize = 10;
+const DUMP_WORDS: usize = 8;
+
+// How far before pc to look for an instruction boundary
+// when disassembling around it
+const DISAS_LOOKBEHIND: u16 = 12;
+
+// Largest value a register or memory word may be given
+const MAX_15_BIT_VAL: u64 = 32_767;
+
+const HELP: &str = "\
+Execution:
+  s, step [N]            execute N instructions (default 1)
+  n, next                step, treating a call as a single instruction
+  c, continue            run until a breakpoint, halt or error
+  fin, finish            run until the current call returns
+  rs, reverse-step [N]   undo the last N instructions (default 1)
+  rc, reverse-continue   step backwards to the previous breakpoint
+  history [DEPTH]        show how far back execution can be reversed,
+                         or keep DEPTH instructions (0 turns it off)
+Breakpoints:
+  b, break [ADDR [NAME]] set a breakpoint, or list them with no address
+  d, delete ID|NAME      remove a breakpoint
+  watch [LOC [KIND] [log]]
+                         watch a memory address or register rN, or list
+                         watchpoints with no location. KIND is read,
+                         write or change (default). With 'log' hits are
+                         reported without stopping
+  unwatch LOC            remove the watchpoints on LOC
+Inspection:
+  r, regs                show registers, pc, cycle count and stack depth
+  set rN|pc VALUE        change a register or the program counter
+  x ADDR [N]             show N memory words (default 8)
+  poke ADDR VALUE...     write words to memory starting at ADDR
+  stack                  show the stack, top first
+  bt, backtrace          show calls in progress
+  l, disas [ADDR [N]]    disassemble N lines at ADDR (default around pc)
+Snapshots:
+  save FILE              save the complete machine state to FILE
+  load FILE              restore the machine state from FILE
+Other:
+  h, help                show this help
+  q, quit                leave the debugger
+An empty line repeats the last command. Numbers may be given in hex
+with a 0x prefix.";
+
+/// A breakpoint set from the debugger.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub id: usize,
+    pub addr: u16,
+    pub name: Option<String>,
+}
+
+/// Debugger state: the VM being debugged and its breakpoints.
+pub struct Debugger {
+    vm: Vm,
+    breakpoints: Vec<Breakpoint>,
+    next_id: usize,
+    last_cmd: String,
+    last_error: Option<VmError>,
+}
+
+impl Debugger {
+    pub fn new (vm: Vm) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: vec![],
+            next_id: 1,
+            last_cmd: String::new(),
+            last_error: None,
+        }
+    }
+
+    pub fn vm (&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn vm_mut (&mut self) -> &mut Vm {
+        &mut self.vm
+    }
+
+    pub fn into_vm (self) -> Vm {
+        self.vm
+    }
+
+    /// The error that stopped the program most recently, if any.
+    pub fn last_error (&self) -> Option<&VmError> {
+        self.last_error.as_ref()
+    }
+
+    pub fn breakpoints (&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Sets a breakpoint at `addr`, returning its ID.
+    pub fn add_breakpoint (&mut self, addr: u16, name: Option<String>) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push(Breakpoint { id, addr, name });
+        self.vm.add_breakpoint(addr);
+        id
+    }
+
+    /// Removes the breakpoint with the given ID or name.
+    pub fn remove_breakpoint (&mut self, key: &str) -> Option<Breakpoint> {
+        let pos = self.breakpoints.iter().position(|bp| {
+            bp.id.to_string() == key || bp.name.as_ref().is_some_and(|n| n == key)
+        })?;
+        let bp = self.breakpoints.remove(pos);
+
+        // Another breakpoint may share the address
+        if !self.breakpoints.iter().any(|other| other.addr == bp.addr) {
+            self.vm.remove_breakpoint(bp.addr);
+        }
+        Some(bp)
+    }
+
+    /// Executes one instruction, stepping over calls.
+    pub fn step_over (&mut self) -> Result<StopReason, VmError> {
+        let pc = self.vm.pc();
+        match decode(self.vm.memory(), pc) {
+            Ok(instr @ Instruction::Call(_)) => {
+                let ret = pc + instr.size();
+                let depth = self.vm.call_stack().len();
+                self.vm.run_until(|vm| vm.pc() == ret && vm.call_stack().len() == depth)
+            },
+            _ => self.step(),
+        }
+    }
+
+    /// Executes one instruction.
+    pub fn step (&mut self) -> Result<StopReason, VmError> {
+        if self.vm.is_halted() {
+            return Ok(StopReason::Halted);
+        }
+        self.vm.step()?;
+        if self.vm.pause_requested() {
+            Ok(StopReason::Paused)
+        }
+        else if self.vm.watch_hits().iter().any(|hit| hit.watchpoint.action == WatchAction::Break) {
+            Ok(StopReason::Watchpoint)
+        }
+        else if self.vm.is_halted() {
+            Ok(StopReason::Halted)
+        }
+        else {
+            Ok(StopReason::Reached)
+        }
+    }
+
+    /// Runs until the innermost call returns.
+    pub fn finish (&mut self) -> Option<Result<StopReason, VmError>> {
+        let depth = self.vm.call_stack().len();
+        if depth == 0 {
+            return None;
+        }
+        Some(self.vm.run_until(|vm| vm.call_stack().len() < depth))
+    }
+
+    /// Steps backwards until a breakpoint is reached, returning false if
+    /// the history ran out first. At least one instruction is undone,
+    /// so a VM sitting on a breakpoint moves off it.
+    pub fn reverse_continue (&mut self) -> bool {
+        while self.vm.reverse_step() {
+            if self.vm.has_breakpoint(self.vm.pc()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Reads and executes commands until `quit` or end of input.
+    pub fn repl (&mut self, console: &mut dyn Io, out: &mut dyn Write) -> io::Result<()> {
+        loop {
+            write!(out, "{}", PROMPT)?;
+            out.flush()?;
+
+            let mut line = String::new();
+            if console.read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(());
+            }
+            let mut line = line.trim().to_string();
+            if line.is_empty() {
+                line = self.last_cmd.clone();
+            }
+            if line.is_empty() {
+                continue;
+            }
+            self.last_cmd = line.clone();
+
+            let args: Vec<&str> = line.split_whitespace().collect();
+            if !self.command(&args, out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Executes one command. Returns false when the debugger should exit.
+    fn command (&mut self, args: &[&str], out: &mut dyn Write) -> io::Result<bool> {
+        match args[0] {
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            "s" | "step" => {
+                let count = match args.get(1) {
+                    Some(n) => match parse_num(n) {
+                        Some(n) => n as usize,
+                        None => return bad_arg(out, n),
+                    },
+                    None => 1,
+                };
+                let mut result = Ok(StopReason::Reached);
+                for _ in 0..count {
+                    result = self.step();
+                    if result != Ok(StopReason::Reached) {
+                        break;
+                    }
+                }
+                self.report(result, out)?;
+            },
+            "n" | "next" => {
+                let result = self.step_over();
+                self.report(result, out)?;
+            },
+            "c" | "continue" => {
+                let result = self.vm.run();
+                self.report(result, out)?;
+            },
+            "fin" | "finish" => match self.finish() {
+                Some(result) => self.report(result, out)?,
+                None => writeln!(out, "Not inside a call")?,
+            },
+            "rs" | "reverse-step" => {
+                let count = match args.get(1) {
+                    Some(n) => match parse_num(n) {
+                        Some(n) => n as usize,
+                        None => return bad_arg(out, n),
+                    },
+                    None => 1,
+                };
+                for _ in 0..count {
+                    if !self.vm.reverse_step() {
+                        writeln!(out, "No more history")?;
+                        break;
+                    }
+                }
+                self.last_error = None;
+                self.show_location(out)?;
+            },
+            "rc" | "reverse-continue" => {
+                self.last_error = None;
+                if self.reverse_continue() {
+                    self.report(Ok(StopReason::Breakpoint), out)?;
+                }
+                else {
+                    writeln!(out, "Reached the start of the history")?;
+                    self.show_location(out)?;
+                }
+            },
+            "history" => match args.get(1) {
+                Some(n) => match parse_num(n) {
+                    Some(n) => {
+                        self.vm.set_history_depth(n as usize);
+                        writeln!(out, "Keeping history of {} instructions", n)?;
+                    },
+                    None => return bad_arg(out, n),
+                },
+                None => writeln!(out, "History holds {} of {} instructions",
+                    self.vm.history_len(), self.vm.history_depth())?,
+            },
+            "b" | "break" => match args.get(1) {
+                Some(a) => match self.parse_addr(a) {
+                    Some(addr) => {
+                        let id = self.add_breakpoint(addr, args.get(2).map(|s| s.to_string()));
+                        writeln!(out, "Breakpoint {} at {}", id, addr)?;
+                    },
+                    None => return bad_arg(out, a),
+                },
+                None => self.list_breakpoints(out)?,
+            },
+            "d" | "delete" => match args.get(1) {
+                Some(key) => match self.remove_breakpoint(key) {
+                    Some(bp) => writeln!(out, "Deleted breakpoint {} at {}", bp.id, bp.addr)?,
+                    None => writeln!(out, "No breakpoint {}", key)?,
+                },
+                None => writeln!(out, "Usage: delete ID|NAME")?,
+            },
+            "watch" => {
+                let addr = match args.get(1) {
+                    Some(loc) => match self.parse_location(loc) {
+                        Some(addr) => addr,
+                        None => return bad_arg(out, loc),
+                    },
+                    None => return self.list_watchpoints(out).map(|_| true),
+                };
+                let mut kind = WatchKind::Change;
+                let mut action = WatchAction::Break;
+                for opt in &args[2..] {
+                    match *opt {
+                        "read" => kind = WatchKind::Read,
+                        "write" => kind = WatchKind::Write,
+                        "change" => kind = WatchKind::Change,
+                        "log" => action = WatchAction::Log,
+                        _ => return bad_arg(out, opt),
+                    }
+                }
+                self.vm.add_watchpoint(Watchpoint { addr, kind, action });
+                writeln!(out, "Watching {} for {}", format_location(addr), kind)?;
+            },
+            "unwatch" => match args.get(1).and_then(|loc| self.parse_location(loc)) {
+                Some(addr) => {
+                    let n = self.vm.remove_watchpoints(addr);
+                    writeln!(out, "Removed {} watchpoint(s) on {}", n, format_location(addr))?;
+                },
+                None => writeln!(out, "Usage: unwatch ADDR|rN")?,
+            },
+            "r" | "regs" => self.show_registers(out)?,
+            "set" => {
+                if args.len() != 3 {
+                    writeln!(out, "Usage: set rN|pc VALUE")?;
+                    return Ok(true);
+                }
+                let val = match parse_num(args[2]) {
+                    Some(v) if v <= MAX_15_BIT_VAL => v as u16,
+                    _ => return bad_arg(out, args[2]),
+                };
+                if args[1] == "pc" {
+                    self.vm.set_pc(val);
+                    self.show_location(out)?;
+                }
+                else {
+                    match parse_reg(args[1]) {
+                        Some(r) => {
+                            if let Err(e) = self.vm.set_register(r, val) {
+                                writeln!(out, "{}", e)?;
+                            }
+                        },
+                        None => return bad_arg(out, args[1]),
+                    }
+                }
+            },
+            "x" => {
+                let addr = match args.get(1).and_then(|a| self.parse_addr(a)) {
+                    Some(addr) => addr,
+                    None => {
+                        writeln!(out, "Usage: x ADDR [N]")?;
+                        return Ok(true);
+                    },
+                };
+                let count = args.get(2).and_then(|n| parse_num(n)).unwrap_or(DUMP_WORDS as u64);
+                self.dump_memory(addr, count as usize, out)?;
+            },
+            "poke" => {
+                if args.len() < 3 {
+                    writeln!(out, "Usage: poke ADDR VALUE...")?;
+                    return Ok(true);
+                }
+                let addr = match self.parse_addr(args[1]) {
+                    Some(addr) => addr,
+                    None => return bad_arg(out, args[1]),
+                };
+                for (i, v) in args[2..].iter().enumerate() {
+                    let val = match parse_num(v) {
+                        Some(val) if val <= MAX_15_BIT_VAL => val as u16,
+                        _ => return bad_arg(out, v),
+                    };
+                    if let Err(e) = self.vm.mem_write(addr.wrapping_add(i as u16), val) {
+                        writeln!(out, "{}", e)?;
+                        break;
+                    }
+                }
+            },
+            "stack" => {
+                let stack = self.vm.stack();
+                if stack.is_empty() {
+                    writeln!(out, "Stack is empty")?;
+                }
+                for (depth, val) in stack.iter().rev().enumerate() {
+                    writeln!(out, "  #{:<3} {}", depth, val)?;
+                }
+            },
+            "bt" | "backtrace" | "where" => self.backtrace(out)?,
+            "l" | "disas" => {
+                let count = args.get(2).and_then(|n| parse_num(n)).map_or(DISAS_LINES, |n| n as usize);
+                let start = match args.get(1) {
+                    Some(a) => match self.parse_addr(a) {
+                        Some(addr) => addr,
+                        None => return bad_arg(out, a),
+                    },
+                    None => aligned_start(self.vm.memory(), self.vm.pc()),
+                };
+                self.disassemble(start, count, out)?;
+            },
+            "save" => match args.get(1) {
+                Some(path) => match self.vm.save_state(path) {
+                    Ok(()) => writeln!(out, "Saved state to {}", path)?,
+                    Err(e) => writeln!(out, "Could not save {}: {}", path, e)?,
+                },
+                None => writeln!(out, "Usage: save FILE")?,
+            },
+            "load" => match args.get(1) {
+                Some(path) => match self.vm.load_state(path) {
+                    Ok(()) => {
+                        self.last_error = None;
+                        writeln!(out, "Loaded state from {}", path)?;
+                        self.show_location(out)?;
+                    },
+                    Err(e) => writeln!(out, "Could not load {}: {}", path, e)?,
+                },
+                None => writeln!(out, "Usage: load FILE")?,
+            },
+            cmd => writeln!(out, "Unknown command '{}'. Try 'help'.", cmd)?,
+        }
+        Ok(true)
+    }
+
+    /// Prints why execution stopped and where.
+    pub fn report (&mut self, result: Result<StopReason, VmError>, out: &mut dyn Write) -> io::Result<()> {
+        match result {
+            Ok(StopReason::Halted) => writeln!(out, "Program halted after {} cycles", self.vm.cc())?,
+            Ok(StopReason::Breakpoint) => {
+                let pc = self.vm.pc();
+                match self.breakpoints.iter().find(|bp| bp.addr == pc) {
+                    Some(bp) => match bp.name {
+                        Some(ref name) => writeln!(out, "Breakpoint {} ({}) hit", bp.id, name)?,
+                        None => writeln!(out, "Breakpoint {} hit", bp.id)?,
+                    },
+                    None => writeln!(out, "Breakpoint hit")?,
+                }
+            },
+            Ok(StopReason::CycleLimit) => writeln!(out, "Cycle limit reached")?,
+            Ok(StopReason::Paused) => writeln!(out, "Paused at input prompt")?,
+            Ok(StopReason::ReplayEnd) => writeln!(out, "Replay stopped at input prompt")?,
+            Ok(StopReason::Watchpoint) => {
+                for hit in self.vm.watch_hits() {
+                    if hit.watchpoint.action == WatchAction::Break {
+                        writeln!(out, "Hit {}", hit)?;
+                    }
+                }
+            },
+            Ok(StopReason::Reached) => {},
+            Err(e) => {
+                writeln!(out, "Error: {}", e)?;
+                self.last_error = Some(e);
+            },
+        }
+        if !self.vm.is_halted() {
+            self.show_location(out)?;
+        }
+        Ok(())
+    }
+
+    fn show_location (&self, out: &mut dyn Write) -> io::Result<()> {
+        let pc = self.vm.pc();
+        match decode(self.vm.memory(), pc) {
+            Ok(instr) => writeln!(out, "=> {:5}: {}", pc, instr),
+            Err(e) => writeln!(out, "=> {:5}: ({})", pc, e),
+        }
+    }
+
+    fn show_registers (&self, out: &mut dyn Write) -> io::Result<()> {
+        for (i, val) in self.vm.registers().iter().enumerate() {
+            write!(out, "r{}={:<6}", i, val)?;
+            if i == 3 {
+                writeln!(out)?;
+            }
+        }
+        writeln!(out)?;
+        writeln!(out, "pc={} cc={} stack depth={}",
+            self.vm.pc(), self.vm.cc(), self.vm.stack().len())
+    }
+
+    fn list_breakpoints (&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.breakpoints.is_empty() {
+            return writeln!(out, "No breakpoints");
+        }
+        for bp in &self.breakpoints {
+            match bp.name {
+                Some(ref name) => writeln!(out, "{:3}  {:5}  {}", bp.id, bp.addr, name)?,
+                None => writeln!(out, "{:3}  {:5}", bp.id, bp.addr)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn list_watchpoints (&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.vm.watchpoints().is_empty() {
+            return writeln!(out, "No watchpoints");
+        }
+        for w in self.vm.watchpoints() {
+            let action = if w.action == WatchAction::Log { " (log)" } else { "" };
+            writeln!(out, "  {:8} {}{}", format_location(w.addr), w.kind, action)?;
+        }
+        Ok(())
+    }
+
+    fn dump_memory (&self, addr: u16, count: usize, out: &mut dyn Write) -> io::Result<()> {
+        let mem = self.vm.memory();
+        let start = addr as usize;
+        let end = start.saturating_add(count).min(mem.len());
+        for (row, chunk) in mem[start..end].chunks(DUMP_WORDS).enumerate() {
+            write!(out, "{:5}:", start + row * DUMP_WORDS)?;
+            for word in chunk {
+                write!(out, " {:5}", word)?;
+            }
+            let text: String = chunk.iter()
+                .map(|&w| if (32..127).contains(&w) { (w as u8) as char } else { '.' })
+                .collect();
+            writeln!(out, "  {}", text)?;
+        }
+        Ok(())
+    }
+
+    fn backtrace (&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "#0  {:5}", self.vm.pc())?;
+        for (depth, frame) in self.vm.call_stack().iter().rev().enumerate() {
+            writeln!(out, "#{:<2} {:5}  in call to {} from {}",
+                depth + 1, frame.return_addr, frame.target, frame.call_pc)?;
+        }
+        Ok(())
+    }
+
+    fn disassemble (&self, start: u16, count: usize, out: &mut dyn Write) -> io::Result<()> {
+        let pc = self.vm.pc();
+        let mem = self.vm.memory();
+        // No instruction is longer than 4 words
+        let end = (start as usize).saturating_add(count.saturating_mul(4)).min(mem.len());
+        let lines = disasm::disassemble(mem, start, end);
+        for line in lines.iter().take(count) {
+            let marker = if line.addr == pc { "=>" } else { "  " };
+            let bp = if self.vm.has_breakpoint(line.addr) { "*" } else { " " };
+            writeln!(out, "{}{}{}", marker, bp, line)?;
+        }
+        Ok(())
+    }
+
+    // Watch locations are registers rN or memory addresses
+    fn parse_location (&self, s: &str) -> Option<u16> {
+        match parse_reg(s) {
+            Some(r) => Some(32_768 + r),
+            None => self.parse_addr(s),
+        }
+    }
+
+    // Addresses may be numbers or breakpoint names
+    fn parse_addr (&self, s: &str) -> Option<u16> {
+        if let Some(bp) = self.breakpoints.iter().find(|bp| bp.name.as_ref().is_some_and(|n| n == s)) {
+            return Some(bp.addr);
+        }
+        match parse_num(s) {
+            Some(n) if n <= 32_767 => Some(n as u16),
+            _ => None,
+        }
+    }
+}
+
+fn bad_arg (out: &mut dyn Write, arg: &str) -> io::Result<bool> {
+    writeln!(out, "Invalid argument '{}'", arg)?;
+    Ok(true)
+}
+
+// Parses a decimal number, or a hexadecimal one prefixed with 0x
+fn parse_num (s: &str) -> Option<u64> {
+    if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16).ok()
+    }
+    else {
+        s.parse().ok()
+    }
+}
+
+fn parse_reg (s: &str) -> Option<u16> {
+    if !s.starts_with('r') {
+        return None;
+    }
+    match s[1..].parse() {
+        Ok(r) if r <= 7 => Some(r),
+        _ => None,
+    }
+}
+
+// Finds an address shortly before pc from which a linear sweep lands on
+// pc, so the lines leading up to it can be shown too
+fn aligned_start (mem: &[u16], pc: u16) -> u16 {
+    for start in pc.saturating_sub(DISAS_LOOKBEHIND)..pc {
+        let mut addr = start;
+        while addr < pc {
+            match decode(mem, addr) {
+                Ok(instr) => addr += instr.size(),
+                Err(_) => break,
+            }
+        }
+        if addr == pc {
+            return start;
+        }
+    }
+    pc
+}
@@ -0,0 +1,145 @@
+//! Diagnostics: messages from the VM about what it is doing, kept apart
+//! from the program's own output.
+//!
+//! Each message has a `Level`. Messages above the configured level are
+//! dropped before being formatted; the rest go to a `Sink`. The default
+//! sink discards everything, so a VM used as a library stays quiet.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+
+/// How important a message is, most important first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    /// Something failed.
+    Error,
+    /// Something looks wrong but execution continues.
+    Warn,
+    /// Replies to meta-commands and other messages for the player.
+    Info,
+    /// State dumps on errors, and notable events such as halting.
+    Debug,
+    /// Individual memory and register accesses.
+    Trace,
+}
+
+impl Level {
+    pub fn from_name (name: &str) -> Option<Level> {
+        match name {
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        })
+    }
+}
+
+/// Destination for diagnostics.
+pub trait Sink {
+    fn emit (&mut self, level: Level, msg: &str);
+}
+
+/// Discards every message.
+#[derive(Default)]
+pub struct NullSink;
+
+impl Sink for NullSink {
+    fn emit (&mut self, _level: Level, _msg: &str) {}
+}
+
+/// Writes messages to stderr, prefixed with their level unless they are
+/// `Info`.
+#[derive(Default)]
+pub struct StderrSink;
+
+impl Sink for StderrSink {
+    fn emit (&mut self, level: Level, msg: &str) {
+        let stderr = io::stderr();
+        let mut out = stderr.lock();
+        let _ = if level == Level::Info {
+            writeln!(out, "{}", msg)
+        }
+        else {
+            writeln!(out, "{}: {}", level, msg)
+        };
+    }
+}
+
+/// Collects messages in memory. Clones share the same buffer.
+#[derive(Clone, Default)]
+pub struct BufferSink {
+    messages: Rc<RefCell<Vec<(Level, String)>>>,
+}
+
+impl BufferSink {
+    /// Returns the messages collected so far and clears the buffer.
+    pub fn take (&self) -> Vec<(Level, String)> {
+        self.messages.borrow_mut().drain(..).collect()
+    }
+}
+
+impl Sink for BufferSink {
+    fn emit (&mut self, level: Level, msg: &str) {
+        self.messages.borrow_mut().push((level, msg.to_string()));
+    }
+}
+
+/// A sink and the most detailed level passed on to it.
+pub struct Diagnostics {
+    level: Level,
+    sink: Box<dyn Sink>,
+}
+
+impl Default for Diagnostics {
+    fn default() -> Diagnostics {
+        Diagnostics { level: Level::Info, sink: Box::new(NullSink) }
+    }
+}
+
+impl Diagnostics {
+    pub fn new (level: Level, sink: Box<dyn Sink>) -> Diagnostics {
+        Diagnostics { level, sink }
+    }
+
+    pub fn level (&self) -> Level {
+        self.level
+    }
+
+    pub fn set_level (&mut self, level: Level) {
+        self.level = level;
+    }
+
+    /// Replaces the sink, returning the previous one.
+    pub fn set_sink (&mut self, sink: Box<dyn Sink>) -> Box<dyn Sink> {
+        ::std::mem::replace(&mut self.sink, sink)
+    }
+
+    pub fn enabled (&self, level: Level) -> bool {
+        level <= self.level
+    }
+
+    /// Sends a message if its level is enabled. Use with `format_args!`
+    /// so that dropped messages are never formatted.
+    pub fn emit (&mut self, level: Level, args: fmt::Arguments) {
+        if self.enabled(level) {
+            self.sink.emit(level, &fmt::format(args));
+        }
+    }
+}
@@ -0,0 +1,121 @@
+//! Translates memory images back into assembly.
+//!
+//! Memory is decoded with a linear sweep using the shared decoder. Words
+//! that can't start a valid instruction are collected into `.data` lines.
+//! Output is accepted by the assembler, so disassembling and reassembling
+//! an image reproduces it exactly.
+
+use std::fmt;
+
+use instr::{decode, Instruction};
+
+// Maximum number of words on one .data line
+const DATA_WORDS_PER_LINE: usize = 8;
+
+/// One line of disassembly: an instruction or a run of data words.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    /// Address of the first word.
+    pub addr: u16,
+    /// The words covered by this line.
+    pub words: Vec<u16>,
+    /// The decoded instruction, or `None` for a `.data` line.
+    pub instr: Option<Instruction>,
+}
+
+impl fmt::Display for Line {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:5}: ", self.addr)?;
+        match self.instr {
+            Some(instr) => write!(f, "{}", instr),
+            None => {
+                write!(f, ".data")?;
+                for (i, &word) in self.words.iter().enumerate() {
+                    let sep = if i == 0 { " " } else { ", " };
+                    write!(f, "{}{}", sep, word)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Disassembles `mem[start..end]`. `end` may be the size of memory,
+/// 32768, which doesn't fit in an address.
+pub fn disassemble (mem: &[u16], start: u16, end: usize) -> Vec<Line> {
+    let end = end.min(mem.len());
+    let mut lines = Vec::new();
+    let mut data: Option<Line> = None;
+    let mut addr = start as usize;
+
+    while addr < end {
+        if let Ok(instr) = decode(&mem[..end], addr as u16) {
+            if let Some(line) = data.take() {
+                lines.push(line);
+            }
+            let len = instr.size() as usize;
+            lines.push(Line {
+                addr: addr as u16,
+                words: mem[addr..addr + len].to_vec(),
+                instr: Some(instr),
+            });
+            addr += len;
+            continue;
+        }
+
+        // Undecodable word, so append it to the current .data run
+        let full = data.as_ref().is_some_and(|line| line.words.len() == DATA_WORDS_PER_LINE);
+        if full {
+            lines.extend(data.take());
+        }
+        data.get_or_insert_with(|| Line { addr: addr as u16, words: vec![], instr: None })
+            .words.push(mem[addr]);
+        addr += 1;
+    }
+
+    lines.extend(data);
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    // Disassembles all of mem and assembles the result
+    fn round_trip (mem: &[u16]) -> Vec<u16> {
+        let src: Vec<String> = disassemble(mem, 0, mem.len()).iter().map(|l| l.to_string()).collect();
+        assemble(&src.join("\n")).unwrap()
+    }
+
+    #[test]
+    fn spec_example () {
+        let mem = [9, 32768, 32769, 4, 19, 32768];
+        assert_eq!(round_trip(&mem), mem);
+    }
+
+    #[test]
+    fn every_opcode () {
+        let mem = [
+            0, 1, 32768, 5, 2, 32775, 3, 32769, 4, 32770, 1, 2, 5, 32771, 32768, 7,
+            6, 40, 7, 32768, 12, 8, 0, 30, 9, 32772, 32773, 32767, 10, 32768, 3, 4,
+            11, 32769, 32768, 2, 12, 32768, 32769, 255, 13, 32768, 1, 2, 14, 32768, 32769,
+            15, 32768, 100, 16, 100, 32768, 17, 52, 18, 19, 65, 19, 32768, 20, 32774, 21,
+        ];
+        // Every word is part of an instruction, and all 22 opcodes appear
+        let codes: Vec<u16> = disassemble(&mem, 0, mem.len()).iter()
+            .map(|l| l.instr.expect("a .data line").opcode().code())
+            .collect();
+        assert!((0..22).all(|code| codes.contains(&code)));
+        assert_eq!(round_trip(&mem), mem);
+    }
+
+    #[test]
+    fn awkward_characters_and_data () {
+        // Quotes, backslashes, control characters, then words that
+        // aren't instructions: an unknown opcode, a bad operand and a
+        // truncated instruction
+        let mem = [19, 39, 19, 92, 19, 10, 19, 7, 19, 34, 22, 1, 40000, 5, 9, 32768];
+        assert_eq!(round_trip(&mem), mem);
+    }
+}
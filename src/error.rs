@@ -0,0 +1,121 @@
+//! Errors raised while loading or executing a program.
+
+use std::error::Error;
+use std::fmt;
+
+/// Everything that can go wrong inside the VM. Execution errors carry the
+/// address of the faulting instruction (`pc`) and the cycle count (`cc`)
+/// at which it was executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmError {
+    /// `pop` was executed with an empty stack.
+    StackUnderflow { pc: u16, cc: u64 },
+
+    /// The word at `addr` held `value`, which is not valid where it was used,
+    /// e.g. a literal where a register was required, or a number > 32775.
+    InvalidOperand { pc: u16, cc: u64, addr: u16, value: u16 },
+
+    /// The word at `pc` is not one of the 22 opcodes.
+    InvalidOpcode { pc: u16, cc: u64, opcode: u16 },
+
+    /// `out` was given a value that is not an ASCII code, or `in` read one.
+    InvalidAscii { pc: u16, cc: u64, value: u16 },
+
+    /// `in` was executed but there is no more input to read.
+    InputExhausted { pc: u16, cc: u64 },
+
+    /// An access outside memory and registers, i.e. above 32775.
+    InvalidAddress { pc: u16, cc: u64, addr: u16 },
+
+    /// A register ID outside 0..7.
+    InvalidRegister { pc: u16, cc: u64, reg: u16 },
+
+    /// Reading input or writing output failed.
+    Io { pc: u16, cc: u64, msg: String },
+
+    /// A replayed transcript expected input `line` (1-based) to be read,
+    /// or the recording to end, at cycle `expected`.
+    ReplayDiverged { pc: u16, cc: u64, line: usize, expected: u64 },
+
+    /// A replay reached the end of the recording with different output.
+    OutputMismatch { pc: u16, cc: u64, expected: u64, actual: u64 },
+
+    /// An empty program image was loaded.
+    EmptyImage,
+
+    /// A program image was larger than the 32768-word memory.
+    ImageTooLarge { len: usize },
+}
+
+impl VmError {
+    /// Address of the instruction that raised the error, if any.
+    pub fn pc (&self) -> Option<u16> {
+        match *self {
+            VmError::StackUnderflow { pc, .. } |
+            VmError::InvalidOperand { pc, .. } |
+            VmError::InvalidOpcode { pc, .. } |
+            VmError::InvalidAscii { pc, .. } |
+            VmError::InputExhausted { pc, .. } |
+            VmError::InvalidAddress { pc, .. } |
+            VmError::InvalidRegister { pc, .. } |
+            VmError::Io { pc, .. } |
+            VmError::ReplayDiverged { pc, .. } |
+            VmError::OutputMismatch { pc, .. } => Some(pc),
+            VmError::EmptyImage |
+            VmError::ImageTooLarge { .. } => None,
+        }
+    }
+
+    /// Cycle count at which the error was raised, if any.
+    pub fn cc (&self) -> Option<u64> {
+        match *self {
+            VmError::StackUnderflow { cc, .. } |
+            VmError::InvalidOperand { cc, .. } |
+            VmError::InvalidOpcode { cc, .. } |
+            VmError::InvalidAscii { cc, .. } |
+            VmError::InputExhausted { cc, .. } |
+            VmError::InvalidAddress { cc, .. } |
+            VmError::InvalidRegister { cc, .. } |
+            VmError::Io { cc, .. } |
+            VmError::ReplayDiverged { cc, .. } |
+            VmError::OutputMismatch { cc, .. } => Some(cc),
+            VmError::EmptyImage |
+            VmError::ImageTooLarge { .. } => None,
+        }
+    }
+}
+
+impl fmt::Display for VmError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VmError::StackUnderflow { pc, cc } =>
+                write!(f, "pop from empty stack at pc {}, cycle {}", pc, cc),
+            VmError::InvalidOperand { pc, cc, addr, value } =>
+                write!(f, "invalid operand {} at address {} (pc {}, cycle {})", value, addr, pc, cc),
+            VmError::InvalidOpcode { pc, cc, opcode } =>
+                write!(f, "unrecognised opcode {} at pc {}, cycle {}", opcode, pc, cc),
+            VmError::InvalidAscii { pc, cc, value } =>
+                write!(f, "invalid ASCII code {} at pc {}, cycle {}", value, pc, cc),
+            VmError::InputExhausted { pc, cc } =>
+                write!(f, "input exhausted at pc {}, cycle {}", pc, cc),
+            VmError::InvalidAddress { pc, cc, addr } =>
+                write!(f, "address {} out of range at pc {}, cycle {}", addr, pc, cc),
+            VmError::InvalidRegister { pc, cc, reg } =>
+                write!(f, "invalid register r{} at pc {}, cycle {}", reg, pc, cc),
+            VmError::Io { pc, cc, ref msg } =>
+                write!(f, "I/O error at pc {}, cycle {}: {}", pc, cc, msg),
+            VmError::ReplayDiverged { pc, cc, line, expected } =>
+                write!(f, "replay diverged at input {}: expected cycle {}, reached at pc {}, cycle {}",
+                    line, expected, pc, cc),
+            VmError::OutputMismatch { pc, cc, expected, actual } =>
+                write!(f, "replay output hash {:016x} doesn't match recorded {:016x} at pc {}, cycle {}",
+                    actual, expected, pc, cc),
+            VmError::EmptyImage =>
+                write!(f, "no memory loaded"),
+            VmError::ImageTooLarge { len } =>
+                write!(f, "image of {} words is larger than memory", len),
+        }
+    }
+}
+
+impl Error for VmError {}
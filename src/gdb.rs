@@ -0,0 +1,582 @@
+//! A stub speaking the GDB remote serial protocol, so that the VM can be
+//! debugged from gdb or any other client of the protocol.
+//!
+//! GDB addresses bytes while the VM addresses 16-bit words, so the stub
+//! presents a byte-addressed view:
+//!
+//! ```text
+//! 0x00000..0x0ffff   memory, word N at bytes 2N and 2N+1, little-endian
+//! 0x10000..          the stack, bottom entry first, 2 bytes per entry
+//! ```
+//!
+//! The registers are r0..r7 (16 bits each), then `pc` and a virtual `sp`
+//! (32 bits each). `pc` is the byte address of the next instruction and
+//! `sp` points just past the top of the stack, so its depth is
+//! `(sp - 0x10000) / 2`. The target description XML sent to the client
+//! spells this out.
+//!
+//! Supported are register and memory access, software breakpoints, write,
+//! read and access watchpoints on memory, single-step, continue and
+//! interrupting a running program with Ctrl-C. The program's own input
+//! and output still go through the VM's `Io` backend; a program waiting
+//! for input can't be interrupted.
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+use diag::Level;
+use vm::{StopReason, Vm};
+use watch::{WatchAction, WatchKind, Watchpoint};
+
+/// Byte address at which the stack appears.
+pub const STACK_BASE: u32 = 0x1_0000;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.synacor.core">
+    <reg name="r0" bitsize="16" type="uint16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="uint16"/>
+    <reg name="r2" bitsize="16" type="uint16"/>
+    <reg name="r3" bitsize="16" type="uint16"/>
+    <reg name="r4" bitsize="16" type="uint16"/>
+    <reg name="r5" bitsize="16" type="uint16"/>
+    <reg name="r6" bitsize="16" type="uint16"/>
+    <reg name="r7" bitsize="16" type="uint16"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+  </feature>
+</target>
+"#;
+
+const PC_REG: usize = 8;
+const SP_REG: usize = 9;
+
+// Largest value r0..r7 and the stack may be given
+const MAX_15_BIT_VAL: u32 = 32_767;
+
+// Instructions run between checks for an interrupt from the client
+const SLICE: u32 = 10_000;
+
+// Signal numbers used in stop replies
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+/// A connection to a debugger client.
+pub trait Connection: Read + Write {
+    /// Checks, without blocking, whether the client has sent an interrupt
+    /// (a 0x03 byte). Anything else received is discarded.
+    fn poll_interrupt (&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn poll_interrupt (&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let result = poll_byte(self);
+        self.set_nonblocking(false)?;
+        result
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn poll_interrupt (&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let result = poll_byte(self);
+        self.set_nonblocking(false)?;
+        result
+    }
+}
+
+// Reads whatever is waiting on a non-blocking stream, looking for 0x03
+fn poll_byte<R: Read> (input: &mut R) -> io::Result<bool> {
+    let mut buf = [0; 64];
+    match input.read(&mut buf) {
+        Ok(n) => Ok(buf[..n].contains(&0x03)),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// A GDB stub serving one client.
+pub struct GdbStub<C: Connection> {
+    vm: Vm,
+    conn: C,
+    // Cleared by QStartNoAckMode
+    ack: bool,
+    // Reply to '?', describing the last stop
+    last_stop: String,
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new (vm: Vm, conn: C) -> GdbStub<C> {
+        GdbStub { vm, conn, ack: true, last_stop: format!("S{:02x}", SIGTRAP) }
+    }
+
+    pub fn vm (&self) -> &Vm {
+        &self.vm
+    }
+
+    pub fn into_vm (self) -> Vm {
+        self.vm
+    }
+
+    /// Serves requests until the client detaches, kills the program or
+    /// closes the connection.
+    pub fn serve (&mut self) -> io::Result<()> {
+        while let Some(packet) = self.read_packet()? {
+            let packet = String::from_utf8_lossy(&packet).into_owned();
+            match self.handle(&packet)? {
+                Some(reply) => self.send(reply.as_bytes())?,
+                None => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the reply to a packet, or None to end the session
+    fn handle (&mut self, packet: &str) -> io::Result<Option<String>> {
+        let (cmd, rest) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+        let reply = match cmd {
+            "?" => self.last_stop.clone(),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(rest),
+            "p" => match usize::from_str_radix(rest, 16) {
+                Ok(n) if n <= SP_REG => encode_reg(n, self.register(n)),
+                _ => error(0),
+            },
+            "P" => self.write_register(rest),
+            "m" => self.read_memory(rest),
+            "M" => self.write_memory(rest),
+            "c" | "C" => {
+                self.resume_at(cmd, rest);
+                self.resume(false)?
+            },
+            "s" | "S" => {
+                self.resume_at(cmd, rest);
+                self.resume(true)?
+            },
+            "v" => self.handle_v(rest)?,
+            "Z" => self.set_point(rest, true),
+            "z" => self.set_point(rest, false),
+            "q" => self.handle_query(rest),
+            "Q" if rest == "StartNoAckMode" => {
+                // This packet has been acknowledged already
+                self.ack = false;
+                "OK".to_string()
+            },
+            // Only one thread, so selecting or probing it always works
+            "H" | "T" => "OK".to_string(),
+            "D" => {
+                self.send(b"OK")?;
+                return Ok(None);
+            },
+            "k" => return Ok(None),
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn handle_query (&mut self, query: &str) -> String {
+        if query.starts_with("Supported") {
+            "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string()
+        }
+        else if let Some(args) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_pair(args, ',') {
+                Some((offset, len)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + len as usize).min(TARGET_XML.len());
+                    let marker = if end == TARGET_XML.len() { "l" } else { "m" };
+                    format!("{}{}", marker, escape(&TARGET_XML[offset..end]))
+                },
+                None => error(0),
+            }
+        }
+        else {
+            match query {
+                "Attached" => "1".to_string(),
+                "C" => "QC1".to_string(),
+                "fThreadInfo" => "m1".to_string(),
+                "sThreadInfo" => "l".to_string(),
+                _ => String::new(),
+            }
+        }
+    }
+
+    fn handle_v (&mut self, packet: &str) -> io::Result<String> {
+        if packet == "Cont?" {
+            return Ok("vCont;c;C;s;S".to_string());
+        }
+        match packet.strip_prefix("Cont;") {
+            // With a single thread only the first action matters
+            Some(actions) => Ok(match actions.chars().next() {
+                Some('c') | Some('C') => self.resume(false)?,
+                Some('s') | Some('S') => self.resume(true)?,
+                _ => error(0),
+            }),
+            None => Ok(String::new()),
+        }
+    }
+
+    fn register (&self, n: usize) -> u32 {
+        match n {
+            PC_REG => u32::from(self.vm.pc()) * 2,
+            SP_REG => STACK_BASE + self.vm.stack().len() as u32 * 2,
+            _ => u32::from(self.vm.registers()[n]),
+        }
+    }
+
+    fn set_register (&mut self, n: usize, val: u32) -> bool {
+        match n {
+            PC_REG if val < STACK_BASE && val.is_multiple_of(2) => {
+                self.vm.set_pc((val / 2) as u16);
+                true
+            },
+            // The stack depth only changes by running the program
+            SP_REG => val == self.register(SP_REG),
+            _ if n < PC_REG && val <= MAX_15_BIT_VAL => self.vm.set_register(n as u16, val as u16).is_ok(),
+            _ => false,
+        }
+    }
+
+    fn read_registers (&self) -> String {
+        (0..=SP_REG).map(|n| encode_reg(n, self.register(n))).collect()
+    }
+
+    fn write_registers (&mut self, hex: &str) -> String {
+        let mut at = 0;
+        for n in 0..=SP_REG {
+            let width = reg_size(n) * 2;
+            let val = match hex.get(at..at + width).and_then(decode_reg) {
+                Some(val) => val,
+                None => return error(0),
+            };
+            if !self.set_register(n, val) {
+                return error(1);
+            }
+            at += width;
+        }
+        "OK".to_string()
+    }
+
+    fn write_register (&mut self, args: &str) -> String {
+        let (n, val) = match args.split_once('=') {
+            Some((n, val)) => (usize::from_str_radix(n, 16).ok(), decode_reg(val)),
+            None => return error(0),
+        };
+        match (n, val) {
+            (Some(n), Some(val)) if n <= SP_REG => {
+                if self.set_register(n, val) { "OK".to_string() } else { error(1) }
+            },
+            _ => error(0),
+        }
+    }
+
+    fn read_byte (&self, addr: u32) -> Option<u8> {
+        // Read memory directly, so looking at it fires no watchpoints
+        let word = if addr < STACK_BASE {
+            self.vm.memory()[(addr / 2) as usize]
+        }
+        else {
+            *self.vm.stack().get(((addr - STACK_BASE) / 2) as usize)?
+        };
+        Some(if addr.is_multiple_of(2) { word as u8 } else { (word >> 8) as u8 })
+    }
+
+    fn write_byte (&mut self, addr: u32, byte: u8) -> bool {
+        let old = if addr < STACK_BASE {
+            self.vm.memory()[(addr / 2) as usize]
+        }
+        else {
+            match self.vm.stack().get(((addr - STACK_BASE) / 2) as usize) {
+                Some(&val) => val,
+                None => return false,
+            }
+        };
+        let word = if addr.is_multiple_of(2) {
+            (old & 0xff00) | u16::from(byte)
+        }
+        else {
+            (old & 0x00ff) | (u16::from(byte) << 8)
+        };
+        if addr < STACK_BASE {
+            self.vm.mem_write((addr / 2) as u16, word).is_ok()
+        }
+        else if word > MAX_15_BIT_VAL as u16 {
+            // Stack values are popped into registers, so they too must
+            // fit in 15 bits
+            false
+        }
+        else {
+            let depth = self.vm.stack().len() - 1 - ((addr - STACK_BASE) / 2) as usize;
+            self.vm.set_stack(depth, word).is_ok()
+        }
+    }
+
+    fn read_memory (&mut self, args: &str) -> String {
+        let (addr, len) = match parse_pair(args, ',') {
+            Some(pair) => pair,
+            None => return error(0),
+        };
+        // Reply with as much as is readable, failing only if none is
+        let mut reply = String::new();
+        for i in 0..len {
+            match self.read_byte(addr.wrapping_add(i)) {
+                Some(byte) => reply.push_str(&format!("{:02x}", byte)),
+                None => break,
+            }
+        }
+        if reply.is_empty() && len > 0 { error(1) } else { reply }
+    }
+
+    fn write_memory (&mut self, args: &str) -> String {
+        let (pair, bytes) = match args.split_once(':') {
+            Some((pair, bytes)) => (parse_pair(pair, ','), decode_hex(bytes)),
+            None => return error(0),
+        };
+        let (addr, bytes) = match (pair, bytes) {
+            (Some((addr, len)), Some(bytes)) if bytes.len() == len as usize => (addr, bytes),
+            _ => return error(0),
+        };
+        for (i, &byte) in bytes.iter().enumerate() {
+            if !self.write_byte(addr.wrapping_add(i as u32), byte) {
+                return error(1);
+            }
+        }
+        "OK".to_string()
+    }
+
+    // Handles the optional resume address of c, C, s and S
+    fn resume_at (&mut self, cmd: &str, args: &str) {
+        // C and S start with a signal number, which is ignored
+        let addr = if cmd == "C" || cmd == "S" {
+            args.split_once(';').map(|(_, addr)| addr)
+        }
+        else if args.is_empty() {
+            None
+        }
+        else {
+            Some(args)
+        };
+        if let Some(addr) = addr.and_then(|a| u32::from_str_radix(a, 16).ok()) {
+            self.set_register(PC_REG, addr);
+        }
+    }
+
+    fn set_point (&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        let kind = fields.next();
+        let addr = fields.next().and_then(|a| u32::from_str_radix(a, 16).ok());
+        let len = fields.next().and_then(|n| u32::from_str_radix(n, 16).ok());
+        let (addr, len) = match (addr, len) {
+            (Some(addr), Some(len)) => (addr, len.max(1)),
+            _ => return error(0),
+        };
+        let end = match addr.checked_add(len) {
+            Some(end) if end <= STACK_BASE => end,
+            _ => return error(1),
+        };
+
+        let kinds: &[WatchKind] = match kind {
+            Some("0") | Some("1") => {
+                let word = (addr / 2) as u16;
+                if insert {
+                    self.vm.add_breakpoint(word);
+                }
+                else {
+                    self.vm.remove_breakpoint(word);
+                }
+                return "OK".to_string();
+            },
+            Some("2") => &[WatchKind::Write],
+            Some("3") => &[WatchKind::Read],
+            Some("4") => &[WatchKind::Read, WatchKind::Write],
+            _ => return String::new(),
+        };
+        for word in addr / 2..=(end - 1) / 2 {
+            let word = word as u16;
+            if insert {
+                for &kind in kinds {
+                    self.vm.add_watchpoint(Watchpoint { addr: word, kind, action: WatchAction::Break });
+                }
+            }
+            else {
+                // Keep the watchpoints of other kinds on the same word
+                let keep: Vec<Watchpoint> = self.vm.watchpoints().iter()
+                    .filter(|w| w.addr == word && !kinds.contains(&w.kind))
+                    .cloned()
+                    .collect();
+                self.vm.remove_watchpoints(word);
+                for w in keep {
+                    self.vm.add_watchpoint(w);
+                }
+            }
+        }
+        "OK".to_string()
+    }
+
+    // Steps or continues, returning the stop reply
+    fn resume (&mut self, single: bool) -> io::Result<String> {
+        if self.vm.is_halted() {
+            return Ok("W00".to_string());
+        }
+        let result = if single {
+            self.vm.step().map(|_| StopReason::Reached)
+        }
+        else {
+            loop {
+                let mut budget = SLICE;
+                match self.vm.run_until(|_| { budget -= 1; budget == 0 }) {
+                    Ok(StopReason::Reached) => if self.conn.poll_interrupt()? {
+                        break Ok(StopReason::Paused);
+                    },
+                    result => break result,
+                }
+            }
+        };
+
+        let reply = match result {
+            Err(e) => {
+                self.vm.diagnostics_mut().emit(Level::Error, format_args!("{}", e));
+                stop_reply(SIGILL)
+            },
+            Ok(_) if self.vm.is_halted() => "W00".to_string(),
+            Ok(StopReason::Paused) => stop_reply(SIGINT),
+            Ok(_) if self.vm.pause_requested() => stop_reply(SIGINT),
+            Ok(_) => self.watch_reply().unwrap_or_else(|| stop_reply(SIGTRAP)),
+        };
+        self.last_stop = reply.clone();
+        Ok(reply)
+    }
+
+    // The stop reply for the first watchpoint that fired, if any
+    fn watch_reply (&self) -> Option<String> {
+        let hit = self.vm.watch_hits().iter().find(|hit| hit.watchpoint.action == WatchAction::Break)?;
+        let kind = match hit.watchpoint.kind {
+            WatchKind::Read => "rwatch",
+            WatchKind::Write | WatchKind::Change => "watch",
+        };
+        Some(format!("T{:02x}{}:{:x};", SIGTRAP, kind, u32::from(hit.watchpoint.addr) * 2))
+    }
+
+    // Reads the next packet, acknowledging it. Returns None when the
+    // connection is closed.
+    fn read_packet (&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            // Skip acks and interrupts sent while stopped
+            match self.read_u8()? {
+                Some(b'$') => {},
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+            let mut data = vec![];
+            loop {
+                match self.read_u8()? {
+                    Some(b'#') => break,
+                    Some(b'}') => match self.read_u8()? {
+                        Some(b) => data.push(b ^ 0x20),
+                        None => return Ok(None),
+                    },
+                    Some(b) => data.push(b),
+                    None => return Ok(None),
+                }
+            }
+            let mut sum = [0; 2];
+            for b in &mut sum {
+                *b = match self.read_u8()? {
+                    Some(b) => b,
+                    None => return Ok(None),
+                };
+            }
+            let expected = u8::from_str_radix(&String::from_utf8_lossy(&sum), 16).ok();
+            let valid = expected == Some(checksum(&data));
+            if self.ack {
+                self.conn.write_all(if valid { b"+" } else { b"-" })?;
+                self.conn.flush()?;
+            }
+            if valid {
+                return Ok(Some(data));
+            }
+        }
+    }
+
+    fn read_u8 (&mut self) -> io::Result<Option<u8>> {
+        let mut buf = [0];
+        loop {
+            match self.conn.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(buf[0])),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn send (&mut self, data: &[u8]) -> io::Result<()> {
+        write!(self.conn, "${}#{:02x}", String::from_utf8_lossy(data), checksum(data))?;
+        self.conn.flush()
+    }
+}
+
+fn stop_reply (signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn error (code: u8) -> String {
+    format!("E{:02x}", code)
+}
+
+fn checksum (data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+// Escapes the characters with a meaning in packets
+fn escape (s: &str) -> String {
+    let mut out = String::new();
+    for ch in s.chars() {
+        if "#$}*".contains(ch) {
+            out.push('}');
+            out.push((ch as u8 ^ 0x20) as char);
+        }
+        else {
+            out.push(ch);
+        }
+    }
+    out
+}
+
+fn reg_size (n: usize) -> usize {
+    if n < PC_REG { 2 } else { 4 }
+}
+
+// Registers are sent as little-endian hex bytes
+fn encode_reg (n: usize, val: u32) -> String {
+    (0..reg_size(n)).map(|i| format!("{:02x}", (val >> (8 * i)) as u8)).collect()
+}
+
+fn decode_reg (hex: &str) -> Option<u32> {
+    let bytes = decode_hex(hex)?;
+    if bytes.is_empty() || bytes.len() > 4 {
+        return None;
+    }
+    Some(bytes.iter().rev().fold(0, |val, &b| (val << 8) | u32::from(b)))
+}
+
+fn decode_hex (hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+// Parses "A<sep>B" with both numbers in hex
+fn parse_pair (s: &str, sep: char) -> Option<(u32, u32)> {
+    let (a, b) = s.split_once(sep)?;
+    Some((u32::from_str_radix(a, 16).ok()?, u32::from_str_radix(b, 16).ok()?))
+}
@@ -0,0 +1,80 @@
+//! A bounded log of what recent instructions changed, so that execution
+//! can be stepped backwards.
+
+use std::collections::VecDeque;
+
+use transcript::OutputHash;
+use vm::Frame;
+
+/// How to reverse one change made by an instruction.
+#[derive(Debug, Clone)]
+pub(crate) enum Undo {
+    /// Set a register back to the value it held.
+    Reg(u8, u16),
+    /// Set a memory word back to the value it held.
+    Mem(u16, u16),
+    /// Remove a value that was pushed.
+    Pop,
+    /// Put back a value that was popped.
+    Push(u16),
+    /// Remove a call frame that was entered.
+    PopFrame,
+    /// Put back the call frames as they were before a `ret`.
+    Frames(Vec<Frame>),
+    /// Put back input consumed by `in`.
+    Input(String),
+    OutHash(OutputHash),
+}
+
+/// The changes made by one instruction, and where it was executed.
+#[derive(Debug, Clone)]
+pub(crate) struct UndoEntry {
+    pub pc: u16,
+    pub cc: u64,
+    pub halt: bool,
+    pub changes: Vec<Undo>,
+}
+
+/// Undo entries for the most recent instructions, oldest first.
+#[derive(Debug, Default)]
+pub(crate) struct History {
+    entries: VecDeque<UndoEntry>,
+    depth: usize,
+}
+
+impl History {
+    pub fn depth (&self) -> usize {
+        self.depth
+    }
+
+    /// Limits the history to `depth` instructions, dropping the oldest
+    /// entries if there are more. 0 turns it off.
+    pub fn set_depth (&mut self, depth: usize) {
+        self.depth = depth;
+        while self.entries.len() > depth {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn len (&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn push (&mut self, entry: UndoEntry) {
+        if self.depth == 0 {
+            return;
+        }
+        if self.entries.len() == self.depth {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+
+    pub fn pop (&mut self) -> Option<UndoEntry> {
+        self.entries.pop_back()
+    }
+
+    pub fn clear (&mut self) {
+        self.entries.clear();
+    }
+}
@@ -0,0 +1,38 @@
+//! Conversion between the little-endian on-disk program format and the
+//! 16-bit words loaded into VM memory.
+
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Converts little-endian byte pairs into 16-bit words. A trailing odd
+/// byte is ignored.
+pub fn words_from_bytes (buf: &[u8]) -> Vec<u16> {
+    let mut data = vec![0; buf.len() / 2];
+    LittleEndian::read_u16_into(&buf[..data.len() * 2], &mut data);
+    data
+}
+
+/// Converts 16-bit words into little-endian byte pairs.
+pub fn bytes_from_words (data: &[u16]) -> Vec<u8> {
+    let mut buf = vec![0; data.len() * 2];
+    LittleEndian::write_u16_into(data, &mut buf);
+    buf
+}
+
+/// Reads a program image from `path`, ready to pass to `Vm::load_mem`.
+pub fn read_image<P: AsRef<Path>> (path: P) -> io::Result<Vec<u16>> {
+    let mut f = File::open(path)?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    Ok(words_from_bytes(&buf))
+}
+
+/// Writes `data` to `path` in the on-disk program format.
+pub fn write_image<P: AsRef<Path>> (path: P, data: &[u16]) -> io::Result<()> {
+    let mut f = File::create(path)?;
+    f.write_all(&bytes_from_words(data))
+}
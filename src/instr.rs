@@ -0,0 +1,237 @@
+//! Decoding of single instructions.
+//!
+//! `decode` is the one place memory words are turned into instructions.
+//! The executor, disassembler and debugging tools all go through it.
+
+use std::error::Error;
+use std::fmt;
+
+use opcode::Opcode;
+
+const MAX_LITERAL: u16 = 32_767;
+const REG_BASE: u16 = 32_768;
+const MAX_REG_ID: u16 = 7;
+
+/// An instruction argument: a literal 0..32767 or a register r0..r7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Literal(u16),
+    Register(u8),
+}
+
+impl Operand {
+    /// Interprets a memory word as an operand. Words above 32775 are invalid.
+    pub fn from_word (word: u16) -> Option<Operand> {
+        if word <= MAX_LITERAL {
+            Some(Operand::Literal(word))
+        }
+        else if word - REG_BASE <= MAX_REG_ID {
+            Some(Operand::Register((word - REG_BASE) as u8))
+        }
+        else {
+            None
+        }
+    }
+
+    /// The memory word encoding this operand.
+    pub fn word (self) -> u16 {
+        match self {
+            Operand::Literal(n) => n,
+            Operand::Register(r) => REG_BASE + u16::from(r),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Operand::Literal(n) => write!(f, "{}", n),
+            Operand::Register(r) => write!(f, "r{}", r),
+        }
+    }
+}
+
+/// A decoded instruction. Operands are in the order given in `arch-spec`,
+/// so the first operand of e.g. `Add` is the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Halt,
+    Set(Operand, Operand),
+    Push(Operand),
+    Pop(Operand),
+    Eq(Operand, Operand, Operand),
+    Gt(Operand, Operand, Operand),
+    Jmp(Operand),
+    Jt(Operand, Operand),
+    Jf(Operand, Operand),
+    Add(Operand, Operand, Operand),
+    Mult(Operand, Operand, Operand),
+    Mod(Operand, Operand, Operand),
+    And(Operand, Operand, Operand),
+    Or(Operand, Operand, Operand),
+    Not(Operand, Operand),
+    Rmem(Operand, Operand),
+    Wmem(Operand, Operand),
+    Call(Operand),
+    Ret,
+    Out(Operand),
+    In(Operand),
+    Noop,
+}
+
+impl Instruction {
+    pub fn opcode (&self) -> Opcode {
+        match *self {
+            Instruction::Halt => Opcode::Halt,
+            Instruction::Set(..) => Opcode::Set,
+            Instruction::Push(..) => Opcode::Push,
+            Instruction::Pop(..) => Opcode::Pop,
+            Instruction::Eq(..) => Opcode::Eq,
+            Instruction::Gt(..) => Opcode::Gt,
+            Instruction::Jmp(..) => Opcode::Jmp,
+            Instruction::Jt(..) => Opcode::Jt,
+            Instruction::Jf(..) => Opcode::Jf,
+            Instruction::Add(..) => Opcode::Add,
+            Instruction::Mult(..) => Opcode::Mult,
+            Instruction::Mod(..) => Opcode::Mod,
+            Instruction::And(..) => Opcode::And,
+            Instruction::Or(..) => Opcode::Or,
+            Instruction::Not(..) => Opcode::Not,
+            Instruction::Rmem(..) => Opcode::Rmem,
+            Instruction::Wmem(..) => Opcode::Wmem,
+            Instruction::Call(..) => Opcode::Call,
+            Instruction::Ret => Opcode::Ret,
+            Instruction::Out(..) => Opcode::Out,
+            Instruction::In(..) => Opcode::In,
+            Instruction::Noop => Opcode::Noop,
+        }
+    }
+
+    /// The operands, in encoding order.
+    pub fn operands (&self) -> Vec<Operand> {
+        match *self {
+            Instruction::Halt | Instruction::Ret | Instruction::Noop => vec![],
+            Instruction::Push(a) | Instruction::Pop(a) | Instruction::Jmp(a) |
+            Instruction::Call(a) | Instruction::Out(a) | Instruction::In(a) => vec![a],
+            Instruction::Set(a, b) | Instruction::Jt(a, b) | Instruction::Jf(a, b) |
+            Instruction::Not(a, b) | Instruction::Rmem(a, b) | Instruction::Wmem(a, b) => vec![a, b],
+            Instruction::Eq(a, b, c) | Instruction::Gt(a, b, c) | Instruction::Add(a, b, c) |
+            Instruction::Mult(a, b, c) | Instruction::Mod(a, b, c) | Instruction::And(a, b, c) |
+            Instruction::Or(a, b, c) => vec![a, b, c],
+        }
+    }
+
+    /// Length in words, including the opcode.
+    pub fn size (&self) -> u16 {
+        1 + self.opcode().arity()
+    }
+
+    /// The memory words encoding this instruction.
+    pub fn words (&self) -> Vec<u16> {
+        let mut words = vec![self.opcode().code()];
+        words.extend(self.operands().iter().map(|op| op.word()));
+        words
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.opcode().mnemonic())?;
+        for op in self.operands() {
+            match (*self, op) {
+                (Instruction::Out(_), Operand::Literal(n)) => write!(f, " {}", format_char(n))?,
+                _ => write!(f, " {}", op)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Renders an `out` literal as a character literal where possible.
+pub fn format_char (word: u16) -> String {
+    match word {
+        10 => "'\\n'".to_string(),
+        9 => "'\\t'".to_string(),
+        39 => "'\\''".to_string(),
+        92 => "'\\\\'".to_string(),
+        32..=126 => format!("'{}'", (word as u8) as char),
+        _ => word.to_string(),
+    }
+}
+
+/// Why the words at an address don't form an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The word at `addr` is not an opcode.
+    InvalidOpcode { addr: u16, opcode: u16 },
+    /// The operand word at `addr` is above 32775.
+    InvalidOperand { addr: u16, value: u16 },
+    /// The instruction at `addr` runs past the end of memory.
+    Truncated { addr: u16 },
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::InvalidOpcode { addr, opcode } =>
+                write!(f, "invalid opcode {} at address {}", opcode, addr),
+            DecodeError::InvalidOperand { addr, value } =>
+                write!(f, "invalid operand {} at address {}", value, addr),
+            DecodeError::Truncated { addr } =>
+                write!(f, "instruction at address {} runs past the end of memory", addr),
+        }
+    }
+}
+
+impl Error for DecodeError {}
+
+/// Decodes the instruction starting at `mem[addr]`.
+pub fn decode (mem: &[u16], addr: u16) -> Result<Instruction, DecodeError> {
+    let word = match mem.get(addr as usize) {
+        Some(&word) => word,
+        None => return Err(DecodeError::Truncated { addr }),
+    };
+    let op = match Opcode::from_u16(word) {
+        Some(op) => op,
+        None => return Err(DecodeError::InvalidOpcode { addr, opcode: word }),
+    };
+
+    let mut args = [Operand::Literal(0); 3];
+    for (i, arg) in args.iter_mut().enumerate().take(op.arity() as usize) {
+        let arg_addr = addr as usize + 1 + i;
+        let word = match mem.get(arg_addr) {
+            Some(&word) => word,
+            None => return Err(DecodeError::Truncated { addr }),
+        };
+        *arg = match Operand::from_word(word) {
+            Some(operand) => operand,
+            None => return Err(DecodeError::InvalidOperand { addr: arg_addr as u16, value: word }),
+        };
+    }
+    let [a, b, c] = args;
+
+    Ok(match op {
+        Opcode::Halt => Instruction::Halt,
+        Opcode::Set => Instruction::Set(a, b),
+        Opcode::Push => Instruction::Push(a),
+        Opcode::Pop => Instruction::Pop(a),
+        Opcode::Eq => Instruction::Eq(a, b, c),
+        Opcode::Gt => Instruction::Gt(a, b, c),
+        Opcode::Jmp => Instruction::Jmp(a),
+        Opcode::Jt => Instruction::Jt(a, b),
+        Opcode::Jf => Instruction::Jf(a, b),
+        Opcode::Add => Instruction::Add(a, b, c),
+        Opcode::Mult => Instruction::Mult(a, b, c),
+        Opcode::Mod => Instruction::Mod(a, b, c),
+        Opcode::And => Instruction::And(a, b, c),
+        Opcode::Or => Instruction::Or(a, b, c),
+        Opcode::Not => Instruction::Not(a, b),
+        Opcode::Rmem => Instruction::Rmem(a, b),
+        Opcode::Wmem => Instruction::Wmem(a, b),
+        Opcode::Call => Instruction::Call(a),
+        Opcode::Ret => Instruction::Ret,
+        Opcode::Out => Instruction::Out(a),
+        Opcode::In => Instruction::In(a),
+        Opcode::Noop => Instruction::Noop,
+    })
+}
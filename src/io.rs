@@ -0,0 +1,176 @@
+//! Backends for the `in` and `out` opcodes.
+//!
+//! The VM talks to the outside world one character at a time on output
+//! and one line at a time on input, through the `Io` trait. `StdIo` is
+//! the terminal, `BufferIo` feeds canned input and captures output in
+//! memory, `ScriptIo` plays a list of commands before handing over to
+//! another backend, and `ChannelIo` connects the VM to another thread.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{Receiver, Sender};
+
+/// Source of input lines and sink for output characters.
+pub trait Io {
+    /// Writes a character produced by `out`.
+    fn write_char (&mut self, ch: char) -> io::Result<()>;
+
+    /// Appends the next line of input, including its trailing newline, to
+    /// `buf`. Returns the number of bytes read, 0 meaning end of input.
+    fn read_line (&mut self, buf: &mut String) -> io::Result<usize>;
+}
+
+/// Reads from stdin and writes to stdout.
+#[derive(Default)]
+pub struct StdIo;
+
+impl Io for StdIo {
+    fn write_char (&mut self, ch: char) -> io::Result<()> {
+        write!(io::stdout(), "{}", ch)
+    }
+
+    fn read_line (&mut self, buf: &mut String) -> io::Result<usize> {
+        // Make sure any prompt is visible before blocking
+        io::stdout().flush()?;
+        let stdin = io::stdin();
+        let n = stdin.lock().read_line(buf)?;
+        Ok(n)
+    }
+}
+
+/// In-memory input and output. Clones share the same buffers, so a
+/// clone kept by the caller can inspect output after the VM has run.
+///
+/// ```no_run
+/// use synacor::{Vm, image};
+/// use synacor::io::BufferIo;
+///
+/// let io = BufferIo::new("look\n");
+/// let mut vm = Vm::with_io(Box::new(io.clone()));
+/// vm.load_mem(&image::read_image("challenge.bin").unwrap()).unwrap();
+/// let _ = vm.run();
+/// assert!(io.output().contains("Foothills"));
+/// ```
+#[derive(Clone, Default)]
+pub struct BufferIo {
+    input: Rc<RefCell<VecDeque<String>>>,
+    output: Rc<RefCell<String>>,
+}
+
+impl BufferIo {
+    /// Creates a buffer whose input is the lines of `input`.
+    pub fn new (input: &str) -> BufferIo {
+        let io = BufferIo::default();
+        io.push_input(input);
+        io
+    }
+
+    /// Queues more input. A missing final newline is added.
+    pub fn push_input (&self, input: &str) {
+        let mut queue = self.input.borrow_mut();
+        for line in input.lines() {
+            queue.push_back(format!("{}\n", line));
+        }
+    }
+
+    /// Everything written so far.
+    pub fn output (&self) -> String {
+        self.output.borrow().clone()
+    }
+
+    /// Returns everything written so far and clears the output buffer.
+    pub fn take_output (&self) -> String {
+        let mut output = self.output.borrow_mut();
+        let taken = output.clone();
+        output.clear();
+        taken
+    }
+}
+
+impl Io for BufferIo {
+    fn write_char (&mut self, ch: char) -> io::Result<()> {
+        self.output.borrow_mut().push(ch);
+        Ok(())
+    }
+
+    fn read_line (&mut self, buf: &mut String) -> io::Result<usize> {
+        match self.input.borrow_mut().pop_front() {
+            Some(line) => {
+                buf.push_str(&line);
+                Ok(line.len())
+            },
+            None => Ok(0),
+        }
+    }
+}
+
+/// Feeds the lines of a script as input, then falls back to another
+/// backend once the script runs out. Output always goes to the fallback.
+pub struct ScriptIo {
+    script: VecDeque<String>,
+    inner: Box<dyn Io>,
+}
+
+impl ScriptIo {
+    pub fn new (script: &str, inner: Box<dyn Io>) -> ScriptIo {
+        let script = script.lines().map(|line| format!("{}\n", line)).collect();
+        ScriptIo { script, inner }
+    }
+
+    /// Number of script lines not yet consumed.
+    pub fn remaining (&self) -> usize {
+        self.script.len()
+    }
+}
+
+impl Io for ScriptIo {
+    fn write_char (&mut self, ch: char) -> io::Result<()> {
+        self.inner.write_char(ch)
+    }
+
+    fn read_line (&mut self, buf: &mut String) -> io::Result<usize> {
+        match self.script.pop_front() {
+            Some(line) => {
+                buf.push_str(&line);
+                Ok(line.len())
+            },
+            None => self.inner.read_line(buf),
+        }
+    }
+}
+
+/// Input lines arrive on a channel and output characters are sent on
+/// another. A closed input channel is end of input.
+pub struct ChannelIo {
+    input: Receiver<String>,
+    output: Sender<char>,
+}
+
+impl ChannelIo {
+    pub fn new (input: Receiver<String>, output: Sender<char>) -> ChannelIo {
+        ChannelIo { input, output }
+    }
+}
+
+impl Io for ChannelIo {
+    fn write_char (&mut self, ch: char) -> io::Result<()> {
+        self.output.send(ch)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "output channel closed"))
+    }
+
+    fn read_line (&mut self, buf: &mut String) -> io::Result<usize> {
+        match self.input.recv() {
+            Ok(mut line) => {
+                if !line.ends_with('\n') {
+                    line.push('\n');
+                }
+                buf.push_str(&line);
+                Ok(line.len())
+            },
+            Err(_) => Ok(0),
+        }
+    }
+}
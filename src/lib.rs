@@ -0,0 +1,40 @@
+//! A virtual machine for the Synacor Challenge architecture.
+//!
+//! The `Vm` type holds the full machine state and can be driven one
+//! instruction at a time with `step()` or until it halts with `run()`.
+//! Both return a `VmError` describing where execution went wrong, so a
+//! failure can be reported or inspected instead of aborting the process.
+
+extern crate byteorder;
+extern crate flate2;
+extern crate regex;
+#[macro_use]
+extern crate serde_json;
+
+pub mod asm;
+pub mod dap;
+pub mod debugger;
+pub mod diag;
+pub mod disasm;
+pub mod error;
+pub mod gdb;
+mod history;
+pub mod image;
+pub mod instr;
+pub mod io;
+pub mod mapper;
+pub mod opcode;
+pub mod patch;
+pub mod snapshot;
+pub mod teleporter;
+pub mod trace;
+pub mod tracefile;
+pub mod transcript;
+pub mod vault;
+pub mod vm;
+pub mod walkthrough;
+pub mod watch;
+
+pub use error::VmError;
+pub use instr::{decode, DecodeError, Instruction, Operand};
+pub use vm::{Frame, Hook, StopReason, Vm};
@@ -1,30 +1,1020 @@
-mod cpu;
-use cpu::CPU;
-use std::fs::File;
-use std::io::Read;
-fn main() {
+extern crate synacor;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::process;
+
+use synacor::{StopReason, Vm, VmError};
+use synacor::{asm, disasm, image, teleporter};
+use synacor::dap::{self, DapServer};
+use synacor::debugger::Debugger;
+use synacor::diag::{Level, StderrSink};
+use synacor::gdb::{Connection, GdbStub};
+use synacor::io::{BufferIo, Io, ScriptIo, StdIo};
+use synacor::mapper::{self, MapOptions};
+use synacor::patch::PatchSet;
+use synacor::trace::{TraceFilter, TraceFormat, TraceRecord};
+use synacor::tracefile::{self, TraceReader};
+use synacor::transcript::{Replay, Transcript};
+use synacor::vault::Vault;
+use synacor::walkthrough::Walkthrough;
+
+// Exit codes
+const EXIT_HALTED: i32 = 0;
+const EXIT_VM_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+const EXIT_STOPPED: i32 = 3;
+
+// Instructions debug and gdb can step back over unless --history says otherwise
+const DEFAULT_HISTORY: usize = 10_000;
+
+const USAGE: &str = "\
+Usage: synacor [run] [OPTIONS] <IMAGE>
+       synacor debug [OPTIONS] <IMAGE>
+       synacor gdb [--listen <ADDR> | --socket <PATH>] [OPTIONS] <IMAGE>
+       synacor walkthrough <SCRIPT> [OPTIONS] <IMAGE>
+       synacor map [--json] [--max-rooms <N>] [--room-addr <ADDR>]
+                   [--path <FROM> <TO>] [OPTIONS] <IMAGE>
+       synacor dap
+       synacor disasm [--start <ADDR>] [--end <ADDR>] <IMAGE>
+       synacor asm [-o <IMAGE>] <SOURCE>
+       synacor solve-teleporter <IMAGE>
+       synacor solve-vault --grid <FILE>
+       synacor solve-vault [OPTIONS] <IMAGE>
+       synacor trace text [--json] <TRACE>
+       synacor trace search <TRACE> <TERMS>...
+       synacor trace count [--by-addr] <TRACE>
+       synacor trace diff <TRACE> <TRACE>
+
+Runs a Synacor Challenge program image, optionally under the debugger,
+prints its disassembly, or assembles a source file into an image (by
+default SOURCE with a .bin extension).
+
+'gdb' waits for a GDB remote protocol client, such as gdb's 'target
+remote', on a TCP address (default 127.0.0.1:1234) or a Unix socket, and
+runs the program under its control. Addresses seen by the client are byte
+addresses, twice the word address, and the stack appears from 0x10000.
+
+'walkthrough' plays the commands in SCRIPT, lines starting with '>', and
+checks the expectations on the output that follow each one: 'expect TEXT',
+'match REGEX', 'room TITLE' and 'inventory ITEM'. It stops at the first
+that fails, showing the commands and output leading up to it, and exits
+with status 1.
+
+'map' explores the adventure from where the program starts, or from
+--state, by trying every exit of every room, and prints the rooms found
+as a Graphviz graph, or as JSON with --json. With --path it prints the
+shortest list of exits from room FROM to room TO instead, as input for
+--input. Rooms are given by title or by their number in the map. Rooms
+are told apart by their text and all of memory, or only the word at
+--room-addr if given (2732 holds the current room in challenge.bin).
+
+'dap' serves the Debug Adapter Protocol on stdin and stdout, for editors.
+The program, an image or assembler source, is given in the launch request.
+
+'solve-teleporter' finds the teleporter's confirmation check in the image,
+searches for the values of r7 that pass it, and prints a patch file
+for --patch or PATCH that presets r7 and skips the check.
+
+'solve-vault' finds the shortest walk across the vault's grid of rooms
+that brings the orb to the door at the weight carved on it, and prints
+the commands to take the orb and make the walk, as input for --input.
+The grid is read from FILE, one row of numbers and operators per line,
+north first, after 'target WEIGHT'. Otherwise it is read from the rooms
+in the program's memory once it first waits for input, or at --state.
+
+The trace commands work on binary traces written with --log-format
+binary or binary-gz. 'text' prints one as text, 'search' prints the
+records matching filter terms as for --log-filter, 'count' shows how
+often each address was executed, and 'diff' finds the first record at
+which two traces differ.
+
+When running, hitting a breakpoint or typing DEBUG at an input prompt
+enters the debugger. Type 'help' there for its commands. Typing SAVE NAME
+or LOAD NAME at an input prompt saves or restores the machine state in
+the slot NAME.snap.
+LOG_START [TERMS] and LOG_END turn the trace on and off, and LOG_FILTER
+TERMS changes its filter. PATCH FILE applies the patch sets in FILE, and
+UNPATCH NAME reverts the set NAME.
+
+A patch file holds sets of changes, each headed by '[NAME]', with lines
+'ADDR: OLD... -> NEW...' to replace the words OLD at ADDR, and 'rN = VALUE'
+to preset a register. A set is refused unless memory holds all the OLD
+words, and reverting it restores them and the registers.
+
+A session recorded with --record can be replayed with --replay. Each line
+must be read at the cycle it was recorded at, and the output must match
+the recording, or the replay stops with an error. Input then continues
+from --input and the keyboard, unless --stop-at is given.
+
+Run options:
+  -b, --break-pc <ADDR>    break when the program counter reaches ADDR
+  -c, --break-cc <CYCLE>   break when the cycle count reaches CYCLE
+      --diag-level <LEVEL> show the VM's own messages on stderr up to LEVEL:
+                           error, warn, info (default), debug or trace
+      --history <N>        keep N instructions of history for the debugger's
+                           reverse-step and reverse-continue (default 10000
+                           under debug and gdb, otherwise 0, which turns it
+                           off)
+  -i, --input <FILE>       feed the lines of FILE as input before the keyboard
+  -l, --log <FILE>         write the instruction trace to FILE, starting at once
+      --log-format <FMT>   write the trace as 'text' (default), 'json' lines,
+                           'binary' or 'binary-gz'
+      --log-filter <TERMS> only trace matching instructions; TERMS are
+                           pc:A-B, not-pc:A-B, op:NAME,..., cc:A-B, regs,
+                           reg:rN[=V] and value:V
+  -m, --max-cycles <N>     stop after executing N instructions
+      --patch <FILE>       apply the patch sets in FILE before starting; may
+                           be given more than once
+  -q, --quiet              don't print the program's output
+  -r, --record <FILE>      record the input lines read to a transcript
+  -p, --replay <FILE>      replay a transcript recorded with --record
+      --stop-at <LINE>     stop the replay before its input line LINE
+  -s, --state <FILE>       resume from a state saved with SAVE or 'save'
+      --save-dir <DIR>     keep SAVE and LOAD slots in DIR (default .)
+  -h, --help               print this help
+
+Exit status is 0 when the program halts, 1 on a VM error, 2 on a usage or
+load error, and 3 when stopped by the cycle limit or by leaving the
+debugger before the program halts.";
+
+struct RunArgs {
+    image: String,
+    break_pc: Option<u16>,
+    break_cc: Option<u64>,
+    diag_level: Level,
+    history: Option<usize>,
+    input: Option<String>,
+    log: Option<String>,
+    log_format: TraceFormat,
+    log_filter: TraceFilter,
+    max_cycles: Option<u64>,
+    patches: Vec<String>,
+    quiet: bool,
+    state: Option<String>,
+    save_dir: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+    stop_at: Option<usize>,
+}
+
+// Discards the program's output, passing input through
+struct QuietIo(Box<dyn Io>);
+
+impl Io for QuietIo {
+    fn write_char (&mut self, _ch: char) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_line (&mut self, buf: &mut String) -> io::Result<usize> {
+        self.0.read_line(buf)
+    }
+}
+
+fn usage_error (msg: &str) -> ! {
+    eprintln!("synacor: {}\n\n{}", msg, USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+// Parses a decimal number, or a hexadecimal one prefixed with 0x
+fn parse_num (flag: &str, s: &str) -> u64 {
+    let parsed = if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16)
+    }
+    else {
+        s.parse()
+    };
+    match parsed {
+        Ok(n) => n,
+        Err(_) => usage_error(&format!("invalid number '{}' for {}", s, flag)),
+    }
+}
+
+// Parses a memory address, which must fit in 15 bits
+fn parse_addr (flag: &str, s: &str) -> u16 {
+    let addr = parse_num(flag, s);
+    if addr > 32_767 {
+        usage_error(&format!("address {} for {} is out of range", s, flag));
+    }
+    addr as u16
+}
+
+fn parse_run_args (args: &[String]) -> RunArgs {
+    let mut image = None;
+    let mut run_args = RunArgs {
+        image: String::new(),
+        break_pc: None,
+        break_cc: None,
+        diag_level: Level::Info,
+        history: None,
+        input: None,
+        log: None,
+        log_format: TraceFormat::Text,
+        log_filter: TraceFilter::default(),
+        max_cycles: None,
+        patches: vec![],
+        quiet: false,
+        state: None,
+        save_dir: None,
+        record: None,
+        replay: None,
+        stop_at: None,
+    };
+
+    let mut filter_terms = vec![];
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.as_str();
+        let mut value = || match args.next() {
+            Some(v) => v.clone(),
+            None => usage_error(&format!("{} needs a value", arg)),
+        };
+        match arg {
+            "-b" | "--break-pc" => run_args.break_pc = Some(parse_addr(arg, &value())),
+            "-c" | "--break-cc" => run_args.break_cc = Some(parse_num(arg, &value())),
+            "-i" | "--input" => run_args.input = Some(value()),
+            "-l" | "--log" => run_args.log = Some(value()),
+            "--log-format" => {
+                let name = value();
+                match TraceFormat::from_name(&name) {
+                    Some(format) => run_args.log_format = format,
+                    None => usage_error(&format!("unknown trace format '{}'", name)),
+                }
+            },
+            "--log-filter" => {
+                // Repeated filters add to each other
+                filter_terms.push(value());
+            },
+            "--diag-level" => {
+                let name = value();
+                match Level::from_name(&name) {
+                    Some(level) => run_args.diag_level = level,
+                    None => usage_error(&format!("unknown diagnostics level '{}'", name)),
+                }
+            },
+            "--history" => run_args.history = Some(parse_num(arg, &value()) as usize),
+            "-m" | "--max-cycles" => run_args.max_cycles = Some(parse_num(arg, &value())),
+            "--patch" => run_args.patches.push(value()),
+            "-q" | "--quiet" => run_args.quiet = true,
+            "-s" | "--state" => run_args.state = Some(value()),
+            "--save-dir" => run_args.save_dir = Some(value()),
+            "-r" | "--record" => run_args.record = Some(value()),
+            "-p" | "--replay" => run_args.replay = Some(value()),
+            "--stop-at" => run_args.stop_at = Some(parse_num(arg, &value()) as usize),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(EXIT_HALTED);
+            },
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option {}", arg)),
+            _ if image.is_none() => image = Some(arg.to_string()),
+            _ => usage_error(&format!("unexpected argument {}", arg)),
+        }
+    }
+
+    match image {
+        Some(path) => run_args.image = path,
+        None => usage_error("no program image given"),
+    }
+    match TraceFilter::parse(&filter_terms.join(" ")) {
+        Ok(filter) => run_args.log_filter = filter,
+        Err(e) => usage_error(&format!("invalid --log-filter: {}", e)),
+    }
+    if run_args.stop_at.is_some() && run_args.replay.is_none() {
+        usage_error("--stop-at needs --replay");
+    }
+    run_args
+}
+
+// Reads a program image and loads it into a new VM, exiting on failure
+fn load_vm (path: &str, io: Box<dyn Io>) -> Vm {
+    let data = match image::read_image(path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("synacor: could not read {}: {}", path, e);
+            process::exit(EXIT_USAGE);
+        },
+    };
+
+    let mut vm = Vm::with_io(io);
+    if let Err(e) = vm.load_mem(&data) {
+        eprintln!("synacor: could not load {}: {}", path, e);
+        process::exit(EXIT_USAGE);
+    }
+    vm
+}
+
+// Builds a VM as configured by the run options, exiting on failure
+fn setup_vm (args: &RunArgs) -> Vm {
+    let mut io: Box<dyn Io> = Box::new(StdIo);
+    if let Some(ref path) = args.input {
+        match fs::read_to_string(path) {
+            Ok(script) => io = Box::new(ScriptIo::new(&script, io)),
+            Err(e) => {
+                eprintln!("synacor: could not read {}: {}", path, e);
+                process::exit(EXIT_USAGE);
+            },
+        }
+    }
+    if args.quiet {
+        io = Box::new(QuietIo(io));
+    }
+
+    let mut vm = load_vm(&args.image, io);
+    vm.diagnostics_mut().set_sink(Box::new(StderrSink));
+    vm.diagnostics_mut().set_level(args.diag_level);
+    vm.set_max_cycles(args.max_cycles);
+    vm.set_break_cycle(args.break_cc);
+    // Recording history costs time on every instruction, so only the
+    // debuggers ask for it by default
+    vm.set_history_depth(args.history.unwrap_or(0));
+    if let Some(pc) = args.break_pc {
+        vm.add_breakpoint(pc);
+    }
+    if let Some(ref dir) = args.save_dir {
+        vm.set_save_dir(dir);
+    }
+    if let Some(ref path) = args.state {
+        if let Err(e) = vm.load_state(path) {
+            eprintln!("synacor: could not load {}: {}", path, e);
+            process::exit(EXIT_USAGE);
+        }
+    }
+    for path in &args.patches {
+        let sets = match PatchSet::load(path) {
+            Ok(sets) => sets,
+            Err(e) => {
+                eprintln!("synacor: could not read {}: {}", path, e);
+                process::exit(EXIT_USAGE);
+            },
+        };
+        for set in sets {
+            if let Err(e) = vm.apply_patch_set(set) {
+                eprintln!("synacor: {}: {}", path, e);
+                process::exit(EXIT_USAGE);
+            }
+        }
+    }
+    if let Some(ref path) = args.replay {
+        let transcript = fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|src| Transcript::parse(&src).map_err(|e| e.to_string()));
+        let mut replay = match transcript {
+            Ok(transcript) => Replay::new(transcript),
+            Err(e) => {
+                eprintln!("synacor: could not read {}: {}", path, e);
+                process::exit(EXIT_USAGE);
+            },
+        };
+        if let Some(line) = args.stop_at {
+            replay = replay.stop_at(line);
+        }
+        vm.start_replay(replay);
+    }
+    if let Some(ref path) = args.record {
+        if let Err(e) = vm.start_recording(path) {
+            eprintln!("synacor: could not create {}: {}", path, e);
+            process::exit(EXIT_USAGE);
+        }
+    }
+    vm.set_log_format(args.log_format);
+    vm.set_log_filter(args.log_filter.clone());
+    if let Some(ref path) = args.log {
+        vm.set_log_path(path);
+        if let Err(e) = vm.start_logging() {
+            eprintln!("synacor: could not create {}: {}", path, e);
+            process::exit(EXIT_USAGE);
+        }
+    }
+    vm
+}
+
+// Ends the transcript being recorded, if any
+fn finish_recording (vm: &mut Vm, args: &RunArgs) {
+    if let Err(e) = vm.finish_recording() {
+        let path = args.record.as_ref().map_or("transcript", |p| p.as_str());
+        eprintln!("synacor: could not write {}: {}", path, e);
+    }
+}
+
+// Runs the debugger prompt on the terminal until the user quits
+fn debug_session (vm: Vm, args: &RunArgs, stop: Result<StopReason, VmError>) -> i32 {
+    let mut dbg = Debugger::new(vm);
+    if let Some(pc) = args.break_pc {
+        dbg.add_breakpoint(pc, None);
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let result = dbg.report(stop, &mut out)
+        .and_then(|_| dbg.repl(&mut StdIo, &mut out));
+    if let Err(e) = result {
+        eprintln!("synacor: {}", e);
+    }
+    finish_recording(dbg.vm_mut(), args);
 
-    // Read little-endian bytes from file into buffer
-    let mut f = File::open("/home/dave/proj/synacor/challenge.bin").unwrap();
-    let mut buf = Vec::new();
-    f.read_to_end(&mut buf).unwrap();
+    if dbg.vm().is_halted() {
+        EXIT_HALTED
+    }
+    else if dbg.last_error().is_some() {
+        EXIT_VM_ERROR
+    }
+    else {
+        EXIT_STOPPED
+    }
+}
+
+fn run (args: &[String]) -> i32 {
+    let args = parse_run_args(args);
+    let mut vm = setup_vm(&args);
+
+    let code = match vm.run() {
+        Ok(StopReason::Halted) => EXIT_HALTED,
+        Ok(StopReason::CycleLimit) => {
+            eprintln!("synacor: stopped after {} cycles at pc {}", vm.cc(), vm.pc());
+            EXIT_STOPPED
+        },
+        Ok(StopReason::ReplayEnd) => {
+            eprintln!("synacor: replay stopped at cycle {}, pc {}", vm.cc(), vm.pc());
+            EXIT_STOPPED
+        },
+        Ok(reason) => return debug_session(vm, &args, Ok(reason)),
+        Err(e) => {
+            eprintln!("synacor: {}", e);
+            EXIT_VM_ERROR
+        },
+    };
+    finish_recording(&mut vm, &args);
+    code
+}
+
+fn debug (args: &[String]) -> i32 {
+    let mut args = parse_run_args(args);
+    args.history = args.history.or(Some(DEFAULT_HISTORY));
+    let vm = setup_vm(&args);
+    debug_session(vm, &args, Ok(StopReason::Reached))
+}
+
+// Serves one gdb client, returning the exit code for the VM's state
+fn gdb_session<C: Connection> (vm: Vm, conn: C, args: &RunArgs) -> i32 {
+    let mut stub = GdbStub::new(vm, conn);
+    if let Err(e) = stub.serve() {
+        eprintln!("synacor: gdb connection failed: {}", e);
+    }
+    let mut vm = stub.into_vm();
+    finish_recording(&mut vm, args);
+    if vm.is_halted() { EXIT_HALTED } else { EXIT_STOPPED }
+}
+
+fn gdb (args: &[String]) -> i32 {
+    // Take out the options for the connection, leaving the run options
+    let mut listen = None;
+    let mut socket = None;
+    let mut run_args = vec![];
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--listen" | "--socket" => {
+                let value = match iter.next() {
+                    Some(v) => v.clone(),
+                    None => usage_error(&format!("{} needs a value", arg)),
+                };
+                if arg == "--listen" { listen = Some(value) } else { socket = Some(value) }
+            },
+            _ => run_args.push(arg.clone()),
+        }
+    }
+    let mut args = parse_run_args(&run_args);
+    args.history = args.history.or(Some(DEFAULT_HISTORY));
+
+    match socket {
+        Some(path) => {
+            if listen.is_some() {
+                usage_error("--listen and --socket can't be used together");
+            }
+            gdb_unix(&path, &args)
+        },
+        None => {
+            let addr = listen.unwrap_or_else(|| "127.0.0.1:1234".to_string());
+            let conn = TcpListener::bind(&addr).and_then(|listener| {
+                eprintln!("Waiting for gdb on {}", addr);
+                listener.accept()
+            });
+            match conn {
+                Ok((conn, _)) => gdb_session(setup_vm(&args), conn, &args),
+                Err(e) => {
+                    eprintln!("synacor: could not listen on {}: {}", addr, e);
+                    EXIT_USAGE
+                },
+            }
+        },
+    }
+}
+
+#[cfg(unix)]
+fn gdb_unix (path: &str, args: &RunArgs) -> i32 {
+    let conn = UnixListener::bind(path).and_then(|listener| {
+        eprintln!("Waiting for gdb on {}", path);
+        listener.accept()
+    });
+    let code = match conn {
+        Ok((conn, _)) => gdb_session(setup_vm(args), conn, args),
+        Err(e) => {
+            eprintln!("synacor: could not listen on {}: {}", path, e);
+            return EXIT_USAGE;
+        },
+    };
+    let _ = fs::remove_file(path);
+    code
+}
+
+#[cfg(not(unix))]
+fn gdb_unix (_path: &str, _args: &RunArgs) -> i32 {
+    usage_error("Unix sockets are not supported on this platform");
+}
+
+fn walkthrough (args: &[String]) -> i32 {
+    let path = match args.first() {
+        Some(path) if !path.starts_with('-') => path,
+        _ => usage_error("no walkthrough script given"),
+    };
+    let args = parse_run_args(&args[1..]);
+    if args.input.is_some() {
+        usage_error("--input can't be used with walkthrough");
+    }
+    let script = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|src| Walkthrough::parse(&src).map_err(|e| e.to_string()));
+    let script = match script {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("synacor: could not read {}: {}", path, e);
+            return EXIT_USAGE;
+        },
+    };
+
+    let mut vm = setup_vm(&args);
+    let code = match script.run(&mut vm) {
+        Ok(summary) => {
+            println!("Walkthrough passed: {} commands, {} checks, {} cycles",
+                summary.commands, summary.checks, summary.cc);
+            EXIT_HALTED
+        },
+        Err(failure) => {
+            println!("Walkthrough failed at {}", failure);
+            EXIT_VM_ERROR
+        },
+    };
+    finish_recording(&mut vm, &args);
+    code
+}
+
+fn map (args: &[String]) -> i32 {
+    // Take out the options for the map, leaving the run options
+    let mut json = false;
+    let mut options = MapOptions::default();
+    let mut path = None;
+    let mut run_args = vec![];
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || match iter.next() {
+            Some(v) => v.clone(),
+            None => usage_error(&format!("{} needs a value", arg)),
+        };
+        match arg.as_str() {
+            "--json" => json = true,
+            "--max-rooms" => options.max_rooms = parse_num(arg, &value()) as usize,
+            "--room-addr" => options.room_addr = Some(parse_addr(arg, &value())),
+            "--path" => path = Some((value(), value())),
+            _ => run_args.push(arg.clone()),
+        }
+    }
+    let args = parse_run_args(&run_args);
+    if args.input.is_some() {
+        usage_error("--input can't be used with map");
+    }
+
+    let mut vm = setup_vm(&args);
+    let world = match mapper::explore(&mut vm, options) {
+        Ok(world) => world,
+        Err(e) => {
+            eprintln!("synacor: {}", e);
+            return EXIT_VM_ERROR;
+        },
+    };
+    if world.truncated {
+        eprintln!("synacor: stopped exploring after {} rooms", world.rooms.len());
+    }
+
+    let (from, to) = match path {
+        Some(path) => path,
+        None => {
+            if json {
+                println!("{}", world.to_json());
+            }
+            else {
+                print!("{}", world.to_dot());
+            }
+            return EXIT_HALTED;
+        },
+    };
+    // Try every room matching each name, keeping the shortest path
+    let mut best: Option<Vec<&str>> = None;
+    for &a in &world.find(&from) {
+        for &b in &world.find(&to) {
+            if let Some(p) = world.shortest_path(a, b) {
+                if best.as_ref().is_none_or(|best| p.len() < best.len()) {
+                    best = Some(p);
+                }
+            }
+        }
+    }
+    match best {
+        Some(exits) => {
+            for exit in exits {
+                println!("{}", exit);
+            }
+            EXIT_HALTED
+        },
+        None => {
+            eprintln!("synacor: no path from '{}' to '{}'", from, to);
+            EXIT_VM_ERROR
+        },
+    }
+}
 
-    // Convert 8-bit little endian values to 16-bit
-    // big endian values to be loaded into CPU memory
-    let mut data = vec![0; buf.len()/2];
-    for i in 0..data.len() {
-        data[i] = u16::from(buf[2*i]) | u16::from(buf[2*i+1]) << 8;
+fn dap (args: &[String]) -> i32 {
+    if let Some(arg) = args.first() {
+        usage_error(&format!("unexpected argument {}", arg));
     }
+    let requests = dap::spawn_reader(io::BufReader::new(io::stdin()));
+    let stdout = io::stdout();
+    let mut server = DapServer::new(stdout.lock());
+    match server.serve(&requests) {
+        Ok(()) => EXIT_HALTED,
+        Err(e) => {
+            eprintln!("synacor: {}", e);
+            EXIT_USAGE
+        },
+    }
+}
+
+fn disassemble (args: &[String]) -> i32 {
+    let mut image_path = None;
+    let mut start = 0;
+    let mut end = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.as_str();
+        let mut value = || match args.next() {
+            Some(v) => v.clone(),
+            None => usage_error(&format!("{} needs a value", arg)),
+        };
+        match arg {
+            "-s" | "--start" => start = parse_addr(arg, &value()),
+            "-e" | "--end" => end = Some(parse_addr(arg, &value())),
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option {}", arg)),
+            _ if image_path.is_none() => image_path = Some(arg.to_string()),
+            _ => usage_error(&format!("unexpected argument {}", arg)),
+        }
+    }
+
+    let path = match image_path {
+        Some(path) => path,
+        None => usage_error("no program image given"),
+    };
+    let data = match image::read_image(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("synacor: could not read {}: {}", path, e);
+            return EXIT_USAGE;
+        },
+    };
+
+    let end = end.map_or(data.len(), usize::from);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for line in disasm::disassemble(&data, start, end) {
+        // Stop quietly if the reader goes away, e.g. piped into head
+        if writeln!(out, "{}", line).is_err() {
+            break;
+        }
+    }
+    EXIT_HALTED
+}
+
+fn assemble (args: &[String]) -> i32 {
+    let mut src_path = None;
+    let mut out_path = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.as_str();
+        match arg {
+            "-o" | "--output" => match args.next() {
+                Some(v) => out_path = Some(v.clone()),
+                None => usage_error(&format!("{} needs a value", arg)),
+            },
+            _ if arg.starts_with('-') => usage_error(&format!("unknown option {}", arg)),
+            _ if src_path.is_none() => src_path = Some(arg.to_string()),
+            _ => usage_error(&format!("unexpected argument {}", arg)),
+        }
+    }
+
+    let src_path = match src_path {
+        Some(path) => path,
+        None => usage_error("no source file given"),
+    };
+    let out_path = out_path.unwrap_or_else(|| {
+        Path::new(&src_path).with_extension("bin").to_string_lossy().into_owned()
+    });
+
+    let src = match fs::read_to_string(&src_path) {
+        Ok(src) => src,
+        Err(e) => {
+            eprintln!("synacor: could not read {}: {}", src_path, e);
+            return EXIT_USAGE;
+        },
+    };
+    let words = match asm::assemble(&src) {
+        Ok(words) => words,
+        Err(e) => {
+            eprintln!("synacor: {}: {}", src_path, e);
+            return EXIT_VM_ERROR;
+        },
+    };
+    if let Err(e) = image::write_image(&out_path, &words) {
+        eprintln!("synacor: could not write {}: {}", out_path, e);
+        return EXIT_USAGE;
+    }
+    EXIT_HALTED
+}
+
+fn solve_teleporter (args: &[String]) -> i32 {
+    let path = match args {
+        [path] if !path.starts_with('-') => path,
+        [arg] => usage_error(&format!("unknown option {}", arg)),
+        [] => usage_error("no program image given"),
+        [_, arg, ..] => usage_error(&format!("unexpected argument {}", arg)),
+    };
+    let data = match image::read_image(path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("synacor: could not read {}: {}", path, e);
+            return EXIT_USAGE;
+        },
+    };
 
-    // Instantiate CPU and load program into memory
-    let mut cpu = CPU::new();
-    if let Err(msg) = cpu.load_mem(&data) {
-        println!("Load memory returned error: {:?}" , msg);
-        panic!();
+    let site = match teleporter::find_check(&data) {
+        Some(site) => site,
+        None => {
+            eprintln!("synacor: no teleporter check found in {}", path);
+            return EXIT_VM_ERROR;
+        },
+    };
+    // Print as a patch file, with the findings as comments
+    println!("# Check routine at {}, called from {} with r0 = {}, r1 = {}, expecting r0 = {}",
+        site.routine, site.call_addr, site.m, site.n, site.expected);
+
+    let r7 = match site.solve().first() {
+        Some(&r7) => r7,
+        None => {
+            eprintln!("synacor: no value of r7 passes the check");
+            return EXIT_VM_ERROR;
+        },
+    };
+    println!("# The check passes with r7 = {}", r7);
+    print!("{}", site.patch_set(r7));
+    EXIT_HALTED
+}
+
+fn solve_vault (args: &[String]) -> i32 {
+    let vault = match args {
+        [flag, path] if flag == "--grid" => {
+            let vault = fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|src| Vault::parse_grid(&src).map_err(|e| e.to_string()));
+            match vault {
+                Ok(vault) => vault,
+                Err(e) => {
+                    eprintln!("synacor: could not read {}: {}", path, e);
+                    return EXIT_USAGE;
+                },
+            }
+        },
+        [flag] if flag == "--grid" => usage_error("--grid needs a value"),
+        _ => {
+            let args = parse_run_args(args);
+            if args.input.is_some() {
+                usage_error("--input can't be used with solve-vault");
+            }
+            // Run until the program is ready for input, so that its
+            // rooms are in memory
+            let mut vm = setup_vm(&args);
+            vm.set_io(Box::new(BufferIo::default()));
+            match vm.run() {
+                Ok(_) | Err(VmError::InputExhausted { .. }) => (),
+                Err(e) => {
+                    eprintln!("synacor: {}", e);
+                    return EXIT_VM_ERROR;
+                },
+            }
+            match Vault::from_memory(vm.memory()) {
+                Some(vault) => vault,
+                None => {
+                    eprintln!("synacor: no vault found in {}", args.image);
+                    return EXIT_VM_ERROR;
+                },
+            }
+        },
+    };
+
+    let moves = match vault.solve() {
+        Some(moves) => moves,
+        None => {
+            eprintln!("synacor: no walk reaches the door at weight {}", vault.target);
+            return EXIT_VM_ERROR;
+        },
+    };
+    eprintln!("{} moves to reach the door at weight {}", moves.len(), vault.target);
+    println!("take orb");
+    for exit in moves {
+        println!("{}", exit);
+    }
+    EXIT_HALTED
+}
+
+fn open_trace (path: &str) -> Result<TraceReader, i32> {
+    TraceReader::open(path).map_err(|e| {
+        eprintln!("synacor: could not read {}: {}", path, e);
+        EXIT_USAGE
+    })
+}
+
+// Writes trace records matching a filter, stopping quietly if the reader
+// goes away
+fn print_records (path: &str, filter: &TraceFilter, json: bool) -> i32 {
+    let reader = match open_trace(path) {
+        Ok(reader) => reader,
+        Err(code) => return code,
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for record in reader {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("synacor: {}: {}", path, e);
+                return EXIT_VM_ERROR;
+            },
+        };
+        if !filter.accepts(&record) {
+            continue;
+        }
+        let written = if json {
+            writeln!(out, "{}", record.to_json())
+        }
+        else {
+            writeln!(out, "{}", record)
+        };
+        if written.is_err() {
+            break;
+        }
     }
-    
-    let breakpoint_cc = 0;
-    let breakpoint_pc = 0;
+    EXIT_HALTED
+}
+
+fn trace (args: &[String]) -> i32 {
+    let cmd = args.first().map(|s| s.as_str()).unwrap_or("");
+    let args = &args[args.len().min(1)..];
+    let mut flags = vec![];
+    let mut files = vec![];
+    for arg in args {
+        if arg.starts_with("--") {
+            flags.push(arg.as_str());
+        }
+        else {
+            files.push(arg.as_str());
+        }
+    }
+
+    match cmd {
+        "text" => {
+            let json = match flags.as_slice() {
+                [] => false,
+                ["--json"] => true,
+                _ => usage_error(&format!("unknown option {}", flags[0])),
+            };
+            match files.as_slice() {
+                [path] => print_records(path, &TraceFilter::default(), json),
+                _ => usage_error("trace text needs one trace file"),
+            }
+        },
+        "search" => {
+            if let Some(flag) = flags.first() {
+                usage_error(&format!("unknown option {}", flag));
+            }
+            if files.len() < 2 {
+                usage_error("trace search needs a trace file and filter terms");
+            }
+            match TraceFilter::parse(&files[1..].join(" ")) {
+                Ok(filter) => print_records(files[0], &filter, false),
+                Err(e) => usage_error(&format!("invalid filter: {}", e)),
+            }
+        },
+        "count" => {
+            let by_addr = match flags.as_slice() {
+                [] => false,
+                ["--by-addr"] => true,
+                _ => usage_error(&format!("unknown option {}", flags[0])),
+            };
+            let path = match files.as_slice() {
+                [path] => *path,
+                _ => usage_error("trace count needs one trace file"),
+            };
+            let mut counts = match open_trace(path).map(tracefile::count_by_pc) {
+                Ok(Ok(counts)) => counts,
+                Ok(Err(e)) => {
+                    eprintln!("synacor: {}: {}", path, e);
+                    return EXIT_VM_ERROR;
+                },
+                Err(code) => return code,
+            };
+            if by_addr {
+                counts.sort_by_key(|&(pc, _, _)| pc);
+            }
+            let stdout = io::stdout();
+            let mut out = stdout.lock();
+            for (pc, count, instr) in counts {
+                if writeln!(out, "{:10} {:5}: {}", count, pc, instr).is_err() {
+                    break;
+                }
+            }
+            EXIT_HALTED
+        },
+        "diff" => {
+            if let Some(flag) = flags.first() {
+                usage_error(&format!("unknown option {}", flag));
+            }
+            let (left, right) = match files.as_slice() {
+                [left, right] => match (open_trace(left), open_trace(right)) {
+                    (Ok(l), Ok(r)) => (l, r),
+                    (Err(code), _) | (_, Err(code)) => return code,
+                },
+                _ => usage_error("trace diff needs two trace files"),
+            };
+            let show = |record: &Option<TraceRecord>| match *record {
+                Some(ref record) => record.to_string(),
+                None => "(end of trace)".to_string(),
+            };
+            match tracefile::first_divergence(left, right) {
+                Ok(None) => {
+                    println!("Traces are identical");
+                    EXIT_HALTED
+                },
+                Ok(Some(d)) => {
+                    println!("Traces diverge at record {}", d.index);
+                    if let Some(ref common) = d.common {
+                        println!("  {}", common);
+                    }
+                    println!("< {}", show(&d.left));
+                    println!("> {}", show(&d.right));
+                    EXIT_STOPPED
+                },
+                Err(e) => {
+                    eprintln!("synacor: {}", e);
+                    EXIT_VM_ERROR
+                },
+            }
+        },
+        "" => usage_error("trace needs a command"),
+        _ => usage_error(&format!("unknown trace command '{}'", cmd)),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
 
-    cpu.run(breakpoint_cc, breakpoint_pc);
+    let code = match args.first().map(|s| s.as_str()) {
+        Some("run") => run(&args[1..]),
+        Some("debug") => debug(&args[1..]),
+        Some("gdb") => gdb(&args[1..]),
+        Some("walkthrough") => walkthrough(&args[1..]),
+        Some("map") => map(&args[1..]),
+        Some("dap") => dap(&args[1..]),
+        Some("disasm") => disassemble(&args[1..]),
+        Some("asm") => assemble(&args[1..]),
+        Some("solve-teleporter") => solve_teleporter(&args[1..]),
+        Some("solve-vault") => solve_vault(&args[1..]),
+        Some("trace") => trace(&args[1..]),
+        _ => run(&args),
+    };
+    process::exit(code);
 }
@@ -0,0 +1,402 @@
+//! Maps the adventure's rooms by trying every exit from every room.
+//!
+//! The explorer reads the room the program describes, saves a snapshot,
+//! and for each exit listed under "There are N exits" restores the
+//! snapshot, takes the exit and reads where it led. Rooms are told apart
+//! by title, description and memory state, so places that look the same,
+//! like the twisty passages, are still kept apart.
+//!
+//! By default the memory state is a hash of all of memory, but the game
+//! also remembers how a room was reached, which makes one room look like
+//! many. `MapOptions::room_addr` narrows the state to the word holding
+//! the current room, 2732 in `challenge.bin`.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+
+use regex::Regex;
+use serde_json::Value;
+
+use error::VmError;
+use io::BufferIo;
+use snapshot::Snapshot;
+use vm::Vm;
+
+/// How to explore.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapOptions {
+    /// Rooms found before exploration stops.
+    pub max_rooms: usize,
+    /// Address of the word identifying the current room. With none, all
+    /// of memory is used.
+    pub room_addr: Option<u16>,
+}
+
+impl Default for MapOptions {
+    fn default() -> MapOptions {
+        MapOptions { max_rooms: 1000, room_addr: None }
+    }
+}
+
+// The game keeps the line typed, up to this many characters, after its
+// length in memory. It is left out of room hashes, since it changes with
+// every command.
+const INPUT_BUFFER_WORDS: usize = 32;
+
+/// An exit from a room and the room it leads to, if any. Exits that
+/// end the game or don't lead to a room have none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exit {
+    pub name: String,
+    pub to: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Room {
+    /// Index in `WorldMap::rooms`.
+    pub id: usize,
+    pub title: String,
+    pub description: String,
+    /// Things of interest listed in the room.
+    pub items: Vec<String>,
+    pub exits: Vec<Exit>,
+    /// The word at `MapOptions::room_addr`, or a hash of memory, when
+    /// the room was first entered.
+    pub state: u64,
+}
+
+/// The rooms found, the first being where exploration started.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorldMap {
+    pub rooms: Vec<Room>,
+    /// Whether exploration stopped at the room limit, leaving exits
+    /// unexplored.
+    pub truncated: bool,
+}
+
+// A room as described by the program
+struct Description {
+    title: String,
+    description: String,
+    items: Vec<String>,
+    exits: Vec<String>,
+}
+
+// Reads the last room described in the output
+fn parse_room (output: &str, exits_re: &Regex) -> Option<Description> {
+    let lines: Vec<&str> = output.lines().collect();
+    let start = lines.iter().rposition(|l| l.starts_with("== ") && l.ends_with(" =="))?;
+    let title = lines[start][3..lines[start].len() - 3].to_string();
+
+    let mut description = vec![];
+    let mut items = vec![];
+    let mut exits = vec![];
+    // Which list the "- " lines belong to
+    let mut list = None;
+    for line in &lines[start + 1..] {
+        if let Some(entry) = line.strip_prefix("- ") {
+            match list {
+                Some(true) => exits.push(entry.to_string()),
+                Some(false) => items.push(entry.to_string()),
+                None => description.push(*line),
+            }
+        }
+        else if exits_re.is_match(line) {
+            list = Some(true);
+        }
+        else if line.starts_with("Things of interest here:") {
+            list = Some(false);
+        }
+        else if list.is_none() && !line.trim().is_empty() {
+            description.push(line.trim());
+        }
+    }
+    Some(Description { title, description: description.join(" "), items, exits })
+}
+
+// Runs until the program wants more input, returning whether it is
+// waiting for it rather than halted or stopped
+fn advance (vm: &mut Vm) -> Result<bool, VmError> {
+    match vm.run() {
+        Err(VmError::InputExhausted { .. }) => Ok(true),
+        Err(e) => Err(e),
+        Ok(_) => Ok(false),
+    }
+}
+
+// Finds where the game stored the line just typed: its length followed
+// by its characters, written since `before`. The game's own word list
+// holds the same strings, but doesn't change.
+fn find_input_buffer (before: &[u16], mem: &[u16], line: &str) -> Option<usize> {
+    let words: Vec<u16> = Some(line.len() as u16).into_iter().chain(line.bytes().map(u16::from)).collect();
+    (0..mem.len().saturating_sub(words.len())).find(|&i| {
+        mem[i..i + words.len()] == words[..] && before[i..i + words.len()] != words[..]
+    })
+}
+
+fn memory_hash (mem: &[u16], input_buffer: Option<usize>) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match input_buffer {
+        Some(start) => {
+            let end = (start + 1 + INPUT_BUFFER_WORDS).min(mem.len());
+            mem[..start].hash(&mut hasher);
+            mem[end..].hash(&mut hasher);
+        },
+        None => mem.hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+struct Explorer<'a> {
+    vm: &'a mut Vm,
+    options: MapOptions,
+    io: BufferIo,
+    exits_re: Regex,
+    input_buffer: Option<usize>,
+    map: WorldMap,
+    ids: HashMap<(String, String, u64), usize>,
+    // Rooms whose exits are still to be tried, with their snapshots
+    queue: VecDeque<(usize, Snapshot)>,
+}
+
+impl<'a> Explorer<'a> {
+    // Sends a line and reads the room it leads to, if any
+    fn send (&mut self, line: &str) -> Result<Option<Description>, VmError> {
+        let before = if self.input_buffer.is_none() { Some(self.vm.memory().to_vec()) } else { None };
+        self.io.push_input(line);
+        let waiting = advance(self.vm)?;
+        let output = self.io.take_output();
+        if let Some(before) = before {
+            self.input_buffer = find_input_buffer(&before, self.vm.memory(), line);
+        }
+        Ok(if waiting { parse_room(&output, &self.exits_re) } else { None })
+    }
+
+    // Returns the ID of the room the VM is in, adding it if it is new
+    fn visit (&mut self, desc: Description) -> usize {
+        let state = match self.options.room_addr {
+            Some(addr) => self.vm.memory()[addr as usize] as u64,
+            None => memory_hash(self.vm.memory(), self.input_buffer),
+        };
+        let key = (desc.title.clone(), desc.description.clone(), state);
+        if let Some(&id) = self.ids.get(&key) {
+            return id;
+        }
+        let id = self.map.rooms.len();
+        let exits = desc.exits.into_iter().map(|name| Exit { name, to: None }).collect();
+        self.map.rooms.push(Room {
+            id, title: desc.title, description: desc.description, items: desc.items, exits, state,
+        });
+        self.ids.insert(key, id);
+        self.queue.push_back((id, self.vm.snapshot()));
+        id
+    }
+
+    fn explore (&mut self) -> Result<(), VmError> {
+        let start = match self.send("look")? {
+            Some(desc) => desc,
+            None => return Ok(()),
+        };
+        self.visit(start);
+
+        while let Some((id, snap)) = self.queue.pop_front() {
+            for i in 0..self.map.rooms[id].exits.len() {
+                if self.map.rooms.len() >= self.options.max_rooms {
+                    self.map.truncated = true;
+                    return Ok(());
+                }
+                let name = self.map.rooms[id].exits[i].name.clone();
+                self.vm.restore(&snap);
+                let to = self.send(&name)?.map(|desc| self.visit(desc));
+                self.map.rooms[id].exits[i].to = to;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Explores from the room the VM is in, which should be waiting for a
+/// command. The VM is left in an unspecified state, and its `Io` backend
+/// and history depth are put back after.
+pub fn explore (vm: &mut Vm, options: MapOptions) -> Result<WorldMap, VmError> {
+    let io = BufferIo::default();
+    let old_io = vm.set_io(Box::new(io.clone()));
+    // Snapshots are restored constantly, so history would be wasted
+    let depth = vm.history_depth();
+    vm.set_history_depth(0);
+
+    let mut explorer = Explorer {
+        vm,
+        options,
+        io,
+        exits_re: Regex::new(r"^There (is|are) \d+ exits?:$").unwrap(),
+        input_buffer: None,
+        map: WorldMap::default(),
+        ids: HashMap::new(),
+        queue: VecDeque::new(),
+    };
+    let result = explorer.explore();
+    let map = explorer.map;
+
+    vm.set_history_depth(depth);
+    vm.set_io(old_io);
+    result.map(|()| map)
+}
+
+fn dot_escape (s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+impl WorldMap {
+    /// IDs of the rooms with this title, or the room with this ID.
+    pub fn find (&self, name: &str) -> Vec<usize> {
+        if let Ok(id) = name.parse::<usize>() {
+            return if id < self.rooms.len() { vec![id] } else { vec![] };
+        }
+        self.rooms.iter().filter(|r| r.title == name).map(|r| r.id).collect()
+    }
+
+    /// The exits to take along a shortest path from `from` to `to`, or
+    /// None if `to` can't be reached.
+    pub fn shortest_path (&self, from: usize, to: usize) -> Option<Vec<&str>> {
+        // Breadth-first, remembering how each room was first reached
+        let mut came_from: Vec<Option<(usize, usize)>> = vec![None; self.rooms.len()];
+        let mut queue = VecDeque::new();
+        queue.push_back(from);
+        while let Some(id) = queue.pop_front() {
+            if id == to {
+                let mut path = vec![];
+                let mut at = to;
+                while at != from {
+                    let (prev, exit) = came_from[at]?;
+                    path.push(self.rooms[prev].exits[exit].name.as_str());
+                    at = prev;
+                }
+                path.reverse();
+                return Some(path);
+            }
+            for (i, exit) in self.rooms[id].exits.iter().enumerate() {
+                if let Some(next) = exit.to {
+                    if next != from && came_from[next].is_none() {
+                        came_from[next] = Some((id, i));
+                        queue.push_back(next);
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// The map as a Graphviz digraph, one node per room and one edge per
+    /// exit that leads to a room.
+    pub fn to_dot (&self) -> String {
+        let mut dot = String::from("digraph world {\n    node [shape=box];\n");
+        for room in &self.rooms {
+            dot.push_str(&format!("    r{} [label=\"{}\"];\n", room.id, dot_escape(&room.title)));
+        }
+        for room in &self.rooms {
+            for exit in &room.exits {
+                if let Some(to) = exit.to {
+                    dot.push_str(&format!("    r{} -> r{} [label=\"{}\"];\n", room.id, to, dot_escape(&exit.name)));
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    pub fn to_json (&self) -> Value {
+        let rooms: Vec<Value> = self.rooms.iter().map(|room| json!({
+            "id": room.id,
+            "title": room.title,
+            "description": room.description,
+            "items": room.items,
+            "exits": room.exits.iter().map(|e| json!({ "name": e.name, "to": e.to })).collect::<Vec<_>>(),
+            "state": format!("{:016x}", room.state),
+        })).collect();
+        json!({ "rooms": rooms, "truncated": self.truncated })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // As challenge.bin prints it, after the self-test
+    const FOOTHILLS: &str = "\
+self-test complete, all tests pass
+
+== Foothills ==
+You find yourself standing at the base of an enormous mountain.  At its base to the north, there is a massive doorway.  A sign nearby reads \"Keep out!  Definitely no treasure within!\"
+
+Things of interest here:
+- tablet
+
+There are 2 exits:
+- doorway
+- south
+
+What do you do?
+";
+
+    fn room (id: usize, title: &str, exits: &[(&str, Option<usize>)]) -> Room {
+        Room {
+            id,
+            title: title.to_string(),
+            description: String::new(),
+            items: vec![],
+            exits: exits.iter().map(|&(name, to)| Exit { name: name.to_string(), to }).collect(),
+            state: 0,
+        }
+    }
+
+    // 0 <-> 1 -> 2, with a longer way to 2 through 3
+    fn world () -> WorldMap {
+        WorldMap {
+            rooms: vec![
+                room(0, "Foothills", &[("north", Some(1)), ("hole", None)]),
+                room(1, "Passage", &[("south", Some(0)), ("west", Some(3)), ("east", Some(2))]),
+                room(2, "Ledge", &[("back", None)]),
+                room(3, "Passage", &[("ladder", Some(2))]),
+            ],
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn parse_captured_room () {
+        let exits_re = Regex::new(r"^There (is|are) \d+ exits?:$").unwrap();
+        let desc = parse_room(FOOTHILLS, &exits_re).unwrap();
+        assert_eq!(desc.title, "Foothills");
+        assert!(desc.description.starts_with("You find yourself standing"));
+        assert!(desc.description.ends_with("treasure within!\""));
+        assert_eq!(desc.items, ["tablet"]);
+        assert_eq!(desc.exits, ["doorway", "south"]);
+        assert!(parse_room("Taken.\n", &exits_re).is_none());
+    }
+
+    #[test]
+    fn shortest_path () {
+        let map = world();
+        assert_eq!(map.shortest_path(0, 2), Some(vec!["north", "east"]));
+        assert_eq!(map.shortest_path(1, 0), Some(vec!["south"]));
+        assert_eq!(map.shortest_path(2, 2), Some(vec![]));
+        assert_eq!(map.shortest_path(2, 0), None);
+        assert_eq!(map.find("Passage"), [1, 3]);
+        assert_eq!(map.find("3"), [3]);
+        assert!(map.find("4").is_empty());
+    }
+
+    #[test]
+    fn dot_escapes_labels () {
+        let mut map = world();
+        map.rooms[0].title = "Say \"hi\" \\o/".to_string();
+        map.rooms[0].exits[0].name = "\"up\"".to_string();
+        let dot = map.to_dot();
+        assert!(dot.contains("    r0 [label=\"Say \\\"hi\\\" \\\\o/\"];\n"), "{}", dot);
+        assert!(dot.contains("    r0 -> r1 [label=\"\\\"up\\\"\"];\n"), "{}", dot);
+        // Exits that lead nowhere get no edge
+        assert_eq!(dot.matches(" -> ").count(), 5);
+    }
+}
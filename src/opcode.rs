@@ -0,0 +1,95 @@
+//! The opcode table of the Synacor architecture, shared by the executor,
+//! the disassembler and the assembler.
+
+/// One of the 22 operations listed in `arch-spec`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Opcode {
+    Halt,
+    Set,
+    Push,
+    Pop,
+    Eq,
+    Gt,
+    Jmp,
+    Jt,
+    Jf,
+    Add,
+    Mult,
+    Mod,
+    And,
+    Or,
+    Not,
+    Rmem,
+    Wmem,
+    Call,
+    Ret,
+    Out,
+    In,
+    Noop,
+}
+
+/// Every opcode, indexed by its numeric code.
+pub const OPCODES: [Opcode; 22] = [
+    Opcode::Halt, Opcode::Set, Opcode::Push, Opcode::Pop, Opcode::Eq, Opcode::Gt,
+    Opcode::Jmp, Opcode::Jt, Opcode::Jf, Opcode::Add, Opcode::Mult, Opcode::Mod,
+    Opcode::And, Opcode::Or, Opcode::Not, Opcode::Rmem, Opcode::Wmem, Opcode::Call,
+    Opcode::Ret, Opcode::Out, Opcode::In, Opcode::Noop,
+];
+
+impl Opcode {
+    /// Looks up the opcode with numeric code `code`.
+    pub fn from_u16 (code: u16) -> Option<Opcode> {
+        OPCODES.get(code as usize).cloned()
+    }
+
+    /// Looks up the opcode with the given mnemonic, as named in `arch-spec`.
+    pub fn from_mnemonic (name: &str) -> Option<Opcode> {
+        OPCODES.iter().find(|op| op.mnemonic() == name).cloned()
+    }
+
+    /// The numeric code stored in memory.
+    pub fn code (self) -> u16 {
+        self as u16
+    }
+
+    /// The name used in `arch-spec`.
+    pub fn mnemonic (self) -> &'static str {
+        match self {
+            Opcode::Halt => "halt",
+            Opcode::Set => "set",
+            Opcode::Push => "push",
+            Opcode::Pop => "pop",
+            Opcode::Eq => "eq",
+            Opcode::Gt => "gt",
+            Opcode::Jmp => "jmp",
+            Opcode::Jt => "jt",
+            Opcode::Jf => "jf",
+            Opcode::Add => "add",
+            Opcode::Mult => "mult",
+            Opcode::Mod => "mod",
+            Opcode::And => "and",
+            Opcode::Or => "or",
+            Opcode::Not => "not",
+            Opcode::Rmem => "rmem",
+            Opcode::Wmem => "wmem",
+            Opcode::Call => "call",
+            Opcode::Ret => "ret",
+            Opcode::Out => "out",
+            Opcode::In => "in",
+            Opcode::Noop => "noop",
+        }
+    }
+
+    /// Number of operand words following the opcode.
+    pub fn arity (self) -> u16 {
+        match self {
+            Opcode::Halt | Opcode::Ret | Opcode::Noop => 0,
+            Opcode::Push | Opcode::Pop | Opcode::Jmp | Opcode::Call |
+            Opcode::Out | Opcode::In => 1,
+            Opcode::Set | Opcode::Jt | Opcode::Jf | Opcode::Not |
+            Opcode::Rmem | Opcode::Wmem => 2,
+            Opcode::Eq | Opcode::Gt | Opcode::Add | Opcode::Mult |
+            Opcode::Mod | Opcode::And | Opcode::Or => 3,
+        }
+    }
+}
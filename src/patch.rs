@@ -0,0 +1,281 @@
+//! Named, reversible changes to a program's memory and registers.
+//!
+//! A patch file holds one or more patch sets, each starting with its
+//! name in brackets. Each patch gives an address, the words expected
+//! there and the words to write instead; register presets give a value
+//! for a register:
+//!
+//! ```text
+//! # Skip the teleporter's confirmation check
+//! [teleporter]
+//! 5485: 4 -> 6
+//! 5489: 17 6027 -> 21 21
+//! r7 = 25734
+//! ```
+//!
+//! Lines before the first name belong to a set named by the caller,
+//! usually after the file. Numbers may be given in hex with a 0x prefix,
+//! and lines starting with `#` are comments.
+//!
+//! A set is only applied if memory holds every old word it expects, so a
+//! patch made for a different image or a changed program can't corrupt
+//! it. Reverting checks the new words in the same way.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MEM_CAPACITY: usize = 32768;
+const MAX_REG_ID: u16 = 7;
+
+/// Words to replace at an address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Patch {
+    pub addr: u16,
+    pub old: Vec<u16>,
+    pub new: Vec<u16>,
+}
+
+impl fmt::Display for Patch {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let words = |words: &[u16]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>().join(" ");
+        write!(f, "{}: {} -> {}", self.addr, words(&self.old), words(&self.new))
+    }
+}
+
+/// Patches and register presets applied and reverted together.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchSet {
+    pub name: String,
+    pub patches: Vec<Patch>,
+    /// Register IDs (0..7) and the values to give them.
+    pub registers: Vec<(u8, u16)>,
+}
+
+/// Writes the set in patch file format.
+impl fmt::Display for PatchSet {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "[{}]", self.name)?;
+        for patch in &self.patches {
+            writeln!(f, "{}", patch)?;
+        }
+        for &(reg, val) in &self.registers {
+            writeln!(f, "r{} = {}", reg, val)?;
+        }
+        Ok(())
+    }
+}
+
+/// A malformed patch file line, 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchFileError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for PatchFileError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl Error for PatchFileError {}
+
+/// Why a patch set could not be applied or reverted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchError {
+    /// Memory at `addr` held `found` instead of `expected`.
+    Mismatch { set: String, addr: u16, expected: u16, found: u16 },
+    /// A set with the same name is already applied.
+    AlreadyApplied(String),
+    /// No set with this name is applied.
+    NotApplied(String),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::Mismatch { ref set, addr, expected, found } =>
+                write!(f, "patch set '{}' expects {} at {} but memory holds {}", set, expected, addr, found),
+            PatchError::AlreadyApplied(ref set) => write!(f, "patch set '{}' is already applied", set),
+            PatchError::NotApplied(ref set) => write!(f, "patch set '{}' is not applied", set),
+        }
+    }
+}
+
+impl Error for PatchError {}
+
+/// A set that has been applied to a VM, with the register values it
+/// replaced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedPatch {
+    pub set: PatchSet,
+    pub old_registers: Vec<(u8, u16)>,
+}
+
+fn parse_word (s: &str) -> Option<u16> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn parse_words (s: &str) -> Option<Vec<u16>> {
+    s.split_whitespace().map(parse_word).collect()
+}
+
+impl PatchSet {
+    pub fn new (name: &str) -> PatchSet {
+        PatchSet { name: name.to_string(), patches: vec![], registers: vec![] }
+    }
+
+    /// Parses a patch file. Patches before the first `[name]` line go in
+    /// a set called `default_name`.
+    pub fn parse (src: &str, default_name: &str) -> Result<Vec<PatchSet>, PatchFileError> {
+        let mut sets: Vec<PatchSet> = vec![];
+        for (i, text) in src.lines().enumerate() {
+            let err = |msg: &str| PatchFileError { line: i + 1, msg: msg.to_string() };
+            let text = text.trim();
+            if text.starts_with('#') || text.is_empty() {
+                continue;
+            }
+
+            if let Some(name) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err(err("empty patch set name"));
+                }
+                if sets.iter().any(|s| s.name == name) {
+                    return Err(err(&format!("duplicate patch set '{}'", name)));
+                }
+                sets.push(PatchSet::new(name));
+                continue;
+            }
+            if sets.is_empty() {
+                sets.push(PatchSet::new(default_name));
+            }
+            let set = sets.last_mut().unwrap();
+
+            if let Some((reg, val)) = text.split_once('=') {
+                let reg = reg.trim().strip_prefix('r').and_then(|r| r.parse::<u16>().ok());
+                match (reg, parse_word(val.trim())) {
+                    (Some(reg), Some(val)) if reg <= MAX_REG_ID && val < MEM_CAPACITY as u16 =>
+                        set.registers.push((reg as u8, val)),
+                    _ => return Err(err("expected 'rN = VALUE'")),
+                }
+                continue;
+            }
+
+            let (addr, words) = match text.split_once(':') {
+                Some((addr, words)) => (addr.trim(), words),
+                None => return Err(err("expected 'ADDR: OLD -> NEW' or 'rN = VALUE'")),
+            };
+            let addr = match parse_word(addr) {
+                Some(addr) => addr,
+                None => return Err(err(&format!("invalid address '{}'", addr))),
+            };
+            let (old, new) = match words.split_once("->").map(|(o, n)| (parse_words(o), parse_words(n))) {
+                Some((Some(old), Some(new))) => (old, new),
+                _ => return Err(err("expected 'ADDR: OLD -> NEW'")),
+            };
+            if old.is_empty() || old.len() != new.len() {
+                return Err(err("old and new words must be the same, non-zero, length"));
+            }
+            if addr as usize + old.len() > MEM_CAPACITY {
+                return Err(err("patch extends past the end of memory"));
+            }
+            set.patches.push(Patch { addr, old, new });
+        }
+        Ok(sets)
+    }
+
+    /// Reads and parses a patch file, naming any set before the first
+    /// `[name]` line after the file. Malformed lines are reported as
+    /// `InvalidData` errors.
+    pub fn load<P: AsRef<Path>> (path: P) -> io::Result<Vec<PatchSet>> {
+        let path = path.as_ref();
+        let src = fs::read_to_string(path)?;
+        let default_name = path.file_stem().map_or_else(|| path.to_string_lossy(), |s| s.to_string_lossy());
+        PatchSet::parse(&src, &default_name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Checks that memory holds the old words, or the new ones if reverting
+    pub(crate) fn verify (&self, mem: &[u16], applied: bool) -> Result<(), PatchError> {
+        for patch in &self.patches {
+            let words = if applied { &patch.new } else { &patch.old };
+            for (addr, &expected) in (patch.addr..).zip(words) {
+                let found = mem[addr as usize];
+                if found != expected {
+                    return Err(PatchError::Mismatch { set: self.name.clone(), addr, expected, found });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vm::Vm;
+
+    const PROGRAM: &[u16] = &[1, 32768, 7, 21, 21, 0];
+
+    const PATCHES: &str = "\
+1: 32768 7 -> 32769 8
+[second]
+3: 21 21 -> 19 65
+r2 = 9
+";
+
+    fn vm () -> Vm {
+        let mut vm = Vm::new();
+        vm.load_mem(PROGRAM).unwrap();
+        vm
+    }
+
+    #[test]
+    fn parse_names_sets () {
+        let sets = PatchSet::parse(PATCHES, "first").unwrap();
+        let names: Vec<&str> = sets.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["first", "second"]);
+        assert_eq!(sets[1].registers, [(2, 9)]);
+        // Written back out, a set parses to itself
+        assert_eq!(PatchSet::parse(&sets[1].to_string(), "").unwrap(), [sets[1].clone()]);
+    }
+
+    #[test]
+    fn apply_and_revert () {
+        let mut vm = vm();
+        vm.set_register(2, 4).unwrap();
+        for set in PatchSet::parse(PATCHES, "first").unwrap() {
+            vm.apply_patch_set(set).unwrap();
+        }
+        assert_eq!(&vm.memory()[..6], &[1, 32769, 8, 19, 65, 0]);
+        assert_eq!(vm.registers()[2], 9);
+
+        vm.revert_patch_set("second").unwrap();
+        vm.revert_patch_set("first").unwrap();
+        assert_eq!(&vm.memory()[..6], PROGRAM);
+        assert_eq!(vm.registers()[2], 4);
+        assert!(vm.applied_patches().is_empty());
+    }
+
+    #[test]
+    fn refuses_mismatched_memory () {
+        let mut vm = vm();
+        let set = PatchSet::parse("2: 8 -> 9", "wrong").unwrap().remove(0);
+        let err = vm.apply_patch_set(set).unwrap_err();
+        assert_eq!(err, PatchError::Mismatch { set: "wrong".to_string(), addr: 2, expected: 8, found: 7 });
+        assert_eq!(&vm.memory()[..6], PROGRAM);
+
+        // Nor reverts a set whose words have since changed
+        let set = PatchSet::parse("2: 7 -> 9", "changed").unwrap().remove(0);
+        vm.apply_patch_set(set).unwrap();
+        vm.mem_write(2, 10).unwrap();
+        assert!(vm.revert_patch_set("changed").is_err());
+        assert_eq!(vm.revert_patch_set("missing"), Err(PatchError::NotApplied("missing".to_string())));
+    }
+}
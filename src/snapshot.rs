@@ -0,0 +1,268 @@
+//! Complete VM state snapshots and their on-disk format.
+//!
+//! A snapshot file is little-endian throughout:
+//!
+//! ```text
+//! magic    4 bytes  "SYNS"
+//! version  u16      currently 1
+//! pc       u16
+//! cc       u64
+//! halt     u8
+//! regs     8 x u16
+//! stack    u32 length, then that many u16
+//! frames   u32 length, then per frame u16 call_pc, u16 target,
+//!          u16 return_addr, u32 stack_depth
+//! input    u32 length, then that many bytes of UTF-8
+//! memory   32768 x u16
+//! ```
+//!
+//! Breakpoints, watchpoints, logging and I/O backends belong to the
+//! session rather than the machine and are not saved.
+
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use vm::Frame;
+
+const MAGIC: &[u8; 4] = b"SYNS";
+const VERSION: u16 = 1;
+const MEM_CAPACITY: usize = 32_768;
+const MAX_15_BIT_VAL: u16 = 32_767;
+
+// Longest stack, call stack and input buffer a snapshot may hold, so a
+// corrupt length can't ask for gigabytes
+const MAX_STACK_LEN: usize = 1 << 20;
+const MAX_FRAMES: usize = 1 << 20;
+const MAX_INPUT_LEN: usize = 4096;
+
+/// Everything needed to resume a VM exactly where it was. Taken with
+/// `Vm::snapshot` and applied with `Vm::restore`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub(crate) pc: u16,
+    pub(crate) cc: u64,
+    pub(crate) halt: bool,
+    pub(crate) reg: Vec<u16>,
+    pub(crate) stack: Vec<u16>,
+    pub(crate) frames: Vec<Frame>,
+    // Input read from the backend but not yet consumed by in
+    pub(crate) input_buffer: String,
+    pub(crate) mem: Vec<u16>,
+}
+
+impl Snapshot {
+    /// Address of the next instruction to execute.
+    pub fn pc (&self) -> u16 {
+        self.pc
+    }
+
+    /// Number of instructions executed when the snapshot was taken.
+    pub fn cc (&self) -> u64 {
+        self.cc
+    }
+
+    pub fn is_halted (&self) -> bool {
+        self.halt
+    }
+
+    /// Writes the snapshot in the format described in the module docs.
+    /// A stack, call stack or pending input too long for `read_from` to
+    /// accept is an `InvalidData` error, and nothing is written.
+    pub fn write_to<W: Write> (&self, w: &mut W) -> io::Result<()> {
+        self.check_lengths()?;
+        w.write_all(MAGIC)?;
+        w.write_u16::<LittleEndian>(VERSION)?;
+        w.write_u16::<LittleEndian>(self.pc)?;
+        w.write_u64::<LittleEndian>(self.cc)?;
+        w.write_u8(self.halt as u8)?;
+        for &r in &self.reg {
+            w.write_u16::<LittleEndian>(r)?;
+        }
+
+        w.write_u32::<LittleEndian>(self.stack.len() as u32)?;
+        for &val in &self.stack {
+            w.write_u16::<LittleEndian>(val)?;
+        }
+
+        w.write_u32::<LittleEndian>(self.frames.len() as u32)?;
+        for f in &self.frames {
+            w.write_u16::<LittleEndian>(f.call_pc)?;
+            w.write_u16::<LittleEndian>(f.target)?;
+            w.write_u16::<LittleEndian>(f.return_addr)?;
+            w.write_u32::<LittleEndian>(f.stack_depth as u32)?;
+        }
+
+        w.write_u32::<LittleEndian>(self.input_buffer.len() as u32)?;
+        w.write_all(self.input_buffer.as_bytes())?;
+
+        for &word in &self.mem {
+            w.write_u16::<LittleEndian>(word)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a snapshot written by `write_to`, rejecting other files
+    /// and other format versions.
+    pub fn read_from<R: Read> (r: &mut R) -> io::Result<Snapshot> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a snapshot file"));
+        }
+        let version = r.read_u16::<LittleEndian>()?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported snapshot version {}", version)));
+        }
+
+        let pc = r.read_u16::<LittleEndian>()?;
+        let cc = r.read_u64::<LittleEndian>()?;
+        let halt = r.read_u8()? != 0;
+        let mut reg = vec![0; 8];
+        r.read_u16_into::<LittleEndian>(&mut reg)?;
+        if reg.iter().any(|&v| v > MAX_15_BIT_VAL) {
+            return Err(invalid("register value out of range"));
+        }
+
+        let len = read_len(r, MAX_STACK_LEN, "stack")?;
+        let mut stack = vec![0; len];
+        r.read_u16_into::<LittleEndian>(&mut stack)?;
+        if stack.iter().any(|&v| v > MAX_15_BIT_VAL) {
+            return Err(invalid("stack value out of range"));
+        }
+
+        let len = read_len(r, MAX_FRAMES, "call stack")?;
+        let mut frames = Vec::with_capacity(len);
+        for _ in 0..len {
+            frames.push(Frame {
+                call_pc: r.read_u16::<LittleEndian>()?,
+                target: r.read_u16::<LittleEndian>()?,
+                return_addr: r.read_u16::<LittleEndian>()?,
+                stack_depth: r.read_u32::<LittleEndian>()? as usize,
+            });
+        }
+
+        let len = read_len(r, MAX_INPUT_LEN, "input buffer")?;
+        let mut bytes = vec![0; len];
+        r.read_exact(&mut bytes)?;
+        let input_buffer = String::from_utf8(bytes)
+            .map_err(|_| invalid("input buffer is not valid UTF-8"))?;
+
+        let mut mem = vec![0; MEM_CAPACITY];
+        r.read_u16_into::<LittleEndian>(&mut mem)?;
+
+        Ok(Snapshot { pc, cc, halt, reg, stack, frames, input_buffer, mem })
+    }
+
+    // Checks the lengths read_from limits
+    fn check_lengths (&self) -> io::Result<()> {
+        check_len(self.stack.len(), MAX_STACK_LEN, "stack")?;
+        check_len(self.frames.len(), MAX_FRAMES, "call stack")?;
+        check_len(self.input_buffer.len(), MAX_INPUT_LEN, "input buffer")
+    }
+
+    /// Writes the snapshot to a file, replacing it if it exists. The
+    /// file is left alone if the snapshot is too large to load back.
+    pub fn save<P: AsRef<Path>> (&self, path: P) -> io::Result<()> {
+        self.check_lengths()?;
+        let mut w = BufWriter::new(File::create(path)?);
+        self.write_to(&mut w)?;
+        w.flush()
+    }
+
+    /// Reads a snapshot from a file.
+    pub fn load<P: AsRef<Path>> (path: P) -> io::Result<Snapshot> {
+        let mut r = BufReader::new(File::open(path)?);
+        Snapshot::read_from(&mut r)
+    }
+}
+
+fn check_len (len: usize, max: usize, what: &str) -> io::Result<()> {
+    if len > max {
+        return Err(invalid(&format!("{} length {} is over the limit of {}", what, len, max)));
+    }
+    Ok(())
+}
+
+// Reads a u32 length, rejecting any over max
+fn read_len<R: Read> (r: &mut R, max: usize, what: &str) -> io::Result<usize> {
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    check_len(len, max, what)?;
+    Ok(len)
+}
+
+fn invalid (msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io::BufferIo;
+    use vm::Vm;
+
+    // set r0 7; push 1234; call 9; halt; noop; in r1; in r1; ret
+    const PROGRAM: &[u16] = &[1, 32768, 7, 2, 1234, 17, 9, 0, 21, 20, 32769, 20, 32769, 18];
+
+    // A VM stopped inside a call, partway through a line of input
+    fn running_vm () -> Vm {
+        let mut vm = Vm::with_io(Box::new(BufferIo::new("hi\n")));
+        vm.load_mem(PROGRAM).unwrap();
+        for _ in 0..4 {
+            vm.step().unwrap();
+        }
+        vm
+    }
+
+    fn to_bytes (snap: &Snapshot) -> Vec<u8> {
+        let mut bytes = vec![];
+        snap.write_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip () {
+        let mut vm = running_vm();
+        let snap = vm.snapshot();
+        let read = Snapshot::read_from(&mut &to_bytes(&snap)[..]).unwrap();
+        assert_eq!(read, snap);
+
+        // Both continue the same way
+        let mut restored = Vm::with_io(Box::new(BufferIo::default()));
+        restored.restore(&read);
+        vm.run().unwrap();
+        restored.run().unwrap();
+        assert_eq!(restored.registers(), vm.registers());
+        assert_eq!(restored.stack(), vm.stack());
+        assert_eq!(restored.cc(), vm.cc());
+    }
+
+    #[test]
+    fn rejects_oversized_lengths () {
+        let mut bytes = to_bytes(&running_vm().snapshot());
+        // The stack length follows the magic, version, pc, cc, halt flag
+        // and registers
+        bytes[33..37].copy_from_slice(&u32::MAX.to_le_bytes());
+        let err = Snapshot::read_from(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Pending input too long to read back isn't written either
+        let mut snap = running_vm().snapshot();
+        snap.input_buffer = "x".repeat(MAX_INPUT_LEN + 1);
+        let mut out = vec![];
+        let err = snap.write_to(&mut out).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn rejects_out_of_range_registers () {
+        let mut bytes = to_bytes(&running_vm().snapshot());
+        bytes[17..19].copy_from_slice(&40000u16.to_le_bytes());
+        let err = Snapshot::read_from(&mut &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,236 @@
+//! Finds the value of r7 that passes the teleporter's confirmation check.
+//!
+//! The check calls a recursive routine, a variant of the Ackermann
+//! function that uses r7 as its base case:
+//!
+//! ```text
+//! f(0, n) = n + 1
+//! f(m, 0) = f(m - 1, r7)
+//! f(m, n) = f(m - 1, f(m, n - 1))
+//! ```
+//!
+//! Interpreted, one evaluation takes far too long. Here each level of `m`
+//! is evaluated for every `n` at once, each row built from the one before,
+//! which makes trying all 32768 values of r7 a matter of seconds.
+
+use std::thread;
+
+use instr::{decode, Instruction, Operand};
+use opcode::Opcode;
+use patch::{Patch, PatchSet};
+use vm::{Hook, Vm};
+
+const MOD: usize = 32768;
+
+/// Where the check is made and the constants it uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CheckSite {
+    /// Address of the `set r0 M` that starts the check.
+    pub setup_addr: u16,
+    /// Address of the `call` to the routine.
+    pub call_addr: u16,
+    /// Address of the recursive routine.
+    pub routine: u16,
+    /// Initial r0.
+    pub m: u16,
+    /// Initial r1.
+    pub n: u16,
+    /// The r0 the routine must return for the check to pass.
+    pub expected: u16,
+}
+
+// Whether the routine at addr starts the way the recursive check does:
+// jt r0 ...; add r0 r1 1; ret
+fn is_check_routine (mem: &[u16], addr: u16) -> bool {
+    let first = match decode(mem, addr) {
+        Ok(Instruction::Jt(Operand::Register(0), _)) => 3,
+        _ => return false,
+    };
+    let second = match decode(mem, addr + first) {
+        Ok(Instruction::Add(Operand::Register(0), Operand::Register(1), Operand::Literal(1))) => 4,
+        _ => return false,
+    };
+    matches!(decode(mem, addr + first + second), Ok(Instruction::Ret))
+}
+
+/// Looks for the check in a memory image: `set r0 M`, `set r1 N`, a call
+/// to the recursive routine, then `eq r1 r0 E`.
+pub fn find_check (mem: &[u16]) -> Option<CheckSite> {
+    let literal = |op: Operand| match op {
+        Operand::Literal(n) => Some(n),
+        Operand::Register(_) => None,
+    };
+    (0..mem.len().saturating_sub(12) as u16).find_map(|addr| {
+        let m = match decode(mem, addr) {
+            Ok(Instruction::Set(Operand::Register(0), m)) => literal(m)?,
+            _ => return None,
+        };
+        let n = match decode(mem, addr + 3) {
+            Ok(Instruction::Set(Operand::Register(1), n)) => literal(n)?,
+            _ => return None,
+        };
+        let routine = match decode(mem, addr + 6) {
+            Ok(Instruction::Call(Operand::Literal(routine))) => routine,
+            _ => return None,
+        };
+        let expected = match decode(mem, addr + 8) {
+            Ok(Instruction::Eq(Operand::Register(1), Operand::Register(0), e)) => literal(e)?,
+            _ => return None,
+        };
+        if !is_check_routine(mem, routine) {
+            return None;
+        }
+        Some(CheckSite { setup_addr: addr, call_addr: addr + 6, routine, m, n, expected })
+    })
+}
+
+/// Evaluates the check routine for the given registers.
+pub fn check (m: u16, n: u16, r7: u16) -> u16 {
+    if m == 0 {
+        return ((n as usize + 1) % MOD) as u16;
+    }
+
+    // Every f(m - 1, _) is needed, so build the rows below m in full,
+    // starting from f(1, n) = n + r7 + 1 when m is above 1
+    let base = if m > 1 { r7 as usize } else { 0 };
+    let mut row: Vec<u16> = (0..MOD).map(|n| ((n + base + 1) % MOD) as u16).collect();
+    let mut next = vec![0; MOD];
+    for _ in 2..m {
+        next[0] = row[r7 as usize];
+        for i in 1..MOD {
+            next[i] = row[next[i - 1] as usize];
+        }
+        ::std::mem::swap(&mut row, &mut next);
+    }
+
+    let mut val = row[r7 as usize];
+    for _ in 0..n {
+        val = row[val as usize];
+    }
+    val
+}
+
+impl CheckSite {
+    /// Returns every non-zero r7 for which the check passes, smallest
+    /// first. The search is spread over the available CPUs.
+    pub fn solve (&self) -> Vec<u16> {
+        let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+        let chunk = MOD.div_ceil(threads);
+        let site = *self;
+        thread::scope(|scope| {
+            let workers: Vec<_> = (0..threads).map(|i| {
+                scope.spawn(move || {
+                    let start = (i * chunk).max(1);
+                    let end = ((i + 1) * chunk).min(MOD);
+                    (start..end)
+                        .map(|r7| r7 as u16)
+                        .filter(|&r7| check(site.m, site.n, r7) == site.expected)
+                        .collect::<Vec<_>>()
+                })
+            }).collect();
+            workers.into_iter().flat_map(|w| w.join().unwrap_or_default()).collect()
+        })
+    }
+
+    /// Patches that make the check pass without calling the routine: r0
+    /// is set to the expected value and the call becomes two `noop`s.
+    pub fn skip_patches (&self) -> Vec<Patch> {
+        let noop = Opcode::Noop.code();
+        vec![
+            Patch { addr: self.setup_addr + 2, old: vec![self.m], new: vec![self.expected] },
+            Patch { addr: self.call_addr, old: vec![Opcode::Call.code(), self.routine], new: vec![noop, noop] },
+        ]
+    }
+
+    /// A patch set named `teleporter` with `skip_patches` and r7 preset,
+    /// for `Vm::apply_patch_set` or a patch file.
+    pub fn patch_set (&self, r7: u16) -> PatchSet {
+        PatchSet {
+            name: "teleporter".to_string(),
+            patches: self.skip_patches(),
+            registers: vec![(7, r7)],
+        }
+    }
+}
+
+/// A hook for `Vm::add_hook` that evaluates the routine natively,
+/// leaving the result in r0 as the routine would.
+pub fn check_hook () -> Hook {
+    Box::new(|vm: &mut Vm| {
+        let (m, n, r7) = (vm.registers()[0], vm.registers()[1], vm.registers()[7]);
+        let _ = vm.set_register(0, check(m, n, r7));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    // The routine as the challenge writes it, and a check calling it
+    const CHECK: &str = "\
+        set r7, 3
+        set r0, 2
+        set r1, 1
+        call f
+        eq r1, r0, 11
+        halt
+f:      jt r0, m
+        add r0, r1, 1
+        ret
+m:      jt r1, mn
+        add r0, r0, 32767
+        set r1, r7
+        call f
+        ret
+mn:     push r0
+        add r1, r1, 32767
+        call f
+        set r1, r0
+        pop r0
+        add r0, r0, 32767
+        call f
+        ret
+";
+
+    fn naive (m: u16, n: u16, r7: u16) -> u16 {
+        match (m, n) {
+            (0, n) => (n + 1) % MOD as u16,
+            (m, 0) => naive(m - 1, r7, r7),
+            (m, n) => naive(m - 1, naive(m, n - 1, r7), r7),
+        }
+    }
+
+    #[test]
+    fn check_matches_recursion () {
+        for m in 0..4 {
+            for n in 0..4 {
+                for r7 in 0..4 {
+                    assert_eq!(check(m, n, r7), naive(m, n, r7), "f({}, {}) with r7 = {}", m, n, r7);
+                }
+            }
+        }
+        assert_eq!(check(4, 0, 1), naive(4, 0, 1));
+        assert_eq!(check(0, 32767, 0), 0);
+    }
+
+    #[test]
+    fn finds_assembled_check () {
+        let mem = assemble(CHECK).unwrap();
+        let site = find_check(&mem).unwrap();
+        assert_eq!(site, CheckSite { setup_addr: 3, call_addr: 9, routine: 16, m: 2, n: 1, expected: 11 });
+        assert_eq!(find_check(&mem[..16]), None);
+
+        // Interpreted and hooked, the routine gives what check does
+        let mut vm = Vm::new();
+        vm.load_mem(&mem).unwrap();
+        vm.run().unwrap();
+        assert_eq!(vm.registers()[0], check(2, 1, 3));
+
+        let mut vm = Vm::new();
+        vm.load_mem(&mem).unwrap();
+        vm.add_hook(site.routine, check_hook());
+        vm.run().unwrap();
+        assert_eq!((vm.registers()[0], vm.registers()[1]), (11, 1));
+    }
+}
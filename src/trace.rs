@@ -0,0 +1,451 @@
+//! The instruction trace.
+//!
+//! Each executed instruction produces a `TraceRecord` with the cycle, the
+//! address and decoded instruction, the values its operands held and the
+//! changes it made to registers, memory and the stack. Records are
+//! written as text, one instruction per line:
+//!
+//! ```text
+//! 180 521: jt r7 1093 (0, 1093)
+//! 185 1534: add r1 r1 1 (3, 3, 1) r1: 3 -> 4
+//! 190 1545: call 1458 (1458) push 1547 -> 1458
+//! ```
+//!
+//! as JSON Lines, one object per instruction, or in the compact binary
+//! format of the `tracefile` module.
+//!
+//! A `TraceFilter` limits which instructions are written. Filters are
+//! given as space-separated terms, all of which must match:
+//!
+//! ```text
+//! pc:5400-5500      pc in the range (repeat for several ranges)
+//! not-pc:5450-5460  pc outside the range
+//! op:call,ret       one of the listed opcodes
+//! cc:1000-2000      cycle in the window; either end may be left out
+//! regs              only instructions that change a register
+//! reg:r7=5          only instructions that set r7, to 5 if given
+//! value:6068        an operand or a value written is 6068
+//! ```
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use instr::Instruction;
+use opcode::Opcode;
+use tracefile;
+use watch::format_location;
+
+/// How trace records are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    /// One JSON object per line.
+    Json,
+    /// Compact binary records, as described in `tracefile`.
+    Binary,
+    /// Binary records, gzip-compressed.
+    BinaryGz,
+}
+
+impl TraceFormat {
+    pub fn from_name (name: &str) -> Option<TraceFormat> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "json" => Some(TraceFormat::Json),
+            "binary" => Some(TraceFormat::Binary),
+            "binary-gz" => Some(TraceFormat::BinaryGz),
+            _ => None,
+        }
+    }
+}
+
+/// A change made by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delta {
+    Reg { reg: u8, old: u16, new: u16 },
+    Mem { addr: u16, old: u16, new: u16 },
+    Push(u16),
+    Pop(u16),
+}
+
+impl fmt::Display for Delta {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Delta::Reg { reg, old, new } => write!(f, "r{}: {} -> {}", reg, old, new),
+            Delta::Mem { addr, old, new } => write!(f, "{}: {} -> {}", format_location(addr), old, new),
+            Delta::Push(val) => write!(f, "push {}", val),
+            Delta::Pop(val) => write!(f, "pop {}", val),
+        }
+    }
+}
+
+/// One executed instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub cc: u64,
+    pub pc: u16,
+    pub instr: Instruction,
+    /// The operands' values before execution, registers resolved to their
+    /// contents.
+    pub values: Vec<u16>,
+    pub deltas: Vec<Delta>,
+    /// Address of the next instruction.
+    pub next_pc: u16,
+}
+
+impl TraceRecord {
+    /// Whether the instruction transferred control somewhere other than
+    /// the instruction following it.
+    pub fn jumped (&self) -> bool {
+        self.next_pc != self.pc.wrapping_add(self.instr.size())
+    }
+
+    pub fn to_json (&self) -> Value {
+        let deltas: Vec<Value> = self.deltas.iter().map(|d| match *d {
+            Delta::Reg { reg, old, new } => json!({ "reg": reg, "old": old, "new": new }),
+            Delta::Mem { addr, old, new } => json!({ "mem": addr, "old": old, "new": new }),
+            Delta::Push(val) => json!({ "push": val }),
+            Delta::Pop(val) => json!({ "pop": val }),
+        }).collect();
+        json!({
+            "cc": self.cc,
+            "pc": self.pc,
+            "op": self.instr.opcode().mnemonic(),
+            "instr": self.instr.to_string(),
+            "values": self.values,
+            "deltas": deltas,
+            "next_pc": self.next_pc,
+        })
+    }
+}
+
+impl fmt::Display for TraceRecord {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}: {}", self.cc, self.pc, self.instr)?;
+        if !self.values.is_empty() {
+            let values: Vec<String> = self.values.iter().map(|v| v.to_string()).collect();
+            write!(f, " ({})", values.join(", "))?;
+        }
+        for delta in &self.deltas {
+            write!(f, " {}", delta)?;
+        }
+        if self.jumped() {
+            write!(f, " -> {}", self.next_pc)?;
+        }
+        Ok(())
+    }
+}
+
+/// Which instructions are traced. The default traces everything.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// Inclusive pc ranges to trace. Empty means all addresses.
+    pub include: Vec<(u16, u16)>,
+    /// Inclusive pc ranges never to trace.
+    pub exclude: Vec<(u16, u16)>,
+    /// Opcodes to trace. Empty means all opcodes.
+    pub opcodes: Vec<Opcode>,
+    /// First and last cycles to trace.
+    pub from_cc: Option<u64>,
+    pub until_cc: Option<u64>,
+    /// Only trace instructions that change a register.
+    pub reg_change: bool,
+    /// Only trace instructions that change this register, optionally
+    /// only when setting it to the given value.
+    pub reg_write: Option<(u8, Option<u16>)>,
+    /// Only trace instructions where an operand or a value written is this.
+    pub value: Option<u16>,
+}
+
+impl TraceFilter {
+    /// Parses filter terms as described in the module docs.
+    pub fn parse (spec: &str) -> Result<TraceFilter, String> {
+        let mut filter = TraceFilter::default();
+        for term in spec.split_whitespace() {
+            let (key, arg) = match term.find(':') {
+                Some(i) => (&term[..i], &term[i + 1..]),
+                None => (term, ""),
+            };
+            match key {
+                "pc" => filter.include.push(parse_pc_range(arg)?),
+                "not-pc" => filter.exclude.push(parse_pc_range(arg)?),
+                "op" => for name in arg.split(',') {
+                    match Opcode::from_mnemonic(name) {
+                        Some(op) => filter.opcodes.push(op),
+                        None => return Err(format!("unknown opcode '{}'", name)),
+                    }
+                },
+                "cc" => {
+                    let (from, until) = parse_range(arg)?;
+                    filter.from_cc = from;
+                    filter.until_cc = until;
+                },
+                "regs" if arg.is_empty() => filter.reg_change = true,
+                "reg" => {
+                    let (reg, val) = match arg.find('=') {
+                        Some(i) => (&arg[..i], Some(parse_value(&arg[i + 1..])?)),
+                        None => (arg, None),
+                    };
+                    let reg = match reg.as_bytes() {
+                        [b'r', n @ b'0'..=b'7'] => n - b'0',
+                        _ => return Err(format!("invalid register '{}'", reg)),
+                    };
+                    filter.reg_write = Some((reg, val));
+                },
+                "value" => filter.value = Some(parse_value(arg)?),
+                _ => return Err(format!("unknown trace filter '{}'", term)),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether the instruction at `pc` may be traced at cycle `cc`. This
+    /// is checked before executing, so says nothing about `reg_change`.
+    pub fn accepts_instr (&self, cc: u64, pc: u16, op: Opcode) -> bool {
+        let in_range = |&(lo, hi): &(u16, u16)| lo <= pc && pc <= hi;
+        (self.include.is_empty() || self.include.iter().any(in_range)) &&
+            !self.exclude.iter().any(in_range) &&
+            (self.opcodes.is_empty() || self.opcodes.contains(&op)) &&
+            self.from_cc.is_none_or(|from| cc >= from) &&
+            self.until_cc.is_none_or(|until| cc <= until)
+    }
+
+    pub fn accepts (&self, record: &TraceRecord) -> bool {
+        let writes_reg = |want: u8, want_val: Option<u16>| record.deltas.iter().any(|d| match *d {
+            Delta::Reg { reg, new, .. } => reg == want && want_val.is_none_or(|v| v == new),
+            _ => false,
+        });
+        let has_value = |val: u16| record.values.contains(&val) || record.deltas.iter().any(|d| match *d {
+            Delta::Reg { new, .. } | Delta::Mem { new, .. } | Delta::Push(new) => new == val,
+            Delta::Pop(_) => false,
+        });
+        self.accepts_instr(record.cc, record.pc, record.instr.opcode()) &&
+            (!self.reg_change || record.deltas.iter().any(|d| matches!(*d, Delta::Reg { .. }))) &&
+            self.reg_write.is_none_or(|(reg, val)| writes_reg(reg, val)) &&
+            self.value.is_none_or(has_value)
+    }
+}
+
+impl fmt::Display for TraceFilter {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut terms = vec![];
+        for &(lo, hi) in &self.include {
+            terms.push(format!("pc:{}-{}", lo, hi));
+        }
+        for &(lo, hi) in &self.exclude {
+            terms.push(format!("not-pc:{}-{}", lo, hi));
+        }
+        if !self.opcodes.is_empty() {
+            let names: Vec<&str> = self.opcodes.iter().map(|op| op.mnemonic()).collect();
+            terms.push(format!("op:{}", names.join(",")));
+        }
+        if self.from_cc.is_some() || self.until_cc.is_some() {
+            let end = |cc: Option<u64>| cc.map_or(String::new(), |cc| cc.to_string());
+            terms.push(format!("cc:{}-{}", end(self.from_cc), end(self.until_cc)));
+        }
+        if self.reg_change {
+            terms.push("regs".to_string());
+        }
+        match self.reg_write {
+            Some((reg, Some(val))) => terms.push(format!("reg:r{}={}", reg, val)),
+            Some((reg, None)) => terms.push(format!("reg:r{}", reg)),
+            None => {},
+        }
+        if let Some(val) = self.value {
+            terms.push(format!("value:{}", val));
+        }
+        if terms.is_empty() {
+            write!(f, "all")
+        }
+        else {
+            write!(f, "{}", terms.join(" "))
+        }
+    }
+}
+
+// Parses "A-B", "A-", "-B" or "A" into inclusive bounds
+fn parse_range (arg: &str) -> Result<(Option<u64>, Option<u64>), String> {
+    let num = |s: &str| -> Result<Option<u64>, String> {
+        if s.is_empty() {
+            return Ok(None);
+        }
+        let parsed = if s.starts_with("0x") || s.starts_with("0X") {
+            u64::from_str_radix(&s[2..], 16)
+        }
+        else {
+            s.parse()
+        };
+        parsed.map(Some).map_err(|_| format!("invalid number '{}'", s))
+    };
+    match arg.find('-') {
+        Some(i) => Ok((num(&arg[..i])?, num(&arg[i + 1..])?)),
+        None if !arg.is_empty() => {
+            let n = num(arg)?;
+            Ok((n, n))
+        },
+        None => Err("expected a range such as 100-200".to_string()),
+    }
+}
+
+fn parse_value (arg: &str) -> Result<u16, String> {
+    match parse_range(arg)? {
+        (Some(n), Some(m)) if n == m && n <= 0xffff => Ok(n as u16),
+        _ => Err(format!("invalid value '{}'", arg)),
+    }
+}
+
+fn parse_pc_range (arg: &str) -> Result<(u16, u16), String> {
+    let (lo, hi) = parse_range(arg)?;
+    let (lo, hi) = (lo.unwrap_or(0), hi.unwrap_or(32_767));
+    if hi > 32_767 || lo > hi {
+        return Err(format!("invalid address range '{}'", arg));
+    }
+    Ok((lo as u16, hi as u16))
+}
+
+/// Writes trace records while enabled. The output file is only created
+/// the first time tracing is enabled.
+pub struct Tracer {
+    format: TraceFormat,
+    filter: TraceFilter,
+    path: PathBuf,
+    out: Option<Box<dyn Write>>,
+    enabled: bool,
+}
+
+impl Default for Tracer {
+    fn default() -> Tracer {
+        Tracer {
+            format: TraceFormat::Text,
+            filter: TraceFilter::default(),
+            path: PathBuf::from("inst_log.txt"),
+            out: None,
+            enabled: false,
+        }
+    }
+}
+
+impl Tracer {
+    /// Sets the file to trace to. A file already open is closed and the
+    /// new one is created when tracing is next enabled.
+    pub fn set_path<P: AsRef<Path>> (&mut self, path: P) {
+        self.path = path.as_ref().to_path_buf();
+        self.out = None;
+    }
+
+    pub fn path (&self) -> &Path {
+        &self.path
+    }
+
+    pub fn set_format (&mut self, format: TraceFormat) {
+        self.format = format;
+    }
+
+    pub fn set_filter (&mut self, filter: TraceFilter) {
+        self.filter = filter;
+    }
+
+    pub fn filter (&self) -> &TraceFilter {
+        &self.filter
+    }
+
+    /// Traces to `out` instead of a file. The format should be set
+    /// first, since binary traces start with a header.
+    pub fn set_writer (&mut self, out: Box<dyn Write>) -> io::Result<()> {
+        self.out = Some(match self.format {
+            TraceFormat::Binary => tracefile::start_trace(out, false)?,
+            TraceFormat::BinaryGz => tracefile::start_trace(out, true)?,
+            TraceFormat::Text | TraceFormat::Json => out,
+        });
+        Ok(())
+    }
+
+    pub fn start (&mut self) -> io::Result<()> {
+        if self.out.is_none() {
+            let file = BufWriter::new(File::create(&self.path)?);
+            self.set_writer(Box::new(file))?;
+        }
+        self.enabled = true;
+        Ok(())
+    }
+
+    /// Disables tracing, flushing what has been written. It can be
+    /// re-enabled later and will continue in the same file.
+    pub fn stop (&mut self) -> io::Result<()> {
+        self.enabled = false;
+        match self.out {
+            Some(ref mut out) => out.flush(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_enabled (&self) -> bool {
+        self.enabled
+    }
+
+    pub fn write (&mut self, record: &TraceRecord) -> io::Result<()> {
+        let out = match self.out {
+            Some(ref mut out) if self.enabled && self.filter.accepts(record) => out,
+            _ => return Ok(()),
+        };
+        match self.format {
+            TraceFormat::Text => writeln!(out, "{}", record),
+            TraceFormat::Json => writeln!(out, "{}", record.to_json()),
+            TraceFormat::Binary | TraceFormat::BinaryGz => out.write_all(&tracefile::encode(record)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instr::Operand;
+
+    fn record (pc: u16, instr: Instruction, deltas: Vec<Delta>) -> TraceRecord {
+        let values = instr.operands().iter().map(|op| op.word()).collect();
+        TraceRecord { cc: 150, pc, instr, values, deltas, next_pc: pc + instr.size() }
+    }
+
+    #[test]
+    fn parse_filter_terms () {
+        let filter = TraceFilter::parse("pc:5400-5500 not-pc:5450-5460 op:call,ret cc:100- reg:r7=5 value:0x10").unwrap();
+        assert_eq!(filter.include, [(5400, 5500)]);
+        assert_eq!(filter.exclude, [(5450, 5460)]);
+        assert_eq!(filter.opcodes, [Opcode::Call, Opcode::Ret]);
+        assert_eq!((filter.from_cc, filter.until_cc), (Some(100), None));
+        assert_eq!(filter.reg_write, Some((7, Some(5))));
+        assert_eq!(filter.value, Some(16));
+        // Written back out, it parses to the same filter
+        assert_eq!(TraceFilter::parse(&filter.to_string()).unwrap(), filter);
+        assert_eq!(TraceFilter::default().to_string(), "all");
+    }
+
+    #[test]
+    fn parse_filter_errors () {
+        for spec in &["pc:200-100", "pc:40000", "op:frob", "reg:r8", "value:1-2", "cc:", "bogus", "regs:1"] {
+            assert!(TraceFilter::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn filter_accepts () {
+        let set = record(100, Instruction::Set(Operand::Register(7), Operand::Literal(5)),
+            vec![Delta::Reg { reg: 7, old: 0, new: 5 }]);
+        let noop = record(200, Instruction::Noop, vec![]);
+
+        let accepts = |spec: &str, r: &TraceRecord| TraceFilter::parse(spec).unwrap().accepts(r);
+        assert!(accepts("", &set) && accepts("", &noop));
+        assert!(accepts("pc:50-150", &set) && !accepts("pc:50-150", &noop));
+        assert!(!accepts("not-pc:100", &set));
+        assert!(accepts("op:set", &set) && !accepts("op:set", &noop));
+        assert!(accepts("cc:100-200", &set) && !accepts("cc:-149", &set));
+        assert!(accepts("regs", &set) && !accepts("regs", &noop));
+        assert!(accepts("reg:r7=5", &set) && !accepts("reg:r7=6", &set) && !accepts("reg:r0", &set));
+        assert!(accepts("value:5", &set) && !accepts("value:6", &set));
+    }
+}
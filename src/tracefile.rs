@@ -0,0 +1,347 @@
+//! Compact binary trace files.
+//!
+//! A binary trace is a header followed by records, all little-endian:
+//!
+//! ```text
+//! header   "SYNT", u16 version (currently 2)
+//! record   29 bytes, then 6 for each change:
+//!   u64      cycle
+//!   u16      pc
+//!   u16      next pc
+//!   u8       opcode
+//!   u8       stack flags: 1 = pushed, 2 = popped
+//!   u16      value pushed or popped
+//!   3 x u16  operand words, unused ones 0
+//!   3 x u16  operand values before execution
+//!   u8       number of changes
+//!   changes  u16 location (memory address, or 32768 + n for register
+//!            rn), u16 old, u16 new
+//! ```
+//!
+//! Most instructions change one register or memory word, but hooks and
+//! meta-commands can change several in one step, so every change is kept.
+//!
+//! The whole file may be gzip-compressed, which `TraceReader` detects.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use instr::{decode, Instruction};
+use trace::{Delta, TraceRecord};
+
+const MAGIC: &[u8; 4] = b"SYNT";
+const VERSION: u16 = 2;
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Size in bytes of a record without its changes.
+pub const RECORD_SIZE: usize = 29;
+const CHANGE_SIZE: usize = 6;
+// More than the eight registers and a memory word an instruction can change
+const MAX_CHANGES: usize = u8::MAX as usize;
+
+const REG_BASE: u16 = 32_768;
+const PUSHED: u8 = 1;
+const POPPED: u8 = 2;
+
+/// Starts a binary trace on `out`, returning the writer records should
+/// be written to.
+pub fn start_trace (out: Box<dyn Write>, compressed: bool) -> io::Result<Box<dyn Write>> {
+    let mut out = if compressed {
+        Box::new(GzEncoder::new(out, Compression::default()))
+    }
+    else {
+        out
+    };
+    out.write_all(MAGIC)?;
+    let mut version = [0; 2];
+    LittleEndian::write_u16(&mut version, VERSION);
+    out.write_all(&version)?;
+    Ok(out)
+}
+
+/// Encodes a record with all of its register and memory changes.
+pub fn encode (record: &TraceRecord) -> Vec<u8> {
+    let mut buf = vec![0; RECORD_SIZE];
+    LittleEndian::write_u64(&mut buf[0..8], record.cc);
+    LittleEndian::write_u16(&mut buf[8..10], record.pc);
+    LittleEndian::write_u16(&mut buf[10..12], record.next_pc);
+    buf[12] = record.instr.opcode().code() as u8;
+
+    let mut changes = vec![];
+    for delta in &record.deltas {
+        match *delta {
+            Delta::Push(val) => {
+                buf[13] |= PUSHED;
+                LittleEndian::write_u16(&mut buf[14..16], val);
+            },
+            Delta::Pop(val) => {
+                buf[13] |= POPPED;
+                LittleEndian::write_u16(&mut buf[14..16], val);
+            },
+            Delta::Reg { reg, old, new } => changes.push((REG_BASE + u16::from(reg), old, new)),
+            Delta::Mem { addr, old, new } => changes.push((addr, old, new)),
+        }
+    }
+
+    for (i, op) in record.instr.operands().iter().enumerate() {
+        LittleEndian::write_u16(&mut buf[16 + 2 * i..], op.word());
+    }
+    for (i, &val) in record.values.iter().take(3).enumerate() {
+        LittleEndian::write_u16(&mut buf[22 + 2 * i..], val);
+    }
+    buf[28] = changes.len().min(MAX_CHANGES) as u8;
+    for &(loc, old, new) in changes.iter().take(MAX_CHANGES) {
+        let mut change = [0; CHANGE_SIZE];
+        LittleEndian::write_u16(&mut change[0..], loc);
+        LittleEndian::write_u16(&mut change[2..], old);
+        LittleEndian::write_u16(&mut change[4..], new);
+        buf.extend_from_slice(&change);
+    }
+    buf
+}
+
+/// Decodes a record written by `encode`: `RECORD_SIZE` bytes followed by
+/// the number of changes given in its last byte.
+pub fn decode_record (buf: &[u8]) -> io::Result<TraceRecord> {
+    if buf.len() < RECORD_SIZE || buf.len() != RECORD_SIZE + CHANGE_SIZE * buf[RECORD_SIZE - 1] as usize {
+        return Err(invalid("trace record has the wrong length"));
+    }
+    let word = |at: usize| LittleEndian::read_u16(&buf[at..at + 2]);
+    let words = [u16::from(buf[12]), word(16), word(18), word(20)];
+    let instr = decode(&words, 0).map_err(|e| invalid(&format!("bad instruction in trace: {}", e)))?;
+    let arity = instr.size() as usize - 1;
+
+    let mut deltas = vec![];
+    if buf[13] & PUSHED != 0 {
+        deltas.push(Delta::Push(word(14)));
+    }
+    if buf[13] & POPPED != 0 {
+        deltas.push(Delta::Pop(word(14)));
+    }
+    for at in (RECORD_SIZE..buf.len()).step_by(CHANGE_SIZE) {
+        let (loc, old, new) = (word(at), word(at + 2), word(at + 4));
+        deltas.push(if loc >= REG_BASE {
+            Delta::Reg { reg: (loc - REG_BASE) as u8, old, new }
+        }
+        else {
+            Delta::Mem { addr: loc, old, new }
+        });
+    }
+
+    Ok(TraceRecord {
+        cc: LittleEndian::read_u64(&buf[0..8]),
+        pc: word(8),
+        instr,
+        values: (0..arity).map(|i| word(22 + 2 * i)).collect(),
+        deltas,
+        next_pc: word(10),
+    })
+}
+
+/// Reads the records of a binary trace, compressed or not.
+pub struct TraceReader {
+    input: Box<dyn Read>,
+}
+
+impl TraceReader {
+    pub fn new (input: Box<dyn Read>) -> io::Result<TraceReader> {
+        let mut input = BufReader::new(input);
+        let mut start = [0; 2];
+        input.read_exact(&mut start)?;
+
+        // Put back the bytes used to detect compression
+        let input: Box<dyn Read> = Box::new(io::Cursor::new(start).chain(input));
+        let mut input: Box<dyn Read> = if start == GZIP_MAGIC {
+            Box::new(GzDecoder::new(input))
+        }
+        else {
+            input
+        };
+
+        let mut header = [0; 6];
+        input.read_exact(&mut header)?;
+        if &header[0..4] != MAGIC {
+            return Err(invalid("not a binary trace"));
+        }
+        let version = LittleEndian::read_u16(&header[4..6]);
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported trace version {}", version)));
+        }
+        Ok(TraceReader { input })
+    }
+
+    pub fn open<P: AsRef<Path>> (path: P) -> io::Result<TraceReader> {
+        TraceReader::new(Box::new(File::open(path)?))
+    }
+
+    // Fills buf, returning false if the trace ended before any of it
+    fn fill (&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.input.read(&mut buf[filled..]) {
+                Ok(0) if filled == 0 => return Ok(false),
+                Ok(0) => return Err(invalid("trace ends in the middle of a record")),
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl Iterator for TraceReader {
+    type Item = io::Result<TraceRecord>;
+
+    fn next (&mut self) -> Option<io::Result<TraceRecord>> {
+        let mut buf = vec![0; RECORD_SIZE];
+        match self.fill(&mut buf) {
+            Ok(true) => (),
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e)),
+        }
+        let changes = CHANGE_SIZE * buf[RECORD_SIZE - 1] as usize;
+        buf.resize(RECORD_SIZE + changes, 0);
+        match self.fill(&mut buf[RECORD_SIZE..]) {
+            Ok(true) => (),
+            Ok(false) => return Some(Err(invalid("trace ends in the middle of a record"))),
+            Err(e) => return Some(Err(e)),
+        }
+        Some(decode_record(&buf))
+    }
+}
+
+/// How often each address was executed, with the instruction first seen
+/// there, most executed first.
+pub fn count_by_pc<I> (records: I) -> io::Result<Vec<(u16, u64, Instruction)>>
+    where I: Iterator<Item = io::Result<TraceRecord>>
+{
+    let mut counts: HashMap<u16, (u64, Instruction)> = HashMap::new();
+    for record in records {
+        let record = record?;
+        counts.entry(record.pc).or_insert((0, record.instr)).0 += 1;
+    }
+    let mut counts: Vec<_> = counts.into_iter().map(|(pc, (n, instr))| (pc, n, instr)).collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    Ok(counts)
+}
+
+/// Where two traces first differ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the first differing record.
+    pub index: u64,
+    /// The last record the traces agree on.
+    pub common: Option<TraceRecord>,
+    /// The differing records. `None` if that trace ended first.
+    pub left: Option<TraceRecord>,
+    pub right: Option<TraceRecord>,
+}
+
+/// Compares two traces record by record, returning where they first
+/// differ, or `None` if they are identical.
+pub fn first_divergence<A, B> (mut left: A, mut right: B) -> io::Result<Option<Divergence>>
+    where A: Iterator<Item = io::Result<TraceRecord>>,
+          B: Iterator<Item = io::Result<TraceRecord>>
+{
+    let mut common = None;
+    let mut index = 0;
+    loop {
+        let l = left.next().transpose()?;
+        let r = right.next().transpose()?;
+        if l.is_none() && r.is_none() {
+            return Ok(None);
+        }
+        if l != r {
+            return Ok(Some(Divergence { index, common, left: l, right: r }));
+        }
+        common = l;
+        index += 1;
+    }
+}
+
+fn invalid (msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instr::Operand;
+
+    // A hooked call: pushes its return address and, through the hook,
+    // changes three registers and a memory word
+    fn hooked_call () -> TraceRecord {
+        TraceRecord {
+            cc: 1_234_567,
+            pc: 5489,
+            instr: Instruction::Call(Operand::Literal(6027)),
+            values: vec![6027],
+            deltas: vec![
+                Delta::Push(5491),
+                Delta::Reg { reg: 0, old: 4, new: 6 },
+                Delta::Reg { reg: 1, old: 1, new: 5 },
+                Delta::Reg { reg: 7, old: 0, new: 25734 },
+                Delta::Mem { addr: 3000, old: 0, new: 1 },
+            ],
+            next_pc: 5491,
+        }
+    }
+
+    fn plain () -> TraceRecord {
+        TraceRecord {
+            cc: 0,
+            pc: 0,
+            instr: Instruction::Noop,
+            values: vec![],
+            deltas: vec![],
+            next_pc: 1,
+        }
+    }
+
+    #[test]
+    fn encode_round_trip () {
+        // Four register and memory changes after the fixed part
+        for &(ref record, len) in &[(hooked_call(), RECORD_SIZE + 24), (plain(), RECORD_SIZE)] {
+            let buf = encode(record);
+            assert_eq!(buf.len(), len);
+            assert_eq!(&decode_record(&buf).unwrap(), record);
+        }
+        assert!(decode_record(&encode(&hooked_call())[..RECORD_SIZE + 6]).is_err());
+    }
+
+    #[test]
+    fn reader_round_trip () {
+        let mut trace = vec![];
+        trace.extend_from_slice(MAGIC);
+        trace.extend_from_slice(&VERSION.to_le_bytes());
+        for record in &[plain(), hooked_call(), plain()] {
+            trace.extend(encode(record));
+        }
+        let records: Vec<TraceRecord> = TraceReader::new(Box::new(io::Cursor::new(trace.clone())))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(records, [plain(), hooked_call(), plain()]);
+
+        // The same, compressed
+        let mut gz = GzEncoder::new(vec![], Compression::default());
+        gz.write_all(&trace).unwrap();
+        let reader = TraceReader::new(Box::new(io::Cursor::new(gz.finish().unwrap()))).unwrap();
+        assert_eq!(reader.count(), 3);
+
+        // Cut short in the middle of the changes
+        trace.truncate(trace.len() - RECORD_SIZE - 3);
+        let mut reader = TraceReader::new(Box::new(io::Cursor::new(trace))).unwrap();
+        assert!(reader.next().unwrap().is_ok());
+        assert!(reader.next().unwrap().is_err());
+    }
+}
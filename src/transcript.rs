@@ -0,0 +1,301 @@
+//! Recording and replaying the input of a play session.
+//!
+//! A transcript is a text file with one input line per line, stamped
+//! with the cycle at which the program read it:
+//!
+//! ```text
+//! # synacor transcript 1
+//! 732585 take tablet
+//! 738906 use tablet
+//! end 745120 3f2a9c0e1d7b6a54
+//! ```
+//!
+//! The optional `end` record gives the cycle the recording stopped at and
+//! a hash of all output produced up to then, so a replay can check that
+//! it reproduced the session exactly. Lines starting with `#` are
+//! comments.
+
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const HEADER: &str = "# synacor transcript 1";
+
+// FNV-1a parameters for 64-bit hashes
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// One input line and the cycle at which it was read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub cc: u64,
+    /// The line without its trailing newline.
+    pub line: String,
+}
+
+/// Where and with what output a recording stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct End {
+    pub cc: u64,
+    pub output_hash: u64,
+}
+
+/// A parsed transcript.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Transcript {
+    pub entries: Vec<Entry>,
+    pub end: Option<End>,
+}
+
+/// A malformed transcript line, 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranscriptError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for TranscriptError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl Error for TranscriptError {}
+
+impl Transcript {
+    pub fn parse (src: &str) -> Result<Transcript, TranscriptError> {
+        let mut transcript = Transcript::default();
+        for (i, text) in src.lines().enumerate() {
+            let err = |msg: &str| TranscriptError { line: i + 1, msg: msg.to_string() };
+            if text.starts_with('#') || text.is_empty() {
+                continue;
+            }
+            if transcript.end.is_some() {
+                return Err(err("input after the end record"));
+            }
+
+            // The line itself follows the first space verbatim
+            let (stamp, line) = match text.find(' ') {
+                Some(i) => (&text[..i], &text[i + 1..]),
+                None => (text, ""),
+            };
+            if stamp == "end" {
+                let mut fields = line.split_whitespace();
+                let cc = fields.next().and_then(|cc| cc.parse().ok());
+                let hash = fields.next().and_then(|h| u64::from_str_radix(h, 16).ok());
+                match (cc, hash, fields.next()) {
+                    (Some(cc), Some(output_hash), None) =>
+                        transcript.end = Some(End { cc, output_hash }),
+                    _ => return Err(err("expected 'end CYCLE HASH'")),
+                }
+                continue;
+            }
+
+            let cc = match stamp.parse() {
+                Ok(cc) => cc,
+                Err(_) => return Err(err(&format!("invalid cycle stamp '{}'", stamp))),
+            };
+            transcript.entries.push(Entry { cc, line: line.to_string() });
+        }
+        Ok(transcript)
+    }
+}
+
+/// Writes a transcript as input is read. Each line is flushed as it is
+/// recorded, so the transcript survives the process being killed.
+pub struct Recorder {
+    out: Box<dyn Write>,
+}
+
+impl Recorder {
+    /// Starts a transcript on `out`, writing its header.
+    pub fn new (mut out: Box<dyn Write>) -> io::Result<Recorder> {
+        writeln!(out, "{}", HEADER)?;
+        out.flush()?;
+        Ok(Recorder { out })
+    }
+
+    /// Starts a transcript in a new file.
+    pub fn create<P: AsRef<Path>> (path: P) -> io::Result<Recorder> {
+        Recorder::new(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn record (&mut self, cc: u64, line: &str) -> io::Result<()> {
+        writeln!(self.out, "{} {}", cc, line)?;
+        self.out.flush()
+    }
+
+    /// Writes the end record. Nothing should be recorded after it.
+    pub fn finish (&mut self, end: End) -> io::Result<()> {
+        writeln!(self.out, "end {} {:016x}", end.cc, end.output_hash)?;
+        self.out.flush()
+    }
+}
+
+/// A transcript being fed back through the VM's input path.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    transcript: Transcript,
+    next: usize,
+    stop_at: Option<usize>,
+}
+
+/// What a replay supplies when the program asks for input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ReplayInput {
+    /// The next recorded line, stamped with the cycle it is expected at.
+    Line(Entry),
+    /// The replay should stop before this input.
+    Stop,
+    /// The transcript has been used up.
+    Done,
+}
+
+impl Replay {
+    /// Replays all of `transcript`.
+    pub fn new (transcript: Transcript) -> Replay {
+        Replay { transcript, next: 0, stop_at: None }
+    }
+
+    /// Stops the replay before input `line` (1-based) is read, instead of
+    /// handing over to the VM's `Io` backend at the end. Has no effect
+    /// if the transcript is shorter.
+    pub fn stop_at (mut self, line: usize) -> Replay {
+        self.stop_at = Some(line);
+        self
+    }
+
+    /// Number of lines replayed so far.
+    pub fn position (&self) -> usize {
+        self.next
+    }
+
+    pub fn transcript (&self) -> &Transcript {
+        &self.transcript
+    }
+
+    /// Whether every recorded line has been replayed.
+    pub fn is_finished (&self) -> bool {
+        self.next == self.transcript.entries.len()
+    }
+
+    pub(crate) fn next_input (&mut self) -> ReplayInput {
+        if self.stop_at == Some(self.next + 1) {
+            return ReplayInput::Stop;
+        }
+        match self.transcript.entries.get(self.next) {
+            Some(entry) => {
+                self.next += 1;
+                ReplayInput::Line(entry.clone())
+            },
+            None => ReplayInput::Done,
+        }
+    }
+}
+
+/// A 64-bit FNV-1a hash of the program's output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputHash(u64);
+
+impl Default for OutputHash {
+    fn default() -> OutputHash {
+        OutputHash(FNV_OFFSET)
+    }
+}
+
+impl OutputHash {
+    pub fn update (&mut self, ch: char) {
+        let mut buf = [0; 4];
+        for &b in ch.encode_utf8(&mut buf).as_bytes() {
+            self.0 = (self.0 ^ u64::from(b)).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    pub fn value (&self) -> u64 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::*;
+    use asm::assemble;
+    use error::VmError;
+    use io::BufferIo;
+    use vm::{StopReason, Vm};
+
+    // Echoes two lines of input, then halts
+    const ECHO: &str = "\
+loop:   in r0
+        out r0
+        eq r1 r0 10
+        jf r1 loop
+        add r2 r2 1
+        eq r1 r2 2
+        jf r1 loop
+        halt
+";
+
+    // FNV-1a of "hello\nworld\n"
+    const ECHO_HASH: u64 = 0x2925_8354_1038_8969;
+
+    fn echo_vm (input: &str) -> (Vm, BufferIo) {
+        let io = BufferIo::new(input);
+        let mut vm = Vm::with_io(Box::new(io.clone()));
+        vm.load_mem(&assemble(ECHO).unwrap()).unwrap();
+        (vm, io)
+    }
+
+    fn record () -> Transcript {
+        let path = env::temp_dir().join(format!("synacor-transcript-{}.txt", ::std::process::id()));
+        let (mut vm, _) = echo_vm("hello\nworld\n");
+        vm.start_recording(&path).unwrap();
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        vm.finish_recording().unwrap();
+        let src = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        Transcript::parse(&src).unwrap()
+    }
+
+    #[test]
+    fn output_hash_is_fnv_1a () {
+        let mut hash = OutputHash::default();
+        "hello\nworld\n".chars().for_each(|ch| hash.update(ch));
+        assert_eq!(hash.value(), ECHO_HASH);
+    }
+
+    #[test]
+    fn record_and_replay () {
+        let transcript = record();
+        let lines: Vec<&str> = transcript.entries.iter().map(|e| e.line.as_str()).collect();
+        assert_eq!(lines, ["hello", "world"]);
+        let end = transcript.end.unwrap();
+        assert_eq!(end.output_hash, ECHO_HASH);
+
+        // With no input of its own, the replay reproduces the session
+        let (mut vm, io) = echo_vm("");
+        vm.start_replay(Replay::new(transcript));
+        assert_eq!(vm.run().unwrap(), StopReason::Halted);
+        assert_eq!(io.output(), "hello\nworld\n");
+        assert_eq!(vm.output_hash(), ECHO_HASH);
+        assert_eq!(vm.cc(), end.cc);
+    }
+
+    #[test]
+    fn replay_detects_divergence () {
+        let mut transcript = record();
+        transcript.entries[1].cc += 1;
+        let (mut vm, _) = echo_vm("");
+        vm.start_replay(Replay::new(transcript));
+        match vm.run() {
+            Err(VmError::ReplayDiverged { line: 2, .. }) => (),
+            other => panic!("expected the second line to diverge, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,359 @@
+//! Finds the shortest walk across the vault's grid of rooms that brings
+//! the orb to the door at the weight carved into it.
+//!
+//! Each room's floor shows a number or an operator. The orb starts at the
+//! weight on its pedestal, and walking through an operator then a number
+//! applies that operation to it. Entering the start room again resets the
+//! orb, and entering the door room at the wrong weight shatters it, so a
+//! walk may do neither. Weights wrap modulo 32768, as the VM's arithmetic
+//! does.
+//!
+//! The grid can be read from the rooms in a running program's memory,
+//! or from a text file with the rooms laid out north at the top:
+//!
+//! ```text
+//! target 30
+//! *  8  -  1
+//! 4  *  11 *
+//! +  4  -  18
+//! 22 -  9  *
+//! ```
+//!
+//! The orb starts in the bottom left room and the door is in the top
+//! right, unless `start ROW COL` or `door ROW COL` lines, counting from
+//! 0 at the top left, say otherwise.
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+
+use regex::Regex;
+
+const MOD: u32 = 32768;
+
+// Longest string that can be a room title or description
+const MAX_STRING_LEN: usize = 1024;
+
+// Most exits a room can have
+const MAX_EXITS: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+}
+
+impl Op {
+    fn from_symbol (s: &str) -> Option<Op> {
+        match s {
+            "+" => Some(Op::Add),
+            "-" => Some(Op::Sub),
+            "*" => Some(Op::Mul),
+            _ => None,
+        }
+    }
+
+    fn apply (self, a: u16, b: u16) -> u16 {
+        let (a, b) = (a as u32, b as u32);
+        (match self {
+            Op::Add => (a + b) % MOD,
+            Op::Sub => (a + MOD - b) % MOD,
+            Op::Mul => (a * b) % MOD,
+        }) as u16
+    }
+}
+
+/// What a room's floor shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tile {
+    Number(u16),
+    Op(Op),
+}
+
+/// A room of the grid and the exits to its neighbours.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VaultRoom {
+    pub tile: Tile,
+    /// Exit names and the indices of the rooms they lead to.
+    pub exits: Vec<(String, usize)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vault {
+    pub rooms: Vec<VaultRoom>,
+    /// Where the orb starts. Its tile is the orb's starting weight.
+    pub start: usize,
+    pub door: usize,
+    /// The weight the orb must have at the door.
+    pub target: u16,
+}
+
+/// A malformed grid file line, 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VaultError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for VaultError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl Error for VaultError {}
+
+// Reads a length-prefixed string, if there is a plausible one at addr
+fn string_at (mem: &[u16], addr: u16) -> Option<String> {
+    let len = *mem.get(addr as usize)? as usize;
+    let words = mem.get(addr as usize + 1..addr as usize + 1 + len)?;
+    if len == 0 || len > MAX_STRING_LEN || words.iter().any(|&w| w > 127) {
+        return None;
+    }
+    Some(words.iter().map(|&w| w as u8 as char).collect())
+}
+
+// Reads a length-prefixed list of words
+fn list_at (mem: &[u16], addr: u16) -> Option<&[u16]> {
+    let len = *mem.get(addr as usize)? as usize;
+    if len > MAX_EXITS {
+        return None;
+    }
+    mem.get(addr as usize + 1..addr as usize + 1 + len)
+}
+
+// A room in memory: its exits, as names and room addresses, and its
+// description
+fn room_at (mem: &[u16], addr: usize) -> Option<(Vec<(String, u16)>, String)> {
+    let words = mem.get(addr..addr + 4)?;
+    string_at(mem, words[0])?;
+    let description = string_at(mem, words[1])?;
+    let names = list_at(mem, words[2])?;
+    let targets = list_at(mem, words[3])?;
+    if names.len() != targets.len() {
+        return None;
+    }
+    let exits = names.iter().map(|&n| string_at(mem, n)).zip(targets)
+        .map(|(name, &to)| name.map(|name| (name, to)))
+        .collect::<Option<Vec<_>>>()?;
+    Some((exits, description))
+}
+
+impl Vault {
+    pub fn parse_grid (src: &str) -> Result<Vault, VaultError> {
+        let mut target = None;
+        let mut start = None;
+        let mut door = None;
+        let mut grid: Vec<Vec<Tile>> = vec![];
+        for (i, text) in src.lines().enumerate() {
+            let err = |msg: &str| VaultError { line: i + 1, msg: msg.to_string() };
+            let fields: Vec<&str> = text.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => (),
+                [first, ..] if first.starts_with('#') => (),
+                ["target", n] => {
+                    let n = n.parse().ok().filter(|&n| n < MOD as u16);
+                    target = Some(n.ok_or_else(|| err("invalid target"))?);
+                },
+                ["start", row, col] | ["door", row, col] => {
+                    let pos = match (row.parse::<usize>(), col.parse::<usize>()) {
+                        (Ok(row), Ok(col)) => (row, col),
+                        _ => return Err(err("expected ROW COL")),
+                    };
+                    if fields[0] == "start" { start = Some(pos) } else { door = Some(pos) }
+                },
+                _ => {
+                    let row = fields.iter().map(|f| match Op::from_symbol(f) {
+                        Some(op) => Some(Tile::Op(op)),
+                        None => f.parse().ok().filter(|&n| n < MOD as u16).map(Tile::Number),
+                    }).collect::<Option<Vec<_>>>();
+                    match row {
+                        Some(row) if grid.first().is_none_or(|r| r.len() == row.len()) => grid.push(row),
+                        Some(_) => return Err(err("rows must all be the same length")),
+                        None => return Err(err("expected numbers and the operators +, - and *")),
+                    }
+                },
+            }
+        }
+
+        let err = |msg: &str| VaultError { line: src.lines().count(), msg: msg.to_string() };
+        let target = target.ok_or_else(|| err("no target given"))?;
+        if grid.is_empty() {
+            return Err(err("no grid given"));
+        }
+        let (rows, cols) = (grid.len(), grid[0].len());
+        let index = |(row, col): (usize, usize)| if row < rows && col < cols { Ok(row * cols + col) } else { Err(err("position outside the grid")) };
+        let start = index(start.unwrap_or((rows - 1, 0)))?;
+        let door = index(door.unwrap_or((0, cols - 1)))?;
+        if let Tile::Op(_) = grid[start / cols][start % cols] {
+            return Err(err("the start room must have a number"));
+        }
+
+        let mut rooms = vec![];
+        for (row, tiles) in grid.iter().enumerate() {
+            for (col, &tile) in tiles.iter().enumerate() {
+                let mut exits = vec![];
+                let mut exit = |name: &str, row: usize, col: usize| exits.push((name.to_string(), row * cols + col));
+                if row > 0 { exit("north", row - 1, col) }
+                if col + 1 < cols { exit("east", row, col + 1) }
+                if row + 1 < rows { exit("south", row + 1, col) }
+                if col > 0 { exit("west", row, col - 1) }
+                rooms.push(VaultRoom { tile, exits });
+            }
+        }
+        Ok(Vault { rooms, start, door, target })
+    }
+
+    /// Finds the vault's rooms in memory, by their descriptions: the
+    /// mosaic on each floor, the weight carved on the orb's pedestal and
+    /// the one carved on the door.
+    pub fn from_memory (mem: &[u16]) -> Option<Vault> {
+        let mosaic = Regex::new(r"mosaic depicting (?:the number '(\d+)'|a '([-+*])' symbol)").unwrap();
+        let pedestal = Regex::new(r"'(\d+)' is carved into the orb's pedestal").unwrap();
+        let door = Regex::new(r"a large '(\d+)' carved into it").unwrap();
+
+        // Addresses of the vault's rooms, their tiles and exits
+        let mut found = vec![];
+        let mut start = None;
+        let mut target = None;
+        for addr in 0..mem.len() {
+            let (exits, description) = match room_at(mem, addr) {
+                Some(room) => room,
+                None => continue,
+            };
+            let number = |re: &Regex| re.captures(&description).and_then(|c| c[1].parse::<u16>().ok());
+            let tile = if let Some(n) = number(&pedestal) {
+                start = Some(found.len());
+                Tile::Number(n)
+            }
+            else {
+                match mosaic.captures(&description) {
+                    Some(c) => match (c.get(1), c.get(2)) {
+                        (Some(n), _) => Tile::Number(n.as_str().parse().ok()?),
+                        (_, Some(op)) => Tile::Op(Op::from_symbol(op.as_str())?),
+                        _ => continue,
+                    },
+                    None => continue,
+                }
+            };
+            if let Some(n) = number(&door) {
+                target = Some((found.len(), n));
+            }
+            found.push((addr as u16, tile, exits));
+        }
+
+        // Keep the exits between vault rooms
+        let index: HashMap<u16, usize> = found.iter().enumerate().map(|(i, r)| (r.0, i)).collect();
+        let rooms = found.into_iter().map(|(_, tile, exits)| VaultRoom {
+            tile,
+            exits: exits.into_iter().filter_map(|(name, to)| index.get(&to).map(|&i| (name, i))).collect(),
+        }).collect();
+        let (door, target) = target?;
+        Some(Vault { rooms, start: start?, door, target })
+    }
+
+    /// The exits to take from the start room along a shortest walk that
+    /// reaches the door at the target weight, or None if there is none.
+    pub fn solve (&self) -> Option<Vec<&str>> {
+        let start = match self.rooms[self.start].tile {
+            Tile::Number(n) => n,
+            Tile::Op(_) => return None,
+        };
+        // Breadth-first over (room, weight), remembering how each state
+        // was first reached. An operator room holds its operator until
+        // the next number is reached.
+        let mut came_from: HashMap<(usize, u16), (usize, u16, usize)> = HashMap::new();
+        let mut queue = VecDeque::new();
+        queue.push_back((self.start, start));
+        while let Some((room, weight)) = queue.pop_front() {
+            for (i, &(_, next)) in self.rooms[room].exits.iter().enumerate() {
+                if next == self.start {
+                    continue;
+                }
+                let new_weight = match (self.rooms[room].tile, self.rooms[next].tile) {
+                    (Tile::Op(op), Tile::Number(n)) => op.apply(weight, n),
+                    _ => weight,
+                };
+                if next == self.door && new_weight != self.target {
+                    continue;
+                }
+                let state = (next, new_weight);
+                if came_from.contains_key(&state) {
+                    continue;
+                }
+                came_from.insert(state, (room, weight, i));
+                if next == self.door {
+                    return Some(self.walk_back(&came_from, state));
+                }
+                queue.push_back(state);
+            }
+        }
+        None
+    }
+
+    // Follows came_from back to the start, returning the exits taken
+    fn walk_back (&self, came_from: &HashMap<(usize, u16), (usize, u16, usize)>, end: (usize, u16)) -> Vec<&str> {
+        let mut exits = vec![];
+        let mut state = end;
+        while let Some(&(room, weight, exit)) = came_from.get(&state) {
+            exits.push(self.rooms[room].exits[exit].0.as_str());
+            state = (room, weight);
+        }
+        exits.reverse();
+        exits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The grid from the module docs
+    const GRID: &str = "\
+target 30
+*  8  -  1
+4  *  11 *
++  4  -  18
+22 -  9  *
+";
+
+    fn err (src: &str) -> VaultError {
+        Vault::parse_grid(src).unwrap_err()
+    }
+
+    #[test]
+    fn solves_documented_grid () {
+        let vault = Vault::parse_grid(GRID).unwrap();
+        assert_eq!((vault.rooms.len(), vault.start, vault.door, vault.target), (16, 12, 3, 30));
+        assert_eq!(vault.rooms[12].exits, [("north".to_string(), 8), ("east".to_string(), 13)]);
+
+        let walk = vault.solve().unwrap();
+        assert_eq!(walk, [
+            "north", "east", "east", "north", "west", "south",
+            "east", "east", "west", "north", "north", "east",
+        ]);
+    }
+
+    #[test]
+    fn unreachable_target () {
+        // Every way to the door passes a + next to the start, and the
+        // start can't be entered again, so the only weight is 1 + 2
+        let vault = Vault::parse_grid("target 4\n+ 2\n1 +").unwrap();
+        assert_eq!(vault.solve(), None);
+        let vault = Vault::parse_grid("target 3\n+ 2\n1 +").unwrap();
+        assert_eq!(vault.solve(), Some(vec!["north", "east"]));
+    }
+
+    #[test]
+    fn grid_errors () {
+        assert_eq!(err("target 30\n* 8\n4 * 11"), VaultError { line: 3, msg: "rows must all be the same length".to_string() });
+        assert_eq!(err("target 30\n4 /").msg, "expected numbers and the operators +, - and *");
+        assert_eq!(err("target 30\n* 8\n- 1").msg, "the start room must have a number");
+        assert_eq!(err("target 30\nstart 0 2\n4 8").msg, "position outside the grid");
+        assert_eq!(err("* 8\n4 1\n").msg, "no target given");
+        assert_eq!(err("target 30\n"), VaultError { line: 1, msg: "no grid given".to_string() });
+        assert_eq!(err("target 32768\n4 8"), VaultError { line: 1, msg: "invalid target".to_string() });
+        assert_eq!(err("target 30\n4 32768").msg, "expected numbers and the operators +, - and *");
+    }
+}
@@ -0,0 +1,1822 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use diag::{Diagnostics, Level};
+use error::VmError;
+use history::{History, Undo, UndoEntry};
+use io::{Io, StdIo};
+use instr::{decode, DecodeError, Instruction, Operand};
+use opcode::Opcode;
+use patch::{AppliedPatch, PatchError, PatchSet};
+use snapshot::Snapshot;
+use trace::{Delta, TraceFilter, TraceFormat, TraceRecord, Tracer};
+use transcript::{End, OutputHash, Recorder, Replay, ReplayInput};
+use watch::{WatchAction, WatchHit, WatchKind, Watchpoint};
+
+const MOD: u16 = 32_768;
+const MAX_ADDR: u16 = 32_775;
+const MAX_VALID_VAL: u16 = 32_775;
+const MAX_MEM_ADDR: u16 = 32_767;
+const MEM_CAPACITY: usize = 32_768;
+const MAX_15_BIT_VAL: u16 = 32_767;
+const MAX_REG_ID: u16 = 7;
+// Longest instruction: an opcode and three operands
+const MAX_INSTR_WORDS: u16 = 4;
+// Where the DUMP meta-command writes memory
+const MEMDUMP_PATH: &str = "memdump.txt";
+
+/// Why `Vm::run` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The program executed `halt`, or `ret` with an empty stack.
+    Halted,
+    /// The program counter reached a breakpoint, or the cycle count
+    /// reached the cycle breakpoint.
+    Breakpoint,
+    /// The cycle limit set with `set_max_cycles` was reached.
+    CycleLimit,
+    /// `DEBUG` was entered at an input prompt. The `in` instruction
+    /// has not been executed and will wait for input again on resume.
+    Paused,
+    /// The condition passed to `run_until` became true.
+    Reached,
+    /// A watchpoint with `WatchAction::Break` fired. The hits are
+    /// available from `watch_hits`.
+    Watchpoint,
+    /// A replay reached the line it was told to stop at. The `in`
+    /// instruction has not been executed.
+    ReplayEnd,
+}
+
+/// A function call in progress, tracked from `call` and `ret`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    /// Address of the `call` instruction.
+    pub call_pc: u16,
+    /// Address called.
+    pub target: u16,
+    /// Address pushed by the call, where `ret` should resume.
+    pub return_addr: u16,
+    /// Stack depth after the return address was pushed.
+    pub stack_depth: usize,
+}
+
+// Machine state captured before an instruction is traced
+struct TraceStart {
+    pc: u16,
+    instr: Instruction,
+    values: Vec<u16>,
+    reg: Vec<u16>,
+    stack_len: usize,
+    stack_top: Option<u16>,
+    mem: Option<(u16, u16)>,
+}
+
+/// Native code run in place of a guest routine. See `Vm::add_hook`.
+pub type Hook = Box<dyn FnMut(&mut Vm)>;
+
+// State captured before an instruction runs, to build its undo entry
+struct UndoStart {
+    pc: u16,
+    cc: u64,
+    halt: bool,
+    reg: [u16; 8],
+    stack_len: usize,
+    stack_top: Option<u16>,
+    frames_len: usize,
+    // Only kept for ret, which may drop several frames
+    frames: Option<Vec<Frame>>,
+    mem: Option<(u16, u16)>,
+    // Register written by in
+    input_reg: Option<u8>,
+    out_hash: OutputHash,
+}
+
+/// A Synacor virtual machine: eight registers, 32768 words of memory
+/// and an unbounded stack, as described in `arch-spec`.
+///
+/// ```no_run
+/// use synacor::Vm;
+///
+/// let mut vm = Vm::new();
+/// vm.load_mem(&[9, 32768, 32769, 4, 19, 32768]).unwrap();
+/// vm.run().unwrap();
+/// ```
+pub struct Vm {
+    // 8 registers holding 16-bit values. This
+    // vector is 8 elements long and refers to
+    // r0..r7 respectively. Mem addresses 32768
+    // to 32775 refer to r0..r7.
+    reg: Vec<u16>,
+
+    // 15-bit address space storing 16-bit values,
+    // so this vector is 32768 elements long
+    mem: Vec<u16>,
+
+    // Unbounded stack holding 16-bit values
+    stack: Vec<u16>,
+
+    // Program counter
+    pc: u16,
+
+    // Cycle counter
+    cc: u64,
+
+    // Address of the instruction currently being executed,
+    // reported in errors raised while decoding its operands
+    instr_pc: u16,
+
+    // Execution halt flag
+    halt: bool,
+
+    // Addresses at which run() stops before executing
+    breakpoints: HashSet<u16>,
+
+    // Cycle count at which run() stops
+    break_cc: Option<u64>,
+
+    // Set by the DEBUG meta-command to stop run()
+    pause_requested: bool,
+
+    // Set when a meta-command interrupted an in instruction, which
+    // will run again and so isn't counted as a cycle
+    incomplete: bool,
+
+    // Shadow call stack maintained by call and ret
+    frames: Vec<Frame>,
+
+    watchpoints: Vec<Watchpoint>,
+
+    // Watchpoints fired by the last instruction
+    watch_hits: Vec<WatchHit>,
+
+    // Stop running once the cycle counter reaches this value
+    max_cycles: Option<u64>,
+
+    input_buffer: String,
+
+    // Transcript of the input read, if recording
+    recorder: Option<Recorder>,
+
+    // Transcript fed to in ahead of the Io backend
+    replay: Option<Replay>,
+
+    // Set when a replay stops before an in instruction
+    replay_stopped: bool,
+
+    // Hash of everything written by out
+    out_hash: OutputHash,
+
+    // Backend for the in and out opcodes
+    io: Box<dyn Io>,
+
+    // Instruction trace, written to while logging is enabled
+    tracer: Tracer,
+
+    // Directory holding the SAVE and LOAD slots
+    save_dir: PathBuf,
+
+    // Undo log used to step backwards
+    history: History,
+
+    // Instructions already decoded, by address. Entries are cleared
+    // when a word they were decoded from is written.
+    decoded: Vec<Option<Instruction>>,
+    decode_cache: bool,
+
+    // Messages about the VM itself, kept out of the program's output
+    diag: Diagnostics,
+
+    // Native replacements for routines, by address
+    hooks: HashMap<u16, Hook>,
+
+    // Patch sets in force, oldest first
+    patches: Vec<AppliedPatch>,
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
+}
+
+impl Vm {
+    /// Creates a machine with zeroed registers and memory, an empty
+    /// stack and the program counter at address 0, talking to the
+    /// terminal.
+    pub fn new() -> Vm {
+        Vm::with_io(Box::new(StdIo))
+    }
+
+    /// Creates a machine as `new()` does, using `io` for `in` and `out`.
+    pub fn with_io (io: Box<dyn Io>) -> Vm {
+        Vm {
+            reg: vec![0; 8],
+            mem: vec![0; MEM_CAPACITY],
+            stack: vec![],
+            pc: 0,
+            halt: false,
+            cc: 0,
+            instr_pc: 0,
+            breakpoints: HashSet::new(),
+            break_cc: None,
+            pause_requested: false,
+            incomplete: false,
+            frames: vec![],
+            watchpoints: vec![],
+            watch_hits: vec![],
+            max_cycles: None,
+            input_buffer: String::new(),
+            recorder: None,
+            replay: None,
+            replay_stopped: false,
+            out_hash: OutputHash::default(),
+            io,
+            tracer: Tracer::default(),
+            save_dir: PathBuf::from("."),
+            history: History::default(),
+            decoded: vec![None; MEM_CAPACITY],
+            decode_cache: true,
+            diag: Diagnostics::default(),
+            hooks: HashMap::new(),
+            patches: vec![],
+        }
+    }
+
+    /// Loads a program image into memory starting at address 0.
+    pub fn load_mem (&mut self, mem_input: &[u16]) -> Result<(), VmError> {
+        if mem_input.is_empty() {
+            return Err(VmError::EmptyImage);
+        }
+        else if mem_input.len() > MEM_CAPACITY {
+            return Err(VmError::ImageTooLarge { len: mem_input.len() });
+        }
+
+        self.mem[..mem_input.len()].clone_from_slice(mem_input);
+        self.clear_decoded();
+        self.patches.clear();
+
+        Ok(())
+    }
+
+    /// Replaces the backend for `in` and `out`, returning the old one.
+    pub fn set_io (&mut self, io: Box<dyn Io>) -> Box<dyn Io> {
+        ::std::mem::replace(&mut self.io, io)
+    }
+
+    /// Sets the file the instruction trace is written to when logging is
+    /// enabled. Defaults to `inst_log.txt`.
+    pub fn set_log_path<P: AsRef<Path>> (&mut self, path: P) {
+        self.tracer.set_path(path);
+    }
+
+    /// Sets how the instruction trace is written. Defaults to text.
+    pub fn set_log_format (&mut self, format: TraceFormat) {
+        self.tracer.set_format(format);
+    }
+
+    /// Limits which instructions are written to the trace.
+    pub fn set_log_filter (&mut self, filter: TraceFilter) {
+        self.tracer.set_filter(filter);
+    }
+
+    pub fn log_filter (&self) -> &TraceFilter {
+        self.tracer.filter()
+    }
+
+    /// Writes the instruction trace to `out` instead of a file. Set the
+    /// format first.
+    pub fn set_log_writer (&mut self, out: Box<dyn Write>) -> io::Result<()> {
+        self.tracer.set_writer(out)
+    }
+
+    /// Enables the instruction trace, creating the log file on first use.
+    pub fn start_logging (&mut self) -> io::Result<()> {
+        self.tracer.start()
+    }
+
+    /// Disables the instruction trace. It can be re-enabled later and
+    /// will continue in the same file.
+    pub fn stop_logging (&mut self) -> io::Result<()> {
+        self.tracer.stop()
+    }
+
+    pub fn is_logging (&self) -> bool {
+        self.tracer.is_enabled()
+    }
+
+    /// Sets the directory holding the slots used by the `SAVE` and `LOAD`
+    /// meta-commands. Defaults to the current directory.
+    pub fn set_save_dir<P: AsRef<Path>> (&mut self, dir: P) {
+        self.save_dir = dir.as_ref().to_path_buf();
+    }
+
+    /// Captures the complete machine state.
+    pub fn snapshot (&self) -> Snapshot {
+        Snapshot {
+            pc: self.pc,
+            cc: self.cc,
+            halt: self.halt,
+            reg: self.reg.clone(),
+            stack: self.stack.clone(),
+            frames: self.frames.clone(),
+            input_buffer: self.input_buffer.clone(),
+            mem: self.mem.clone(),
+        }
+    }
+
+    /// Returns the machine to a state captured by `snapshot`.
+    /// Breakpoints, watchpoints, logging and I/O are left as they are.
+    pub fn restore (&mut self, snap: &Snapshot) {
+        self.pc = snap.pc;
+        self.instr_pc = snap.pc;
+        self.cc = snap.cc;
+        self.halt = snap.halt;
+        self.reg.clone_from_slice(&snap.reg);
+        self.mem.clone_from_slice(&snap.mem);
+        self.clear_decoded();
+        self.stack = snap.stack.clone();
+        self.frames = snap.frames.clone();
+        self.input_buffer = snap.input_buffer.clone();
+        self.watch_hits.clear();
+        self.history.clear();
+        self.patches.clear();
+    }
+
+    /// Where diagnostics go and which are kept. By default they are
+    /// discarded.
+    pub fn diagnostics (&self) -> &Diagnostics {
+        &self.diag
+    }
+
+    pub fn diagnostics_mut (&mut self) -> &mut Diagnostics {
+        &mut self.diag
+    }
+
+    /// Turns the cache of decoded instructions on or off. It is on by
+    /// default; turning it off decodes every instruction as it runs.
+    pub fn set_decode_cache (&mut self, enabled: bool) {
+        self.decode_cache = enabled;
+        self.clear_decoded();
+    }
+
+    fn clear_decoded (&mut self) {
+        for entry in &mut self.decoded {
+            *entry = None;
+        }
+    }
+
+    // Forgets the instructions that may include the word at addr
+    fn invalidate_decoded (&mut self, addr: u16) {
+        for start in addr.saturating_sub(MAX_INSTR_WORDS - 1)..=addr {
+            self.decoded[start as usize] = None;
+        }
+    }
+
+    /// Keeps undo information for the last `depth` instructions so that
+    /// `reverse_step` can go back over them. 0, the default, turns the
+    /// history off. Changes made through `mem_write`, `set_reg` and the
+    /// like from outside a step are not recorded.
+    pub fn set_history_depth (&mut self, depth: usize) {
+        self.history.set_depth(depth);
+    }
+
+    pub fn history_depth (&self) -> usize {
+        self.history.depth()
+    }
+
+    /// Number of instructions `reverse_step` can currently undo.
+    pub fn history_len (&self) -> usize {
+        self.history.len()
+    }
+
+    /// Undoes the last instruction executed, returning false if there is
+    /// no history left. Output already written and input lines already
+    /// read stay as they are, but characters consumed by `in` are put
+    /// back so that stepping forward again reads them again.
+    pub fn reverse_step (&mut self) -> bool {
+        let entry = match self.history.pop() {
+            Some(entry) => entry,
+            None => return false,
+        };
+        for change in entry.changes.into_iter().rev() {
+            match change {
+                Undo::Reg(r, val) => self.reg[r as usize] = val,
+                Undo::Mem(addr, val) => {
+                    self.mem[addr as usize] = val;
+                    self.invalidate_decoded(addr);
+                },
+                Undo::Pop => { self.stack.pop(); },
+                Undo::Push(val) => self.stack.push(val),
+                Undo::PopFrame => { self.frames.pop(); },
+                Undo::Frames(frames) => self.frames = frames,
+                Undo::Input(buf) => self.input_buffer = buf,
+                Undo::OutHash(hash) => self.out_hash = hash,
+            }
+        }
+        self.pc = entry.pc;
+        self.instr_pc = entry.pc;
+        self.cc = entry.cc;
+        self.halt = entry.halt;
+        self.watch_hits.clear();
+        true
+    }
+
+    /// Saves the machine state to a file, in the format described in
+    /// the `snapshot` module.
+    pub fn save_state<P: AsRef<Path>> (&self, path: P) -> io::Result<()> {
+        self.snapshot().save(path)
+    }
+
+    /// Restores the machine state from a file written by `save_state`.
+    pub fn load_state<P: AsRef<Path>> (&mut self, path: P) -> io::Result<()> {
+        let snap = Snapshot::load(path)?;
+        self.restore(&snap);
+        Ok(())
+    }
+
+    /// Records every line of input read from now on to a transcript file.
+    pub fn start_recording<P: AsRef<Path>> (&mut self, path: P) -> io::Result<()> {
+        self.recorder = Some(Recorder::create(path)?);
+        Ok(())
+    }
+
+    /// Ends the transcript being recorded, if any, with the current cycle
+    /// and output hash so that replays can be verified.
+    pub fn finish_recording (&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(mut recorder) => recorder.finish(End { cc: self.cc, output_hash: self.out_hash.value() }),
+            None => Ok(()),
+        }
+    }
+
+    /// Feeds a recorded transcript to `in` before reading from the `Io`
+    /// backend. Each line must be read at the cycle it was recorded at,
+    /// and once the recording's end cycle is reached the output so far
+    /// must match its hash; otherwise execution stops with an error.
+    pub fn start_replay (&mut self, replay: Replay) {
+        self.replay = Some(replay);
+    }
+
+    /// The replay in progress, if any.
+    pub fn replay (&self) -> Option<&Replay> {
+        self.replay.as_ref()
+    }
+
+    /// FNV-1a hash of all output written so far.
+    pub fn output_hash (&self) -> u64 {
+        self.out_hash.value()
+    }
+
+    /// Limits `run` to stop once `max` cycles have been executed.
+    pub fn set_max_cycles (&mut self, max: Option<u64>) {
+        self.max_cycles = max;
+    }
+
+    /// Runs `hook` instead of the routine at `addr` whenever a `call`
+    /// targets it, replacing any hook already there. The hook sees the
+    /// machine as the routine would, except that nothing has been pushed
+    /// and `pc` is already the return address, and it runs as part of the
+    /// `call` instruction. Afterwards execution continues at the return
+    /// address, as if the routine had returned.
+    ///
+    /// Only register changes and single pushes or pops made by a hook are
+    /// undone by `reverse_step`.
+    ///
+    /// ```no_run
+    /// use std::collections::HashMap;
+    /// use synacor::Vm;
+    ///
+    /// // A memoized version of the teleporter's confirmation routine
+    /// fn check (m: u16, n: u16, r7: u16, memo: &mut HashMap<(u16, u16), u16>) -> u16 {
+    ///     if let Some(&val) = memo.get(&(m, n)) {
+    ///         return val;
+    ///     }
+    ///     let val = match (m, n) {
+    ///         (0, n) => (n + 1) % 32768,
+    ///         (m, 0) => check(m - 1, r7, r7, memo),
+    ///         (m, n) => {
+    ///             let inner = check(m, n - 1, r7, memo);
+    ///             check(m - 1, inner, r7, memo)
+    ///         },
+    ///     };
+    ///     memo.insert((m, n), val);
+    ///     val
+    /// }
+    ///
+    /// let mut vm = Vm::new();
+    /// vm.add_hook(6027, Box::new(|vm: &mut Vm| {
+    ///     let (m, n, r7) = (vm.registers()[0], vm.registers()[1], vm.registers()[7]);
+    ///     let val = check(m, n, r7, &mut HashMap::new());
+    ///     vm.set_register(0, val).unwrap();
+    /// }));
+    /// ```
+    pub fn add_hook (&mut self, addr: u16, hook: Hook) {
+        self.hooks.insert(addr, hook);
+    }
+
+    /// Removes the hook at `addr`, returning whether there was one.
+    pub fn remove_hook (&mut self, addr: u16) -> bool {
+        self.hooks.remove(&addr).is_some()
+    }
+
+    pub fn has_hook (&self, addr: u16) -> bool {
+        self.hooks.contains_key(&addr)
+    }
+
+    /// Applies a patch set, after checking that memory holds every old
+    /// word it expects. Nothing is changed if the check fails.
+    pub fn apply_patch_set (&mut self, set: PatchSet) -> Result<(), PatchError> {
+        if self.patches.iter().any(|p| p.set.name == set.name) {
+            return Err(PatchError::AlreadyApplied(set.name));
+        }
+        set.verify(&self.mem, false)?;
+        for patch in &set.patches {
+            for (addr, &word) in (patch.addr..).zip(&patch.new) {
+                self.mem[addr as usize] = word;
+                self.invalidate_decoded(addr);
+            }
+        }
+        let old_registers = set.registers.iter()
+            .map(|&(reg, val)| (reg, ::std::mem::replace(&mut self.reg[reg as usize], val)))
+            .collect();
+        self.patches.push(AppliedPatch { set, old_registers });
+        Ok(())
+    }
+
+    /// Reverts the applied patch set `name`, after checking that memory
+    /// still holds the words it wrote. Registers it preset get back the
+    /// values they had when it was applied.
+    pub fn revert_patch_set (&mut self, name: &str) -> Result<(), PatchError> {
+        let i = match self.patches.iter().position(|p| p.set.name == name) {
+            Some(i) => i,
+            None => return Err(PatchError::NotApplied(name.to_string())),
+        };
+        self.patches[i].set.verify(&self.mem, true)?;
+        let applied = self.patches.remove(i);
+        for patch in applied.set.patches.iter().rev() {
+            for (addr, &word) in (patch.addr..).zip(&patch.old) {
+                self.mem[addr as usize] = word;
+                self.invalidate_decoded(addr);
+            }
+        }
+        for &(reg, val) in applied.old_registers.iter().rev() {
+            self.reg[reg as usize] = val;
+        }
+        Ok(())
+    }
+
+    /// Patch sets in force, oldest first. Loading a program or restoring
+    /// a snapshot forgets them.
+    pub fn applied_patches (&self) -> &[AppliedPatch] {
+        &self.patches
+    }
+
+    // Applies every patch set in a file for the PATCH meta-command
+    fn apply_patch_file (&mut self, path: &str) {
+        let sets = match PatchSet::load(path) {
+            Ok(sets) => sets,
+            Err(e) => {
+                self.diag.emit(Level::Error, format_args!("could not read {}: {}", path, e));
+                return;
+            },
+        };
+        for set in sets {
+            let name = set.name.clone();
+            match self.apply_patch_set(set) {
+                Ok(()) => self.diag.emit(Level::Info, format_args!("Applied patch set '{}'", name)),
+                Err(e) => self.diag.emit(Level::Error, format_args!("{}", e)),
+            }
+        }
+    }
+
+    /// Makes `run` stop when the program counter reaches `addr`.
+    pub fn add_breakpoint (&mut self, addr: u16) {
+        self.breakpoints.insert(addr);
+    }
+
+    /// Removes a breakpoint, returning whether it was set.
+    pub fn remove_breakpoint (&mut self, addr: u16) -> bool {
+        self.breakpoints.remove(&addr)
+    }
+
+    /// Whether a breakpoint is set at `addr`.
+    pub fn has_breakpoint (&self, addr: u16) -> bool {
+        self.breakpoints.contains(&addr)
+    }
+
+    /// Makes `run` stop when the cycle count reaches `cc`.
+    pub fn set_break_cycle (&mut self, cc: Option<u64>) {
+        self.break_cc = cc;
+    }
+
+    /// Whether the last `step` stopped at an input prompt because `DEBUG`
+    /// was entered, rather than executing an instruction.
+    pub fn pause_requested (&self) -> bool {
+        self.pause_requested
+    }
+
+    /// Adds a watchpoint on a memory address (0..32767) or register
+    /// (32768..32775).
+    pub fn add_watchpoint (&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Removes all watchpoints on `addr`, returning how many there were.
+    pub fn remove_watchpoints (&mut self, addr: u16) -> usize {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|w| w.addr != addr);
+        before - self.watchpoints.len()
+    }
+
+    pub fn watchpoints (&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Watchpoints fired by the most recent instruction.
+    pub fn watch_hits (&self) -> &[WatchHit] {
+        &self.watch_hits
+    }
+
+    /// Calls in progress, outermost first.
+    pub fn call_stack (&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// The eight registers r0..r7.
+    pub fn registers (&self) -> &[u16] {
+        &self.reg
+    }
+
+    /// Sets register `reg_id` (0..7) to `val`, which must fit in 15 bits.
+    pub fn set_register (&mut self, reg_id: u16, val: u16) -> Result<(), VmError> {
+        if reg_id > MAX_REG_ID {
+            return Err(VmError::InvalidRegister { pc: self.instr_pc, cc: self.cc, reg: reg_id });
+        }
+        if val > MAX_15_BIT_VAL {
+            return Err(VmError::InvalidOperand { pc: self.instr_pc, cc: self.cc, addr: MOD + reg_id, value: val });
+        }
+        self.reg[reg_id as usize] = val;
+        Ok(())
+    }
+
+    /// The full 32768-word memory.
+    pub fn memory (&self) -> &[u16] {
+        &self.mem
+    }
+
+    /// The stack, with the most recently pushed value last.
+    pub fn stack (&self) -> &[u16] {
+        &self.stack
+    }
+
+    /// Sets the value `depth` entries below the top of the stack, 0
+    /// being the top. The value must fit in 15 bits.
+    pub fn set_stack (&mut self, depth: usize, val: u16) -> Result<(), VmError> {
+        if val > MAX_15_BIT_VAL {
+            return Err(VmError::InvalidOperand { pc: self.instr_pc, cc: self.cc, addr: self.instr_pc, value: val });
+        }
+        let len = self.stack.len();
+        if depth >= len {
+            return Err(VmError::StackUnderflow { pc: self.instr_pc, cc: self.cc });
+        }
+        self.stack[len - 1 - depth] = val;
+        Ok(())
+    }
+
+    /// Address of the next instruction to execute.
+    pub fn pc (&self) -> u16 {
+        self.pc
+    }
+
+    /// Moves the program counter to `addr`.
+    pub fn set_pc (&mut self, addr: u16) {
+        self.pc = addr;
+    }
+
+    /// Number of instructions executed so far.
+    pub fn cc (&self) -> u64 {
+        self.cc
+    }
+
+    /// Whether execution has stopped, either on `halt` or a breakpoint.
+    pub fn is_halted (&self) -> bool {
+        self.halt
+    }
+
+    /// Reads memory if `mem_addr` <= 32767, or registers r0..r7
+    /// if 32768 <= `mem_addr` <= 32775.
+    pub fn mem_read (&mut self, mem_addr: u16) -> Result<u16, VmError> {
+        // Requires cast from u16 to usize for indexing into memory
+
+        if mem_addr > MAX_ADDR {
+            return Err(VmError::InvalidAddress { pc: self.instr_pc, cc: self.cc, addr: mem_addr });
+        }
+
+        if mem_addr > MAX_MEM_ADDR {
+            // Read from registers, so take modulus of mem address
+            let reg_id = mem_addr % MOD;
+            self.diag.emit(Level::Trace, format_args!("read r{} = {}", reg_id, self.reg[reg_id as usize]));
+            let val = self.reg[reg_id as usize];
+            self.watch(mem_addr, WatchKind::Read, val, val);
+            return Ok(val);
+        }
+
+        
+        let ret_val = self.mem[mem_addr as usize];
+        if ret_val > MAX_VALID_VAL {
+            return Err(VmError::InvalidOperand {
+                pc: self.instr_pc, cc: self.cc, addr: mem_addr, value: ret_val });
+        }
+        self.watch(mem_addr, WatchKind::Read, ret_val, ret_val);
+
+        Ok(ret_val)
+    }
+
+
+    /// Writes memory if `mem_addr` <= 32767, or registers r0..r7
+    /// if 32768 <= `mem_addr` <= 32775, which take values up to 32767.
+    pub fn mem_write (&mut self, mem_addr: u16, val: u16) -> Result<(), VmError> {
+        // Requires cast from u16 to usize for indexing into memory
+        
+        if mem_addr > MAX_ADDR {
+            return Err(VmError::InvalidAddress { pc: self.instr_pc, cc: self.cc, addr: mem_addr });
+        }
+
+        let old = if mem_addr > MAX_MEM_ADDR {
+            // Write to registers, so take modulus of mem address.
+            // Registers only ever hold 15-bit values.
+            if val > MAX_15_BIT_VAL {
+                return Err(VmError::InvalidOperand { pc: self.instr_pc, cc: self.cc, addr: mem_addr, value: val });
+            }
+            let reg_id = mem_addr % MOD;
+            ::std::mem::replace(&mut self.reg[reg_id as usize], val)
+        } 
+        else {
+            self.invalidate_decoded(mem_addr);
+            ::std::mem::replace(&mut self.mem[mem_addr as usize], val)
+        };
+        self.watch(mem_addr, WatchKind::Write, old, val);
+        Ok(())
+    }
+
+    // Writes all of memory, one word per line, for the DUMP meta-command
+    fn write_memdump (&self, path: &str) -> io::Result<()> {
+        let mut buf = BufWriter::new(File::create(path)?);
+        for word in &self.mem {
+            writeln!(buf, "{}", word)?;
+        }
+        buf.flush()
+    }
+
+    fn mem_dump (&mut self, level: Level, minus: u16, plus: u16) {
+        // Memory around the current instruction
+        let pc = self.instr_pc;
+        let start = pc.saturating_sub(minus);
+        let words = self.mem.iter()
+            .skip(start as usize).take((pc - start + plus + 1) as usize)
+            .collect::<Vec<_>>();
+        self.diag.emit(level, format_args!("memory from {} to {} (pc {}): {:?}",
+            start, start as usize + words.len() - 1, pc, words));
+    }
+
+    fn reg_dump (&mut self, level: Level) {
+        self.diag.emit(level, format_args!("registers at pc {}: {:?}", self.instr_pc, self.reg));
+    }
+
+
+    fn watch (&mut self, addr: u16, access: WatchKind, old: u16, new: u16) {
+        // Records any watchpoints on addr triggered by an access.
+        // Reads only trigger read watchpoints; writes trigger write
+        // watchpoints, and change watchpoints if the value changed
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        let pc = self.instr_pc;
+        let instr = decode(&self.mem, pc).ok();
+        for &w in &self.watchpoints {
+            let fired = w.addr == addr && match w.kind {
+                WatchKind::Read => access == WatchKind::Read,
+                WatchKind::Write => access == WatchKind::Write,
+                WatchKind::Change => access == WatchKind::Write && old != new,
+            };
+            if !fired {
+                continue;
+            }
+            let hit = WatchHit { watchpoint: w, pc, cc: self.cc, instr, old, new };
+            if w.action == WatchAction::Log {
+                self.diag.emit(Level::Info, format_args!("{}", hit));
+            }
+            self.watch_hits.push(hit);
+        }
+    }
+
+    fn value (&mut self, op: Operand) -> u16 {
+        // Resolves an operand to the literal it holds,
+        // or the contents of the register it names
+        match op {
+            Operand::Literal(n) => n,
+            Operand::Register(r) => {
+                let val = self.reg[r as usize];
+                self.watch(op.word(), WatchKind::Read, val, val);
+                val
+            },
+        }
+    }
+
+    fn write_reg (&mut self, dest: Operand, val: u16) -> Result<(), VmError> {
+        // Writes the result of an instruction to its destination
+        // operand, which is always the first and must be a register
+        match dest {
+            Operand::Register(r) => {
+                let old = ::std::mem::replace(&mut self.reg[r as usize], val);
+                self.watch(dest.word(), WatchKind::Write, old, val);
+                Ok(())
+            },
+            Operand::Literal(n) => Err(VmError::InvalidOperand {
+                pc: self.instr_pc, cc: self.cc, addr: self.instr_pc + 1, value: n }),
+        }
+    }
+
+
+    fn get_instr (&mut self) -> Result<(), VmError> {
+    // This function decodes the next instruction from memory and
+    // executes it. The program counter is moved past the instruction
+    // first, so jumps only have to overwrite it.
+
+        let pc = self.pc;
+        self.instr_pc = pc;
+
+        let cached = if self.decode_cache { self.decoded.get(pc as usize).cloned().flatten() } else { None };
+        let decoded = match cached {
+            Some(instr) => Ok(instr),
+            None => decode(&self.mem, pc),
+        };
+        let instr = match decoded {
+            Ok(instr) => {
+                if self.decode_cache && cached.is_none() {
+                    self.decoded[pc as usize] = Some(instr);
+                }
+                instr
+            },
+            Err(DecodeError::InvalidOpcode { opcode, .. }) => {
+                self.halt = true;
+                self.mem_dump(Level::Debug, 5, 10);
+                self.reg_dump(Level::Debug);
+                return Err(VmError::InvalidOpcode { pc, cc: self.cc, opcode });
+            },
+            Err(DecodeError::InvalidOperand { addr, value }) =>
+                return Err(VmError::InvalidOperand { pc, cc: self.cc, addr, value }),
+            Err(DecodeError::Truncated { addr }) =>
+                return Err(VmError::InvalidAddress { pc, cc: self.cc, addr }),
+        };
+        self.pc = pc + instr.size();
+
+        match instr {
+            Instruction::Halt => {
+                self.halt = true;
+                self.diag.emit(Level::Debug, format_args!("halt at pc {}, cycle {}", pc, self.cc));
+            },
+            Instruction::Set(a, b) => self.set(a, b)?,
+            Instruction::Push(a) => self.push(a),
+            Instruction::Pop(a) => self.pop(a)?,
+            Instruction::Eq(a, b, c) => self.eq(a, b, c)?,
+            Instruction::Gt(a, b, c) => self.gt(a, b, c)?,
+            Instruction::Jmp(a) => self.jmp(a),
+            Instruction::Jt(a, b) => self.jt(a, b),
+            Instruction::Jf(a, b) => self.jf(a, b),
+            Instruction::Add(a, b, c) => self.add(a, b, c)?,
+            Instruction::Mult(a, b, c) => self.mult(a, b, c)?,
+            Instruction::Mod(a, b, c) => self.modulo(a, b, c)?,
+            Instruction::And(a, b, c) => self.and(a, b, c)?,
+            Instruction::Or(a, b, c) => self.or(a, b, c)?,
+            Instruction::Not(a, b) => self.not(a, b)?,
+            Instruction::Rmem(a, b) => self.rmem(a, b)?,
+            Instruction::Wmem(a, b) => self.wmem(a, b)?,
+            Instruction::Call(a) => self.call(a),
+            Instruction::Ret => self.ret(),
+            Instruction::Out(a) => self.out(a)?,
+            Instruction::In(a) => self.in_stdin(a)?,
+            Instruction::Noop => {},
+        }
+        Ok(())
+    }
+
+    /* opcodes */
+
+    fn set (&mut self, a: Operand, b: Operand) -> Result<(), VmError> {
+        // set register <a> to the value of <b>
+        // SET a b
+
+        let value = self.value(b);
+        self.write_reg(a, value)?;
+
+        Ok(())
+    }
+
+
+    fn push (&mut self, a: Operand) {
+        // push <a> onto the stack
+        // PUSH a
+
+        let val = self.value(a);
+        self.stack.push(val);
+    }
+
+
+    fn pop (&mut self, a: Operand) -> Result<(), VmError> {
+        // remove the top element from the stack and write it into <a>;
+        // empty stack = error
+        // POP a
+
+        if let Some(val) = self.stack.pop() {
+            self.write_reg(a, val)?;
+            Ok(())
+        }
+        else {
+            Err(VmError::StackUnderflow { pc: self.instr_pc, cc: self.cc })
+        }
+    }
+
+
+    fn eq (&mut self, a: Operand, b: Operand, c: Operand) -> Result<(), VmError> {
+        // set <a> to 1 if <b> is equal to <c>; set it to 0 otherwise
+        // EQ a b c
+
+        let val_1 = self.value(b);
+        let val_2 = self.value(c);
+        self.write_reg(a, (val_1 == val_2) as u16)?;
+
+        Ok(())
+    }
+
+
+    fn gt (&mut self, a: Operand, b: Operand, c: Operand) -> Result<(), VmError> {
+        // set <a> to 1 if <b> is greater than <c>; set it to 0 otherwise
+        // GT a b c
+
+        let val_1 = self.value(b);
+        let val_2 = self.value(c);
+        self.write_reg(a, (val_1 > val_2) as u16)?;
+
+        Ok(())
+    }
+
+
+    fn jmp (&mut self, a: Operand) {
+        // jump to <a>
+        // JMP a
+
+        let addr = self.value(a);
+        self.pc = addr;
+    }
+
+
+    fn jt (&mut self, a: Operand, b: Operand) {
+        // if <a> is nonzero, jump to <b>
+        // JT a b
+
+        let val_branch_if_nz = self.value(a);
+        let branch_addr = self.value(b);
+        if val_branch_if_nz != 0 {
+            self.pc = branch_addr;
+        }
+    }
+
+
+    fn jf (&mut self, a: Operand, b: Operand) {
+        // if <a> is zero, jump to <b>
+        // JF a b
+
+        let val_branch_if_z = self.value(a);
+        let branch_addr = self.value(b);
+        if val_branch_if_z == 0 {
+            self.pc = branch_addr;
+        }
+    }
+
+
+    fn add (&mut self, a: Operand, b: Operand, c: Operand) -> Result<(), VmError> {
+        // assign into <a> the sum of <b> and <c> (modulo 32768)
+        // ADD a b c
+
+        let val_1 = self.value(b);
+        let val_2 = self.value(c);
+        self.write_reg(a, ((u32::from(val_1) + u32::from(val_2)) % u32::from(MOD)) as u16)?;
+
+        Ok(())
+    }
+
+
+    fn mult (&mut self, a: Operand, b: Operand, c: Operand) -> Result<(), VmError> {
+        // store into <a> the product of <b> and <c> (modulo 32768)
+        // MULT a b c
+
+        let val_1 = self.value(b);
+        let val_2 = self.value(c);
+        self.write_reg(a, ((u32::from(val_1) * u32::from(val_2)) % u32::from(MOD)) as u16)?;
+
+        Ok(())
+    }
+
+
+    fn modulo (&mut self, a: Operand, b: Operand, c: Operand) -> Result<(), VmError> {
+        // store into <a> the remainder of <b> divided by <c>
+        // MOD a b c
+
+        let val_1 = self.value(b);
+        let val_2 = self.value(c);
+        if val_2 == 0 {
+            return Err(VmError::InvalidOperand {
+                pc: self.instr_pc, cc: self.cc, addr: self.instr_pc + 3, value: c.word() });
+        }
+        self.write_reg(a, val_1 % val_2)?;
+
+        Ok(())
+    }
+
+
+    fn and (&mut self, a: Operand, b: Operand, c: Operand) -> Result<(), VmError> {
+        // stores into <a> the bitwise and of <b> and <c>
+        // AND a b c
+
+        let val_1 = self.value(b);
+        let val_2 = self.value(c);
+        self.write_reg(a, val_1 & val_2)?;
+
+        Ok(())
+    }
+
+
+    fn or (&mut self, a: Operand, b: Operand, c: Operand) -> Result<(), VmError> {
+        // stores into <a> the bitwise or of <b> and <c>
+        // OR a b c
+
+        let val_1 = self.value(b);
+        let val_2 = self.value(c);
+        self.write_reg(a, val_1 | val_2)?;
+
+        Ok(())
+    }
+
+
+    fn not (&mut self, a: Operand, b: Operand) -> Result<(), VmError> {
+        // stores 15-bit bitwise inverse of <b> in <a>
+        // NOT a b
+
+        let val = self.value(b);
+
+        // Do bitwise not, and mask off top bit if it got set
+        self.write_reg(a, (!val) & 0x7FFF)?;
+
+        Ok(())
+    }
+
+
+    fn rmem (&mut self, a: Operand, b: Operand) -> Result<(), VmError> {
+        // read memory at address <b> and write it to <a>
+        // RMEM a b
+
+        let src_addr = self.value(b);
+        let val = self.mem_read(src_addr)?;
+        if val > MAX_15_BIT_VAL {
+            // Memory contained a register's address.
+            // Probably not valid.
+            return Err(VmError::InvalidOperand { pc: self.instr_pc, cc: self.cc, addr: src_addr, value: val });
+        }
+        self.write_reg(a, val)?;
+
+        Ok(())
+    }
+
+
+    fn wmem (&mut self, a: Operand, b: Operand) -> Result<(), VmError> {
+        // write the value from <b> into memory at address <a>
+        // WMEM a b
+
+        let dest_addr = self.value(a);
+        let val = self.value(b);
+        self.mem_write(dest_addr, val)?;
+
+        Ok(())
+    }
+
+
+    fn call (&mut self, a: Operand) {
+        // write the address of the next instruction to the stack and jump to <a>
+        // CALL a
+
+        let jump_to_addr = self.value(a);
+
+        // A hooked routine runs natively and returns straight away. The
+        // hook is taken out while it runs so that it can borrow the VM.
+        if let Some(mut hook) = self.hooks.remove(&jump_to_addr) {
+            hook(self);
+            self.hooks.entry(jump_to_addr).or_insert(hook);
+            return;
+        }
+
+        self.stack.push(self.pc);
+        self.frames.push(Frame {
+            call_pc: self.instr_pc,
+            target: jump_to_addr,
+            return_addr: self.pc,
+            stack_depth: self.stack.len(),
+        });
+        self.pc = jump_to_addr;
+    }
+
+
+    fn ret (&mut self) {
+        // remove the top element from the stack and jump to it; empty stack = halt
+        // RET
+
+        if let Some(ret_addr) = self.stack.pop() {
+            self.pc = ret_addr;
+
+            // Drop frames whose return address is no longer on the stack.
+            // Usually that is just the innermost one.
+            let depth = self.stack.len();
+            while self.frames.last().is_some_and(|f| f.stack_depth > depth) {
+                self.frames.pop();
+            }
+        }
+        else {
+            self.halt = true;
+            self.diag.emit(Level::Debug, format_args!("ret with an empty stack at pc {}, halting", self.instr_pc));
+        }
+    }
+
+
+    fn out (&mut self, a: Operand) -> Result<(), VmError> {
+        // write the character represented by ascii code <a> to the terminal
+        // OUT a
+
+        let val = self.value(a);
+
+        if val > 255 {
+            self.halt = true;
+            self.mem_dump(Level::Debug, 5, 10);
+            self.reg_dump(Level::Debug);
+            return Err(VmError::InvalidAscii { pc: self.instr_pc, cc: self.cc, value: val });
+        }
+        if let Err(e) = self.io.write_char((val as u8) as char) {
+            return Err(VmError::Io { pc: self.instr_pc, cc: self.cc, msg: e.to_string() });
+        }
+        self.out_hash.update((val as u8) as char);
+
+        Ok(())
+    }
+
+    fn read_input_line (&mut self) -> Result<bool, VmError> {
+        // Reads the next line of input into the input buffer, from the
+        // replay if there is one. Running out of input is an error, since
+        // the program would otherwise wait forever on a character.
+        // Returns false if a replay stopped before this instruction.
+        let start = self.input_buffer.len();
+        let input = match self.replay {
+            Some(ref mut replay) => replay.next_input(),
+            None => ReplayInput::Done,
+        };
+        match input {
+            ReplayInput::Line(entry) => {
+                if entry.cc != self.cc {
+                    let line = self.replay.as_ref().map_or(0, |r| r.position());
+                    return Err(VmError::ReplayDiverged {
+                        pc: self.instr_pc, cc: self.cc, line, expected: entry.cc });
+                }
+                self.input_buffer.push_str(&entry.line);
+                self.input_buffer.push('\n');
+            },
+            ReplayInput::Stop => {
+                self.replay = None;
+                self.replay_stopped = true;
+                self.incomplete = true;
+                self.pc = self.instr_pc;
+                return Ok(false);
+            },
+            ReplayInput::Done => {
+                self.end_replay()?;
+                match self.io.read_line(&mut self.input_buffer) {
+                    Ok(0) => return Err(VmError::InputExhausted { pc: self.instr_pc, cc: self.cc }),
+                    Ok(_) => (),
+                    Err(e) => return Err(VmError::Io { pc: self.instr_pc, cc: self.cc, msg: e.to_string() }),
+                }
+            },
+        }
+
+        // DEBUG is left out of transcripts, since replays shouldn't stop
+        let line = self.input_buffer[start..].trim_end_matches('\n');
+        if let Some(ref mut recorder) = self.recorder {
+            if line != "DEBUG" {
+                if let Err(e) = recorder.record(self.cc, line) {
+                    return Err(VmError::Io { pc: self.instr_pc, cc: self.cc, msg: e.to_string() });
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn end_replay (&mut self) -> Result<(), VmError> {
+        // Hands input back to the Io backend, first checking that the
+        // replay reproduced the recording if it has an end record
+        let replay = match self.replay.take() {
+            Some(replay) => replay,
+            None => return Ok(()),
+        };
+        if let Some(end) = replay.transcript().end {
+            if end.cc != self.cc {
+                return Err(VmError::ReplayDiverged {
+                    pc: self.instr_pc, cc: self.cc, line: replay.position() + 1, expected: end.cc });
+            }
+            if end.output_hash != self.out_hash.value() {
+                return Err(VmError::OutputMismatch {
+                    pc: self.instr_pc, cc: self.cc, expected: end.output_hash, actual: self.out_hash.value() });
+            }
+            self.diag.emit(Level::Info, format_args!("Replay of {} lines verified at cycle {}", replay.position(), self.cc));
+        }
+        Ok(())
+    }
+
+    fn in_stdin (&mut self, a: Operand) -> Result<(), VmError> {
+        // read character from input and write ascii code to <a>
+        // IN a
+
+        // Check if there are still characters in buffer to be read.
+        // If not, then read a line of input to fill buffer
+        if self.input_buffer.is_empty() && !self.read_input_line()? {
+            return Ok(());
+        }
+
+        if self.input_buffer == "DEBUG\n" {
+            // Stop before this instruction so it asks for input
+            // again once execution resumes
+            self.input_buffer.clear();
+            self.pause_requested = true;
+            self.incomplete = true;
+            self.pc = self.instr_pc;
+            return Ok(());
+        }
+        else if let Some(name) = meta_arg(&self.input_buffer, "SAVE") {
+            // Save as if stopped before this instruction, so loading
+            // the slot asks for input again
+            self.input_buffer.clear();
+            let mut snap = self.snapshot();
+            snap.pc = self.instr_pc;
+            match self.slot_path(&name) {
+                Some(path) => match snap.save(&path) {
+                    Ok(()) => self.diag.emit(Level::Info, format_args!("Saved state to {}", path.display())),
+                    Err(e) => self.diag.emit(Level::Error, format_args!("could not save {}: {}", path.display(), e)),
+                },
+                None => self.diag.emit(Level::Warn, format_args!("invalid slot name '{}'", name)),
+            }
+            if !self.read_input_line()? {
+                return Ok(());
+            }
+        }
+        else if let Some(name) = meta_arg(&self.input_buffer, "LOAD") {
+            self.input_buffer.clear();
+            match self.slot_path(&name) {
+                Some(path) => match Snapshot::load(&path) {
+                    Ok(snap) => {
+                        // The loaded state continues at its own in
+                        // instruction, abandoning this one
+                        self.diag.emit(Level::Info, format_args!("Loaded state from {}", path.display()));
+                        self.restore(&snap);
+                        self.incomplete = true;
+                        return Ok(());
+                    },
+                    Err(e) => self.diag.emit(Level::Error, format_args!("could not load {}: {}", path.display(), e)),
+                },
+                None => self.diag.emit(Level::Warn, format_args!("invalid slot name '{}'", name)),
+            }
+            if !self.read_input_line()? {
+                return Ok(());
+            }
+        }
+        else if let Some(path) = meta_arg(&self.input_buffer, "PATCH") {
+            self.input_buffer.clear();
+            self.apply_patch_file(&path);
+            if !self.read_input_line()? {
+                return Ok(());
+            }
+        }
+        else if let Some(name) = meta_arg(&self.input_buffer, "UNPATCH") {
+            self.input_buffer.clear();
+            match self.revert_patch_set(&name) {
+                Ok(()) => self.diag.emit(Level::Info, format_args!("Reverted patch set '{}'", name)),
+                Err(e) => self.diag.emit(Level::Error, format_args!("{}", e)),
+            }
+            if !self.read_input_line()? {
+                return Ok(());
+            }
+        }
+        else if self.input_buffer == "DUMP\n" {
+            self.reg_dump(Level::Info);
+            self.mem_dump(Level::Info, 0, 10);
+            if let Err(e) = self.write_memdump(MEMDUMP_PATH) {
+                self.diag.emit(Level::Error, format_args!("could not write {}: {}", MEMDUMP_PATH, e));
+            }
+            self.input_buffer.clear();
+            if !self.read_input_line()? {
+                return Ok(());
+            }
+        }
+        else if self.input_buffer == "LOG_START\n" {
+            self.diag.emit(Level::Info, format_args!("Enabling instruction logging"));
+            if let Err(e) = self.start_logging() {
+                self.diag.emit(Level::Error, format_args!("could not open {}: {}", self.tracer.path().display(), e));
+            }
+            self.input_buffer.clear();
+            if !self.read_input_line()? {
+                return Ok(());
+            }
+        }
+        else if let Some(spec) = meta_arg(&self.input_buffer, "LOG_FILTER")
+            .or_else(|| meta_arg(&self.input_buffer, "LOG_START")) {
+            // Set the filter, then start logging if asked to
+            let start = self.input_buffer.starts_with("LOG_START");
+            match TraceFilter::parse(&spec) {
+                Ok(filter) => {
+                    self.diag.emit(Level::Info, format_args!("Trace filter: {}", filter));
+                    self.set_log_filter(filter);
+                    if start {
+                        self.diag.emit(Level::Info, format_args!("Enabling instruction logging"));
+                        if let Err(e) = self.start_logging() {
+                            self.diag.emit(Level::Error, format_args!("could not open {}: {}", self.tracer.path().display(), e));
+                        }
+                    }
+                },
+                Err(e) => self.diag.emit(Level::Warn, format_args!("invalid trace filter: {}", e)),
+            }
+            self.input_buffer.clear();
+            if !self.read_input_line()? {
+                return Ok(());
+            }
+        }
+        else if self.input_buffer == "LOG_END\n" {
+            self.diag.emit(Level::Info, format_args!("Disabling instruction logging"));
+            if let Err(e) = self.stop_logging() {
+                self.diag.emit(Level::Error, format_args!("could not write {}: {}", self.tracer.path().display(), e));
+            }
+            self.input_buffer.clear();
+            if !self.read_input_line()? {
+                return Ok(());
+            }
+        }
+        else if self.input_buffer == "FIX\n" {
+            self.diag.emit(Level::Info, format_args!("Setting r7 to 5"));
+            self.reg[7] = 5;
+            self.input_buffer.clear();
+            if !self.read_input_line()? {
+                return Ok(());
+            }
+        }
+
+        // Read first character in buffer and write to <a>, then remove
+        let ch = self.input_buffer.remove(0);
+
+        if ch as u32 > 127 {
+            return Err(VmError::InvalidAscii { pc: self.instr_pc, cc: self.cc, value: ch as u16 });
+        }
+
+        self.write_reg(a, ch as u16)?;
+        Ok(())
+    }
+
+    fn slot_path (&self, name: &str) -> Option<PathBuf> {
+        // Slot names become file names, so keep them to a safe set
+        let valid = !name.is_empty() &&
+            name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if valid {
+            Some(self.save_dir.join(format!("{}.snap", name)))
+        }
+        else {
+            None
+        }
+    }
+
+    fn trace_start (&self) -> Option<TraceStart> {
+        // Captures what the instruction at pc may change, so that
+        // trace() can report the differences once it has run
+        let instr = decode(&self.mem, self.pc).ok()?;
+        if !self.tracer.filter().accepts_instr(self.cc, self.pc, instr.opcode()) {
+            return None;
+        }
+        let values = instr.operands().iter().map(|&op| match op {
+            Operand::Literal(n) => n,
+            Operand::Register(r) => self.reg[r as usize],
+        }).collect::<Vec<_>>();
+        let mem = match instr {
+            Instruction::Wmem(..) if values[0] <= MAX_MEM_ADDR =>
+                Some((values[0], self.mem[values[0] as usize])),
+            _ => None,
+        };
+        Some(TraceStart {
+            pc: self.pc,
+            instr,
+            values,
+            reg: self.reg.clone(),
+            stack_len: self.stack.len(),
+            stack_top: self.stack.last().cloned(),
+            mem,
+        })
+    }
+
+    fn trace (&mut self, start: TraceStart) -> Result<(), VmError> {
+        // Writes the trace record for the instruction just executed
+        let mut deltas = vec![];
+        if self.stack.len() == start.stack_len + 1 {
+            deltas.extend(self.stack.last().map(|&val| Delta::Push(val)));
+        }
+        else if self.stack.len() + 1 == start.stack_len {
+            deltas.extend(start.stack_top.map(Delta::Pop));
+        }
+        for (i, (&old, &new)) in start.reg.iter().zip(&self.reg).enumerate() {
+            if old != new {
+                deltas.push(Delta::Reg { reg: i as u8, old, new });
+            }
+        }
+        if let Some((addr, old)) = start.mem {
+            deltas.push(Delta::Mem { addr, old, new: self.mem[addr as usize] });
+        }
+
+        let record = TraceRecord {
+            cc: self.cc,
+            pc: start.pc,
+            instr: start.instr,
+            values: start.values,
+            deltas,
+            next_pc: self.pc,
+        };
+        self.tracer.write(&record)
+            .map_err(|e| VmError::Io { pc: record.pc, cc: record.cc, msg: e.to_string() })
+    }
+
+    fn undo_start (&self) -> UndoStart {
+        // Captures what the instruction at pc may change. Only wmem
+        // writes memory and only in writes from the input buffer.
+        let word = |offset: u16| self.mem.get((self.pc + offset) as usize).cloned().unwrap_or(0);
+        let op = Opcode::from_u16(word(0));
+        let mem = match op {
+            Some(Opcode::Wmem) => {
+                let addr = match Operand::from_word(word(1)) {
+                    Some(Operand::Literal(n)) => n,
+                    Some(Operand::Register(r)) => self.reg[r as usize],
+                    None => MOD,
+                };
+                if addr <= MAX_MEM_ADDR { Some((addr, self.mem[addr as usize])) } else { None }
+            },
+            _ => None,
+        };
+        let input_reg = match (op, Operand::from_word(word(1))) {
+            (Some(Opcode::In), Some(Operand::Register(r))) => Some(r),
+            _ => None,
+        };
+        let mut reg = [0; 8];
+        reg.copy_from_slice(&self.reg);
+        UndoStart {
+            pc: self.pc,
+            cc: self.cc,
+            halt: self.halt,
+            reg,
+            stack_len: self.stack.len(),
+            stack_top: self.stack.last().cloned(),
+            frames_len: self.frames.len(),
+            frames: if op == Some(Opcode::Ret) { Some(self.frames.clone()) } else { None },
+            mem,
+            input_reg,
+            out_hash: self.out_hash,
+        }
+    }
+
+    fn record_undo (&mut self, start: UndoStart) {
+        // Stores how to reverse the instruction just executed
+        let mut changes = vec![];
+        if self.stack.len() == start.stack_len + 1 {
+            changes.push(Undo::Pop);
+        }
+        else if self.stack.len() + 1 == start.stack_len {
+            changes.extend(start.stack_top.map(Undo::Push));
+        }
+        if self.frames.len() == start.frames_len + 1 {
+            changes.push(Undo::PopFrame);
+        }
+        else if let Some(frames) = start.frames {
+            if frames != self.frames {
+                changes.push(Undo::Frames(frames));
+            }
+        }
+        for (i, (&old, &new)) in start.reg.iter().zip(&self.reg).enumerate() {
+            if old != new {
+                changes.push(Undo::Reg(i as u8, old));
+            }
+        }
+        if let Some((addr, old)) = start.mem {
+            changes.push(Undo::Mem(addr, old));
+        }
+        if let Some(r) = start.input_reg {
+            // Put the character read back in front of what is left
+            let ch = (self.reg[r as usize] as u8) as char;
+            changes.push(Undo::Input(format!("{}{}", ch, self.input_buffer)));
+        }
+        if start.out_hash != self.out_hash {
+            changes.push(Undo::OutHash(start.out_hash));
+        }
+        self.history.push(UndoEntry { pc: start.pc, cc: start.cc, halt: start.halt, changes });
+    }
+
+    /// Executes a single instruction and advances the cycle counter.
+    pub fn step (&mut self) -> Result<(), VmError> {
+        self.pause_requested = false;
+        self.incomplete = false;
+        self.replay_stopped = false;
+        self.watch_hits.clear();
+        let trace_start = if self.tracer.is_enabled() { self.trace_start() } else { None };
+        let undo_start = if self.history.depth() > 0 { Some(self.undo_start()) } else { None };
+        if let Err(e) = self.get_instr() {
+            // Rewind to the start of the faulting instruction
+            self.pc = self.instr_pc;
+            return Err(e);
+        }
+        // The instruction has run, so its cycle and undo entry are
+        // recorded even if writing its trace fails
+        let traced = match trace_start {
+            Some(start) if !self.incomplete => self.trace(start),
+            _ => Ok(()),
+        };
+        if let Some(start) = undo_start {
+            if !self.incomplete {
+                self.record_undo(start);
+            }
+        }
+        if !self.incomplete {
+            self.cc += 1;
+        }
+        self.instr_pc = self.pc;
+        traced?;
+
+        // Verify a finished replay once it reaches the recording's end
+        let at_end = self.replay.as_ref().is_some_and(|r| {
+            r.is_finished() && r.transcript().end.is_some_and(|end| end.cc == self.cc)
+        });
+        if at_end {
+            self.end_replay()?;
+        }
+        Ok(())
+    }
+
+    /// Runs until the program halts, a breakpoint is hit or the cycle
+    /// limit is reached. On error the VM is left at the faulting
+    /// instruction so it can be inspected.
+    pub fn run (&mut self) -> Result<StopReason, VmError> {
+        self.run_until(|_| false)
+    }
+
+    /// Runs as `run` does, also stopping with `StopReason::Reached` as
+    /// soon as `stop` returns true. `stop` is checked after each
+    /// instruction. The first instruction is always executed, so a VM
+    /// sitting on a breakpoint moves off it.
+    pub fn run_until<F: FnMut(&Vm) -> bool> (&mut self, mut stop: F) -> Result<StopReason, VmError> {
+        while !self.halt {
+            if let Some(max) = self.max_cycles {
+                if self.cc >= max {
+                    return Ok(StopReason::CycleLimit);
+                }
+            }
+
+            self.step()?;
+
+            if self.pause_requested {
+                return Ok(StopReason::Paused);
+            }
+            if self.replay_stopped {
+                return Ok(StopReason::ReplayEnd);
+            }
+            if self.watch_hits.iter().any(|hit| hit.watchpoint.action == WatchAction::Break) {
+                return Ok(StopReason::Watchpoint);
+            }
+            if self.halt {
+                break;
+            }
+            if Some(self.cc) == self.break_cc ||
+                (!self.breakpoints.is_empty() && self.breakpoints.contains(&self.pc)) {
+                return Ok(StopReason::Breakpoint);
+            }
+            if stop(self) {
+                return Ok(StopReason::Reached);
+            }
+        }
+        Ok(StopReason::Halted)
+    }
+}
+
+// Returns the argument of a meta-command line such as "SAVE start"
+fn meta_arg (line: &str, cmd: &str) -> Option<String> {
+    line.trim_end()
+        .strip_prefix(cmd)
+        .and_then(|rest| rest.strip_prefix(' '))
+        .map(|name| name.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+    use asm::assemble;
+    use patch::PatchSet;
+
+    #[test]
+    fn registers_and_stack_hold_15_bit_values () {
+        let mut vm = Vm::new();
+        vm.load_mem(&[2, 5, 0]).unwrap();
+        vm.step().unwrap();
+        assert!(vm.set_register(0, 32768).is_err());
+        assert!(vm.mem_write(32768, 40000).is_err());
+        assert!(vm.set_stack(0, 0xffff).is_err());
+        assert!(vm.set_stack(1, 1).is_err());
+        vm.set_stack(0, 32767).unwrap();
+        assert_eq!(vm.stack(), [32767]);
+        // Memory itself may hold register references
+        vm.mem_write(10, 32770).unwrap();
+    }
+
+    // Touches registers, memory and the stack, and makes a call
+    const UNDO_PROGRAM: &str = "\
+        set r0, 5
+        push r0
+        wmem 100, r0
+        call sub
+        pop r1
+        halt
+sub:    add r0, r0, 1
+        ret
+";
+
+    #[test]
+    fn reverse_step_restores_each_state () {
+        let mut vm = Vm::new();
+        vm.load_mem(&assemble(UNDO_PROGRAM).unwrap()).unwrap();
+        vm.set_history_depth(100);
+        let mut states = vec![];
+        while !vm.is_halted() {
+            states.push(vm.snapshot());
+            vm.step().unwrap();
+        }
+        assert_eq!(states.len(), 8);
+        assert_eq!((vm.registers()[0], vm.registers()[1], vm.memory()[100]), (6, 5, 5));
+
+        for state in states.iter().rev() {
+            assert!(vm.reverse_step());
+            assert_eq!(vm.snapshot(), *state);
+        }
+        assert!(!vm.reverse_step());
+    }
+
+    #[test]
+    fn history_keeps_only_the_last_instructions () {
+        let mut vm = Vm::new();
+        vm.load_mem(&assemble(UNDO_PROGRAM).unwrap()).unwrap();
+        vm.set_history_depth(3);
+        vm.run().unwrap();
+        assert_eq!(vm.history_len(), 3);
+        // Back over halt, pop and ret, leaving the machine inside sub
+        for _ in 0..3 {
+            assert!(vm.reverse_step());
+        }
+        assert!(!vm.reverse_step());
+        assert_eq!(vm.pc(), 17);
+        assert_eq!(vm.stack(), [5, 10]);
+        assert_eq!(vm.registers()[0], 6);
+    }
+
+    // Rewrites the routine at 20 between calls: first the constant it
+    // adds, then the add itself into a mult
+    const SELF_MODIFYING: &str = "\
+        call inc
+        wmem 23, 10
+        call inc
+        wmem 20, 10
+        call inc
+        halt
+        .data 0, 0, 0, 0, 0, 0, 0
+inc:    add r0, r0, 1
+        ret
+";
+
+    fn run_to_halt (vm: &mut Vm) -> u16 {
+        assert_eq!(vm.run_until(|vm| vm.pc() == 12).unwrap(), StopReason::Reached);
+        vm.registers()[0]
+    }
+
+    #[test]
+    fn code_changes_reach_the_decode_cache () {
+        for &cache in &[true, false] {
+            let mut vm = Vm::new();
+            vm.set_decode_cache(cache);
+            vm.load_mem(&assemble(SELF_MODIFYING).unwrap()).unwrap();
+            let start = vm.snapshot();
+            assert_eq!(run_to_halt(&mut vm), 110);
+
+            // Restoring puts back the original routine
+            vm.restore(&start);
+            assert_eq!(run_to_halt(&mut vm), 110);
+
+            // A poke from outside the program
+            vm.mem_write(23, 3).unwrap();
+            vm.set_pc(10);
+            assert_eq!(run_to_halt(&mut vm), 330);
+
+            // And a patch turning the mult back into an add
+            vm.apply_patch_set(PatchSet::parse("20: 10 -> 9", "add").unwrap().remove(0)).unwrap();
+            vm.set_pc(10);
+            assert_eq!(run_to_halt(&mut vm), 333);
+        }
+    }
+
+    // A routine at 3 that adds 1 to r0 and writes it to 100
+    const HOOKED: &str = "\
+        call inc
+        halt
+inc:    add r0, r0, 1
+        wmem 100, r0
+        ret
+";
+
+    fn hooked_vm () -> Vm {
+        let mut vm = Vm::new();
+        vm.load_mem(&assemble(HOOKED).unwrap()).unwrap();
+        vm
+    }
+
+    #[test]
+    fn hook_replaces_call () {
+        let mut vm = hooked_vm();
+        vm.add_hook(3, Box::new(|vm: &mut Vm| {
+            // Nothing pushed, and pc is already the return address
+            assert_eq!((vm.pc(), vm.stack().len()), (2, 0));
+            vm.set_register(0, 42).unwrap();
+        }));
+        vm.step().unwrap();
+        assert_eq!((vm.pc(), vm.registers()[0]), (2, 42));
+        assert!(vm.stack().is_empty() && vm.call_stack().is_empty());
+        vm.run().unwrap();
+        assert_eq!(vm.memory()[100], 0);
+    }
+
+    #[test]
+    fn hook_falls_through_to_return_address () {
+        let mut vm = hooked_vm();
+        let calls = Rc::new(Cell::new(0));
+        let count = calls.clone();
+        vm.add_hook(3, Box::new(move |_: &mut Vm| count.set(count.get() + 1)));
+        let before = vm.snapshot();
+        vm.step().unwrap();
+        assert_eq!(calls.get(), 1);
+
+        // Only pc and the cycle count move on; the routine never runs
+        let after = vm.snapshot();
+        assert_eq!((after.pc(), after.cc()), (2, 1));
+        assert_eq!((after.reg, after.stack, after.mem), (before.reg, before.stack, before.mem));
+
+        // Without the hook the guest routine runs again
+        assert!(vm.remove_hook(3));
+        assert!(!vm.has_hook(3));
+        vm.set_pc(0);
+        vm.run().unwrap();
+        assert_eq!((vm.registers()[0], vm.memory()[100]), (1, 1));
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn hook_can_reenter_its_routine () {
+        let mut vm = hooked_vm();
+        let calls = Rc::new(Cell::new(0));
+        let count = calls.clone();
+        vm.add_hook(3, Box::new(move |vm: &mut Vm| {
+            count.set(count.get() + 1);
+            // Taken out while it runs, so the call at 0 reaches the guest
+            // routine
+            assert!(!vm.has_hook(3));
+            let ret = vm.pc();
+            vm.set_pc(0);
+            vm.run_until(|vm| vm.pc() == 2).unwrap();
+            vm.set_register(0, vm.registers()[0] * 10).unwrap();
+            vm.set_pc(ret);
+        }));
+        vm.run_until(|vm| vm.pc() == 2).unwrap();
+        assert_eq!((calls.get(), vm.registers()[0], vm.memory()[100]), (1, 10, 1));
+
+        // And put back afterwards
+        assert!(vm.has_hook(3));
+        vm.set_pc(0);
+        vm.run_until(|vm| vm.pc() == 2).unwrap();
+        assert_eq!((calls.get(), vm.registers()[0], vm.memory()[100]), (2, 110, 11));
+    }
+}
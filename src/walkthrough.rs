@@ -0,0 +1,365 @@
+//! Scripted walkthroughs: commands to type into the game, interleaved
+//! with expectations on what it prints in reply.
+//!
+//! ```text
+//! # The start of the game
+//! room Foothills
+//! > take tablet
+//! expect Taken.
+//! inventory tablet
+//! > doorway
+//! match ^There (is|are) \d+ exits?:$
+//! ```
+//!
+//! Lines starting with `>` are commands. The expectations after a
+//! command apply to the output it produced, and those before the first
+//! command to the introduction:
+//!
+//! - `expect TEXT`: the output contains TEXT
+//! - `match REGEX`: the output matches REGEX, where `^` and `$` match at
+//!   line boundaries
+//! - `room TITLE`: the last room heading, `== TITLE ==`, names TITLE
+//! - `inventory ITEM`: `inv` lists ITEM. The runner sends `inv` itself,
+//!   and its reply doesn't replace the command's output
+//!
+//! Blank lines and lines starting with `#` are ignored.
+
+use std::error::Error;
+use std::fmt;
+
+use regex::{Regex, RegexBuilder};
+
+use error::VmError;
+use io::BufferIo;
+use vm::{StopReason, Vm};
+
+// Commands and replies shown before a failure
+const CONTEXT_EXCHANGES: usize = 3;
+
+/// One line of a walkthrough.
+#[derive(Debug, Clone)]
+pub enum Step {
+    /// A line of input for the program.
+    Command(String),
+    /// The output contains this text.
+    Contains(String),
+    /// The output matches this regular expression.
+    Matches(Regex),
+    /// The last room heading in the output has this title.
+    Room(String),
+    /// `inv` lists this item.
+    Inventory(String),
+}
+
+/// Writes the step as it appears in a script.
+impl fmt::Display for Step {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Step::Command(ref cmd) => write!(f, "> {}", cmd),
+            Step::Contains(ref text) => write!(f, "expect {}", text),
+            Step::Matches(ref re) => write!(f, "match {}", re.as_str()),
+            Step::Room(ref title) => write!(f, "room {}", title),
+            Step::Inventory(ref item) => write!(f, "inventory {}", item),
+        }
+    }
+}
+
+/// A parsed walkthrough script.
+#[derive(Debug, Clone, Default)]
+pub struct Walkthrough {
+    /// Steps in order, with their 1-based line numbers.
+    pub steps: Vec<(usize, Step)>,
+}
+
+/// A malformed script line, 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkthroughError {
+    pub line: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for WalkthroughError {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+
+impl Error for WalkthroughError {}
+
+/// A command sent to the program and the output it produced. The
+/// introduction has no command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Exchange {
+    pub command: Option<String>,
+    pub output: String,
+}
+
+impl fmt::Display for Exchange {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref cmd) = self.command {
+            writeln!(f, "> {}", cmd)?;
+        }
+        write!(f, "{}", self.output)
+    }
+}
+
+/// The first step that failed.
+#[derive(Debug, Clone)]
+pub struct Failure {
+    /// Line of the step, or 0 if the program failed before the first.
+    pub line: usize,
+    pub step: Option<Step>,
+    pub reason: String,
+    /// The last few exchanges, ending with the one the step checked.
+    pub context: Vec<Exchange>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.step {
+            Some(ref step) => writeln!(f, "line {}: {}: {}", self.line, step, self.reason)?,
+            None => writeln!(f, "the start: {}", self.reason)?,
+        }
+        for exchange in &self.context {
+            write!(f, "{}", exchange)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a successful run did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Summary {
+    /// Commands sent by the script.
+    pub commands: usize,
+    /// Expectations checked.
+    pub checks: usize,
+    /// Cycle count at the end.
+    pub cc: u64,
+}
+
+// Runs until the program wants more input, returning its output and,
+// if it stopped for any other reason than waiting or halting, why
+fn advance (vm: &mut Vm, io: &BufferIo) -> (String, Option<String>) {
+    let problem = match vm.run() {
+        Ok(StopReason::Halted) | Err(VmError::InputExhausted { .. }) => None,
+        Ok(StopReason::CycleLimit) => Some("the cycle limit was reached".to_string()),
+        Ok(reason) => Some(format!("the program stopped at pc {} ({:?})", vm.pc(), reason)),
+        Err(e) => Some(e.to_string()),
+    };
+    (io.take_output(), problem)
+}
+
+// Items listed after "Your inventory:" in the reply to inv
+fn inventory_items (output: &str) -> Vec<&str> {
+    output.lines()
+        .skip_while(|line| !line.starts_with("Your inventory:"))
+        .skip(1)
+        .map_while(|line| line.strip_prefix("- "))
+        .collect()
+}
+
+fn room_title (output: &str) -> Option<&str> {
+    output.lines().rev().find_map(|line| line.strip_prefix("== ").and_then(|l| l.strip_suffix(" ==")))
+}
+
+// Checks an expectation against the output it applies to
+fn check (step: &Step, output: &str) -> Result<(), String> {
+    match *step {
+        Step::Command(_) => Ok(()),
+        Step::Contains(ref text) if output.contains(text.as_str()) => Ok(()),
+        Step::Contains(_) => Err("the output doesn't contain it".to_string()),
+        Step::Matches(ref re) if re.is_match(output) => Ok(()),
+        Step::Matches(_) => Err("the output doesn't match".to_string()),
+        Step::Room(ref title) => match room_title(output) {
+            Some(found) if found == title => Ok(()),
+            Some(found) => Err(format!("the room is '{}'", found)),
+            None => Err("the output has no room heading".to_string()),
+        },
+        Step::Inventory(ref item) => {
+            let items = inventory_items(output);
+            if items.contains(&item.as_str()) {
+                Ok(())
+            }
+            else if items.is_empty() {
+                Err("the inventory is empty".to_string())
+            }
+            else {
+                Err(format!("the inventory holds {}", items.join(", ")))
+            }
+        },
+    }
+}
+
+impl Walkthrough {
+    pub fn parse (src: &str) -> Result<Walkthrough, WalkthroughError> {
+        let mut walkthrough = Walkthrough::default();
+        for (i, text) in src.lines().enumerate() {
+            let err = |msg: &str| WalkthroughError { line: i + 1, msg: msg.to_string() };
+            let text = text.trim();
+            if text.starts_with('#') || text.is_empty() {
+                continue;
+            }
+
+            let step = if let Some(cmd) = text.strip_prefix('>') {
+                Step::Command(cmd.trim().to_string())
+            }
+            else {
+                let (keyword, arg) = text.split_once(' ').unwrap_or((text, ""));
+                let arg = arg.trim();
+                if arg.is_empty() {
+                    return Err(err(&format!("'{}' needs an argument", keyword)));
+                }
+                match keyword {
+                    "expect" => Step::Contains(arg.to_string()),
+                    "match" => match RegexBuilder::new(arg).multi_line(true).build() {
+                        Ok(re) => Step::Matches(re),
+                        Err(e) => return Err(err(&format!("invalid regex: {}", e))),
+                    },
+                    "room" => Step::Room(arg.to_string()),
+                    "inventory" => Step::Inventory(arg.to_string()),
+                    _ => return Err(err(&format!("unknown step '{}'", keyword))),
+                }
+            };
+            walkthrough.steps.push((i + 1, step));
+        }
+        Ok(walkthrough)
+    }
+
+    /// Plays the walkthrough on `vm`, from wherever it is, and stops at
+    /// the first step that fails. The VM's `Io` backend is replaced for
+    /// the run and put back afterwards.
+    pub fn run (&self, vm: &mut Vm) -> Result<Summary, Failure> {
+        let io = BufferIo::default();
+        let old_io = vm.set_io(Box::new(io.clone()));
+        let result = self.run_with(vm, &io);
+        vm.set_io(old_io);
+        result
+    }
+
+    fn run_with (&self, vm: &mut Vm, io: &BufferIo) -> Result<Summary, Failure> {
+        let mut summary = Summary { commands: 0, checks: 0, cc: 0 };
+        let (output, problem) = advance(vm, io);
+        let mut transcript = vec![Exchange { command: None, output }];
+        // The exchange that expectations check
+        let mut current = 0;
+
+        let fail = |line: usize, step: Option<&Step>, reason: String, transcript: &[Exchange]| Failure {
+            line,
+            step: step.cloned(),
+            reason,
+            context: transcript[transcript.len().saturating_sub(CONTEXT_EXCHANGES)..].to_vec(),
+        };
+        if let Some(reason) = problem {
+            return Err(fail(0, None, reason, &transcript));
+        }
+
+        for &(line, ref step) in &self.steps {
+            // Commands and inventory checks need the program waiting for input
+            let send = match *step {
+                Step::Command(ref cmd) => Some(cmd.as_str()),
+                Step::Inventory(_) => Some("inv"),
+                _ => None,
+            };
+            if let Some(cmd) = send {
+                if vm.is_halted() {
+                    return Err(fail(line, Some(step), "the program has halted".to_string(), &transcript));
+                }
+                io.push_input(cmd);
+                let (output, problem) = advance(vm, io);
+                transcript.push(Exchange { command: Some(cmd.to_string()), output });
+                if let Some(reason) = problem {
+                    return Err(fail(line, Some(step), reason, &transcript));
+                }
+            }
+
+            if let Step::Command(_) = *step {
+                summary.commands += 1;
+                current = transcript.len() - 1;
+                continue;
+            }
+
+            // Inventory checks look at the reply to their own inv
+            let checked = if let Step::Inventory(_) = *step { transcript.len() - 1 } else { current };
+            summary.checks += 1;
+            if let Err(reason) = check(step, &transcript[checked].output) {
+                return Err(fail(line, Some(step), reason, &transcript[..=checked]));
+            }
+        }
+        summary.cc = vm.cc();
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    // Prints a room heading, then echoes its input
+    const ECHO: &str = "\
+        set r1, msg
+print:  rmem r0, r1
+        jf r0, echo
+        out r0
+        add r1, r1, 1
+        jmp print
+echo:   in r0
+        out r0
+        jmp echo
+msg:    .string \"== Start ==\\n\"
+        .data 0
+";
+
+    fn echo_vm () -> Vm {
+        let mut vm = Vm::new();
+        vm.load_mem(&assemble(ECHO).unwrap()).unwrap();
+        vm
+    }
+
+    fn parse_err (src: &str) -> WalkthroughError {
+        Walkthrough::parse(src).unwrap_err()
+    }
+
+    #[test]
+    fn parse_steps () {
+        let walkthrough = Walkthrough::parse("# intro\nroom Start\n\n>  go north \nmatch ^go\n").unwrap();
+        let steps: Vec<(usize, String)> = walkthrough.steps.iter().map(|(l, s)| (*l, s.to_string())).collect();
+        assert_eq!(steps, [(2, "room Start".to_string()), (4, "> go north".to_string()), (5, "match ^go".to_string())]);
+    }
+
+    #[test]
+    fn parse_errors () {
+        assert_eq!(parse_err("> look\nlook around"), WalkthroughError { line: 2, msg: "unknown step 'look'".to_string() });
+        let err = parse_err("match (unclosed");
+        assert_eq!(err.line, 1);
+        assert!(err.msg.starts_with("invalid regex"), "{}", err.msg);
+        assert_eq!(parse_err("\n\nexpect   "), WalkthroughError { line: 3, msg: "'expect' needs an argument".to_string() });
+        assert_eq!(parse_err("inventory").msg, "'inventory' needs an argument");
+    }
+
+    #[test]
+    fn run_passes () {
+        let walkthrough = Walkthrough::parse("room Start\n> take lamp\nexpect take lamp\nmatch ^take \\w+$").unwrap();
+        let summary = walkthrough.run(&mut echo_vm()).unwrap();
+        assert_eq!((summary.commands, summary.checks), (1, 3));
+    }
+
+    #[test]
+    fn run_reports_first_failure () {
+        let src = "room Start\n> one\n> two\n> three\nexpect three\n> four\nexpect five\nexpect four";
+        let failure = Walkthrough::parse(src).unwrap().run(&mut echo_vm()).unwrap_err();
+        assert_eq!(failure.line, 7);
+        assert_eq!(failure.step.unwrap().to_string(), "expect five");
+        assert_eq!(failure.reason, "the output doesn't contain it");
+        // The last exchanges, ending with the one checked
+        let commands: Vec<Option<&str>> = failure.context.iter().map(|e| e.command.as_deref()).collect();
+        assert_eq!(commands, [Some("two"), Some("three"), Some("four")]);
+        assert_eq!(failure.context[2].output, "four\n");
+
+        // Inventory checks send their own inv
+        let failure = Walkthrough::parse("> one\ninventory lamp").unwrap().run(&mut echo_vm()).unwrap_err();
+        assert_eq!((failure.line, failure.reason.as_str()), (2, "the inventory is empty"));
+        assert_eq!(failure.context.last().unwrap().command.as_deref(), Some("inv"));
+    }
+}
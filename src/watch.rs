@@ -0,0 +1,83 @@
+//! Watchpoints on memory addresses and registers.
+//!
+//! Addresses follow the architecture's numbering: 0..32767 is memory and
+//! 32768..32775 are registers r0..r7. Watchpoints are checked by the VM
+//! whenever an instruction reads or writes a location, so they catch
+//! `rmem`/`wmem` as well as ordinary register traffic.
+
+use std::fmt;
+
+use instr::{Instruction, Operand};
+
+/// Which accesses trigger a watchpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    /// Any read of the location.
+    Read,
+    /// Any write, even one storing the value already there.
+    Write,
+    /// A write that changes the stored value.
+    Change,
+}
+
+/// What happens when a watchpoint fires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchAction {
+    /// Stop `run` after the instruction, with `StopReason::Watchpoint`.
+    Break,
+    /// Report the access and keep running.
+    Log,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub addr: u16,
+    pub kind: WatchKind,
+    pub action: WatchAction,
+}
+
+/// A watchpoint firing: which location was accessed, by what, and the
+/// value before and after. For reads `old` and `new` are the same.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub pc: u16,
+    pub cc: u64,
+    pub instr: Option<Instruction>,
+    pub old: u16,
+    pub new: u16,
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Change => "change",
+        })
+    }
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt (&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "watch {} {} at pc {}, cycle {}",
+            self.watchpoint.kind, format_location(self.watchpoint.addr), self.pc, self.cc)?;
+        if let Some(instr) = self.instr {
+            write!(f, " ({})", instr)?;
+        }
+        if self.watchpoint.kind == WatchKind::Read {
+            write!(f, ": {}", self.new)
+        }
+        else {
+            write!(f, ": {} -> {}", self.old, self.new)
+        }
+    }
+}
+
+/// Renders a watchable address as a register name or a memory address.
+pub fn format_location (addr: u16) -> String {
+    match Operand::from_word(addr) {
+        Some(Operand::Register(r)) => format!("r{}", r),
+        _ => format!("[{}]", addr),
+    }
+}